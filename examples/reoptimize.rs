@@ -0,0 +1,56 @@
+//! Re-encodes lossless and animated WebP files in place, if that makes them smaller.
+//!
+//! Usage: `cargo run --release --example reoptimize -- FILE...`
+
+use std::{env, fs, process::ExitCode};
+
+use image_webp::{reoptimize, EncoderParams, Reoptimized};
+
+fn main() -> ExitCode {
+    let paths: Vec<_> = env::args_os().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("Usage: reoptimize FILE...");
+        return ExitCode::FAILURE;
+    }
+
+    let mut params = EncoderParams::default();
+    params.method = 6;
+    let mut status = ExitCode::SUCCESS;
+    for path in paths {
+        let name = path.to_string_lossy();
+        let original = match fs::read(&path) {
+            Ok(original) => original,
+            Err(err) => {
+                eprintln!("{name}: {err}");
+                status = ExitCode::FAILURE;
+                continue;
+            }
+        };
+
+        match reoptimize(&original, &params) {
+            Ok(Reoptimized::Smaller(encoded)) => {
+                // Write next to the original first, so a failed write can't lose it.
+                let mut temporary = path.clone();
+                temporary.push(".tmp");
+                let written =
+                    fs::write(&temporary, &encoded).and_then(|()| fs::rename(&temporary, &path));
+                match written {
+                    Ok(()) => println!("{name}: {} -> {} bytes", original.len(), encoded.len()),
+                    Err(err) => {
+                        let _ = fs::remove_file(&temporary);
+                        eprintln!("{name}: {err}");
+                        status = ExitCode::FAILURE;
+                    }
+                }
+            }
+            Ok(Reoptimized::NotSmaller) => println!("{name}: kept, already as small"),
+            Ok(Reoptimized::Lossy) => println!("{name}: kept, lossy"),
+            Ok(_) => println!("{name}: kept"),
+            Err(err) => {
+                eprintln!("{name}: {err}");
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+    status
+}
@@ -0,0 +1,321 @@
+//! Finding LZ77 backward references for the lossless encoder.
+
+use crate::lossless::DISTANCE_MAP;
+
+/// Number of bits of the hash used to find earlier occurrences of a pair of pixels.
+const HASH_BITS: u32 = 18;
+
+/// Furthest back a copy may reach, in pixels.
+const WINDOW_SIZE: usize = (1 << 20) - 120;
+
+/// Longest copy allowed by the format.
+const MAX_LENGTH: usize = 4096;
+
+/// Shortest copy worth emitting instead of literals.
+const MIN_LENGTH: usize = 2;
+
+/// Plane code plus one of each (xoffset, yoffset) pair in `DISTANCE_MAP`, indexed by
+/// `yoffset * 16 + xoffset + 7`. Zero for pairs that don't have a plane code.
+const PLANE_CODES: [u8; 128] = {
+    let mut table = [0; 128];
+    let mut i = 0;
+    while i < DISTANCE_MAP.len() {
+        let (xoffset, yoffset) = DISTANCE_MAP[i];
+        table[(yoffset as isize * 16 + xoffset as isize + 7) as usize] = i as u8 + 1;
+        i += 1;
+    }
+    table
+};
+
+/// A symbol of the entropy coded image: a literal pixel or a copy of earlier pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PixOrCopy {
+    /// A pixel in RGBA order.
+    Literal([u8; 4]),
+    /// Copy of `length` pixels starting at the pixel identified by the plane code `distance_code`.
+    Copy { length: u16, distance_code: u32 },
+    /// A pixel found at this index of the color cache.
+    CacheIndex(u16),
+}
+
+impl PixOrCopy {
+    /// Number of pixels coded by the symbol.
+    pub(crate) fn num_pixels(&self) -> usize {
+        match *self {
+            PixOrCopy::Copy { length, .. } => usize::from(length),
+            _ => 1,
+        }
+    }
+}
+
+/// Converts a distance in pixels into the shortest plane code that represents it.
+pub(crate) fn distance_to_plane_code(width: u16, distance: usize) -> u32 {
+    let width = usize::from(width);
+    let yoffset = distance / width;
+    let xoffset = distance % width;
+
+    let mut code = distance as u32 + 120;
+    if xoffset <= 8 && yoffset < 8 {
+        let plane_code = PLANE_CODES[yoffset * 16 + xoffset + 7];
+        if plane_code != 0 {
+            code = code.min(u32::from(plane_code));
+        }
+    }
+    if width - xoffset <= 7 && yoffset + 1 < 8 {
+        let plane_code = PLANE_CODES[(yoffset + 1) * 16 + 7 - (width - xoffset)];
+        if plane_code != 0 {
+            code = code.min(u32::from(plane_code));
+        }
+    }
+    code
+}
+
+/// Splits a length or distance code into its prefix symbol and extra bits.
+///
+/// Returns the symbol, the number of extra bits and their value.
+pub(crate) const fn prefix_encode(value: u32) -> (u16, u8, u32) {
+    let value = value - 1;
+    if value < 4 {
+        return (value as u16, 0, 0);
+    }
+    let highest_bit = value.ilog2();
+    let second_highest_bit = (value >> (highest_bit - 1)) & 1;
+    let extra_bits = highest_bit - 1;
+    let symbol = 2 * highest_bit + second_highest_bit;
+    (
+        symbol as u16,
+        extra_bits as u8,
+        value & ((1 << extra_bits) - 1),
+    )
+}
+
+/// Links every position of the image to the previous position starting with the same pair of
+/// pixels, or rather with a pair of pixels with the same hash.
+struct HashChain {
+    head: Vec<usize>,
+    chain: Vec<usize>,
+}
+
+impl HashChain {
+    fn new(num_pixels: usize) -> Self {
+        Self {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            chain: vec![usize::MAX; num_pixels],
+        }
+    }
+
+    fn hash(argb: &[u32], i: usize) -> usize {
+        let h = argb[i].wrapping_mul(0x1e35a7bd) ^ argb[i + 1].wrapping_mul(0x9e3779b1);
+        (h >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, argb: &[u32], i: usize) {
+        if i + 1 < argb.len() {
+            let h = Self::hash(argb, i);
+            self.chain[i] = self.head[h];
+            self.head[h] = i;
+        }
+    }
+
+    /// Returns the most recently inserted position with the same hash as position `i`, or
+    /// `usize::MAX` if there is none.
+    fn first(&self, argb: &[u32], i: usize) -> usize {
+        self.head[Self::hash(argb, i)]
+    }
+
+    /// Returns the position inserted before `i` with the same hash, or `usize::MAX` if there is
+    /// none.
+    fn next(&self, i: usize) -> usize {
+        self.chain[i]
+    }
+}
+
+/// Splits the image into literal pixels and copies of earlier pixels.
+///
+/// Copies from the pixels directly to the left and directly above are checked at every position,
+/// along with up to `max_chain_length` earlier positions found through hash chains of pixel
+/// pairs. The longest match is used.
+pub(crate) fn compute_backward_references(
+    pixels: &[u8],
+    width: u16,
+    max_chain_length: usize,
+) -> Vec<PixOrCopy> {
+    let argb: Vec<u32> = pixels
+        .chunks_exact(4)
+        .map(|p| u32::from_be_bytes([p[3], p[0], p[1], p[2]]))
+        .collect();
+    let num_pixels = argb.len();
+
+    let mut hash_chain = (max_chain_length > 0).then(|| HashChain::new(num_pixels));
+
+    let match_length = |start: usize, i: usize, max_length: usize| {
+        let (a, b) = (&argb[start..][..max_length], &argb[i..][..max_length]);
+        let mut length = 0;
+        while length < max_length && a[length] == b[length] {
+            length += 1;
+        }
+        length
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < num_pixels {
+        let max_length = (num_pixels - i).min(MAX_LENGTH);
+
+        let mut best_length = 0;
+        let mut best_distance = 0;
+        for distance in [1, usize::from(width)] {
+            if distance <= i {
+                let length = match_length(i - distance, i, max_length);
+                if length > best_length {
+                    best_length = length;
+                    best_distance = distance;
+                }
+            }
+        }
+
+        let hash_chain_search = i + 1 < num_pixels && best_length < max_length;
+        if let Some(hash_chain) = hash_chain.as_ref().filter(|_| hash_chain_search) {
+            let mut candidate = hash_chain.first(&argb, i);
+            for _ in 0..max_chain_length {
+                if candidate == usize::MAX || i - candidate > WINDOW_SIZE {
+                    break;
+                }
+                if argb[candidate + best_length] == argb[i + best_length] {
+                    let length = match_length(candidate, i, max_length);
+                    if length > best_length {
+                        best_length = length;
+                        best_distance = i - candidate;
+                        if length == max_length {
+                            break;
+                        }
+                    }
+                }
+                candidate = hash_chain.next(candidate);
+            }
+        }
+
+        if best_length >= MIN_LENGTH {
+            tokens.push(PixOrCopy::Copy {
+                length: best_length as u16,
+                distance_code: distance_to_plane_code(width, best_distance),
+            });
+            if let Some(hash_chain) = hash_chain.as_mut() {
+                for j in i..i + best_length {
+                    hash_chain.insert(&argb, j);
+                }
+            }
+            i += best_length;
+        } else {
+            tokens.push(PixOrCopy::Literal(pixels[i * 4..][..4].try_into().unwrap()));
+            if let Some(hash_chain) = hash_chain.as_mut() {
+                hash_chain.insert(&argb, i);
+            }
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+/// Hash of a pixel whose top `cache_bits` bits are its index in a color cache of that size.
+pub(crate) fn color_cache_hash(pixel: &[u8]) -> u32 {
+    let argb = u32::from_be_bytes([pixel[3], pixel[0], pixel[1], pixel[2]]);
+    0x1e35a7bdu32.wrapping_mul(argb)
+}
+
+/// Replaces the literals that can be found in a color cache of `cache_bits` bits by references
+/// to the cache.
+///
+/// The cache is updated with every pixel of the image, in the same way as the decoder does.
+pub(crate) fn apply_color_cache(
+    pixels: &[u8],
+    tokens: &[PixOrCopy],
+    cache_bits: u8,
+) -> Vec<PixOrCopy> {
+    let hash = |pixel: &[u8]| (color_cache_hash(pixel) >> (32 - cache_bits)) as usize;
+
+    let mut cache = vec![[0u8; 4]; 1 << cache_bits];
+    let mut output = Vec::with_capacity(tokens.len());
+    let mut index = 0;
+    for token in tokens {
+        match *token {
+            PixOrCopy::Literal(pixel) => {
+                let key = hash(&pixel);
+                if cache[key] == pixel {
+                    output.push(PixOrCopy::CacheIndex(key as u16));
+                } else {
+                    cache[key] = pixel;
+                    output.push(*token);
+                }
+                index += 1;
+            }
+            PixOrCopy::Copy { length, .. } => {
+                for pixel in pixels[index * 4..][..usize::from(length) * 4].chunks_exact(4) {
+                    cache[hash(pixel)].copy_from_slice(pixel);
+                }
+                output.push(*token);
+                index += usize::from(length);
+            }
+            PixOrCopy::CacheIndex(_) => unreachable!(),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plane_codes() {
+        for width in [1, 2, 3, 7, 8, 9, 16, 100] {
+            for distance in 1..20 * usize::from(width) {
+                let code = distance_to_plane_code(width, distance) as usize;
+                let decoded = if code > 120 {
+                    code - 120
+                } else {
+                    let (xoffset, yoffset) = DISTANCE_MAP[code - 1];
+                    (i32::from(xoffset) + i32::from(yoffset) * i32::from(width)).max(1) as usize
+                };
+                assert_eq!(decoded, distance, "width {width} distance {distance}");
+            }
+        }
+    }
+
+    #[test]
+    fn color_cache_hits() {
+        let pixels = [
+            [1, 2, 3, 4],
+            [5, 6, 7, 8],
+            [5, 6, 7, 8],
+            [5, 6, 7, 8],
+            [5, 6, 7, 8],
+            [1, 2, 3, 4],
+        ]
+        .concat();
+        let tokens = compute_backward_references(&pixels, 6, 0);
+        assert_eq!(tokens.len(), 4);
+
+        let tokens = apply_color_cache(&pixels, &tokens, 4);
+        assert!(matches!(tokens[0], PixOrCopy::Literal(_)));
+        assert!(matches!(tokens[1], PixOrCopy::Literal(_)));
+        assert!(matches!(tokens[2], PixOrCopy::Copy { length: 3, .. }));
+        assert!(matches!(tokens[3], PixOrCopy::CacheIndex(_)));
+    }
+
+    #[test]
+    fn prefix_codes() {
+        for value in 1..100_000u32 {
+            let (symbol, extra_bits, extra_value) = prefix_encode(value);
+            let decoded = if symbol < 4 {
+                u32::from(symbol) + 1
+            } else {
+                let offset = (2 + (u32::from(symbol) & 1)) << extra_bits;
+                offset + extra_value + 1
+            };
+            assert_eq!(decoded, value);
+            assert_eq!(u32::from(extra_bits), (u32::from(symbol).max(2) - 2) >> 1);
+        }
+    }
+}
@@ -517,6 +517,16 @@ impl<R: BufRead + Seek> WebPDecoder<R> {
         }
     }
 
+    /// Returns the background color of an animated image, which is used to clear the canvas
+    /// before the first frame and wherever frames are disposed. Returns None if the image is not
+    /// animated.
+    pub fn background_color(&self) -> Option<[u8; 4]> {
+        match &self.kind {
+            ImageKind::Extended(info) if info.animation => Some(info.background_color),
+            _ => None,
+        }
+    }
+
     /// Returns the (width, height) of the image in pixels.
     pub fn dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
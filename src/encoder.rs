@@ -1,10 +1,21 @@
 //! Encoding of WebP images.
-use std::collections::BinaryHeap;
+use std::borrow::Cow;
 use std::io::{self, Write};
-use std::slice::ChunksExact;
 
 use quick_error::quick_error;
 
+use crate::backward_references::{
+    apply_color_cache, compute_backward_references, prefix_encode, PixOrCopy,
+};
+use crate::histogram::{cluster_histograms, estimate_entropy, Histogram};
+use crate::lossless::subsample_size;
+use crate::lossless_transform::{
+    color_transform_delta, forward_color_indexing_transform, forward_color_transform,
+    forward_lossy_predictor_transform, forward_predictor_transform, predict_pixel,
+};
+use crate::parallel;
+use crate::vp8_encoder;
+
 /// Color type of the image.
 ///
 /// Note that the WebP format doesn't have a concept of color type. All images are encoded as RGBA
@@ -20,6 +31,72 @@ pub enum ColorType {
     Rgb8,
     /// Image with a red, green, blue, and alpha byte per pixel.
     Rgba8,
+    /// Opaque image with a blue, green, and red byte per pixel.
+    Bgr8,
+    /// Image with a blue, green, red, and alpha byte per pixel.
+    Bgra8,
+    /// Image with a native-endian `u32` per pixel, holding alpha, red, green and blue from the
+    /// most to the least significant byte.
+    ///
+    /// The image data is still passed as bytes, four per pixel.
+    Argb32,
+    /// Opaque image with a single native-endian `u16` luminance value per pixel.
+    ///
+    /// Like the other 16-bit color types, the image is reduced to 8 bits per channel as set by
+    /// [`EncoderParams::dithering`], and the image data is passed as bytes, two per value.
+    L16,
+    /// Image with a native-endian `u16` luminance and alpha value per pixel.
+    La16,
+    /// Opaque image with a native-endian `u16` red, green, and blue value per pixel.
+    Rgb16,
+    /// Image with a native-endian `u16` red, green, blue, and alpha value per pixel.
+    Rgba16,
+}
+
+impl ColorType {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorType::L8 => 1,
+            ColorType::La8 => 2,
+            ColorType::Rgb8 => 3,
+            ColorType::Rgba8 | ColorType::Bgra8 | ColorType::Argb32 => 4,
+            ColorType::Bgr8 => 3,
+            ColorType::L16 => 2,
+            ColorType::La16 => 4,
+            ColorType::Rgb16 => 6,
+            ColorType::Rgba16 => 8,
+        }
+    }
+
+    fn is_grayscale(self) -> bool {
+        matches!(
+            self,
+            ColorType::L8 | ColorType::La8 | ColorType::L16 | ColorType::La16
+        )
+    }
+
+    fn has_alpha(self) -> bool {
+        matches!(
+            self,
+            ColorType::La8
+                | ColorType::Rgba8
+                | ColorType::Bgra8
+                | ColorType::Argb32
+                | ColorType::La16
+                | ColorType::Rgba16
+        )
+    }
+
+    /// The color type with the same channels at 8 bits per channel, for 16-bit color types.
+    fn reduced(self) -> Option<Self> {
+        match self {
+            ColorType::L16 => Some(ColorType::L8),
+            ColorType::La16 => Some(ColorType::La8),
+            ColorType::Rgb16 => Some(ColorType::Rgb8),
+            ColorType::Rgba16 => Some(ColorType::Rgba8),
+            _ => None,
+        }
+    }
 }
 
 quick_error! {
@@ -38,6 +115,33 @@ quick_error! {
         InvalidDimensions {
             display("Invalid dimensions")
         }
+
+        /// The image data is too short or too long for the image dimensions and color type.
+        InvalidBufferSize {
+            display("Buffer size doesn't match the image dimensions")
+        }
+
+        /// The row stride is shorter than a row of pixels.
+        InvalidStride {
+            display("Row stride is shorter than a row of pixels")
+        }
+
+        /// A row passed to [`WebPRowEncoder::write_row()`] doesn't hold exactly one row of
+        /// pixels.
+        InvalidRowLength {
+            display("Row length doesn't match the image width")
+        }
+
+        /// The number of rows passed to a [`WebPRowEncoder`] doesn't match the image height.
+        RowCountMismatch {
+            display("Number of rows doesn't match the image height")
+        }
+
+        /// The macroblock headers of a lossy image don't fit in the first partition of the VP8
+        /// bitstream, which is limited to 512 KiB. Only happens for very large images.
+        PartitionTooLarge {
+            display("Lossy image has too many macroblocks to encode")
+        }
     }
 }
 
@@ -78,6 +182,24 @@ impl<W: Write> BitWriter<W> {
     }
 }
 
+/// Writer that counts the bytes written through it.
+struct ByteCounter<W> {
+    writer: W,
+    count: usize,
+}
+
+impl<W: Write> Write for ByteCounter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
 fn write_single_entry_huffman_tree<W: Write>(w: &mut BitWriter<W>, symbol: u8) -> io::Result<()> {
     w.write_bits(1, 2)?;
     if symbol <= 1 {
@@ -90,6 +212,10 @@ fn write_single_entry_huffman_tree<W: Write>(w: &mut BitWriter<W>, symbol: u8) -
     Ok(())
 }
 
+/// Builds an optimal prefix code with codes of at most `length_limit` bits for the symbols counted
+/// in `frequencies`, using the package-merge algorithm.
+///
+/// Returns false, with all lengths set to zero, if fewer than two symbols are used.
 fn build_huffman_tree(
     frequencies: &[u32],
     lengths: &mut [u8],
@@ -99,108 +225,70 @@ fn build_huffman_tree(
     assert_eq!(frequencies.len(), lengths.len());
     assert_eq!(frequencies.len(), codes.len());
 
-    if frequencies.iter().filter(|&&f| f > 0).count() <= 1 {
-        lengths.fill(0);
-        codes.fill(0);
-        return false;
-    }
-
-    #[derive(Eq, PartialEq, Copy, Clone, Debug)]
-    struct Item(u32, u16);
-    impl Ord for Item {
-        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            other.0.cmp(&self.0)
-        }
-    }
-    impl PartialOrd for Item {
-        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            Some(self.cmp(other))
-        }
-    }
-
-    // Build a huffman tree
-    let mut internal_nodes = Vec::new();
-    let mut nodes = BinaryHeap::from_iter(
-        frequencies
-            .iter()
-            .enumerate()
-            .filter(|(_, &frequency)| frequency > 0)
-            .map(|(i, &frequency)| Item(frequency, i as u16)),
-    );
-    while nodes.len() > 1 {
-        let Item(frequency1, index1) = nodes.pop().unwrap();
-        let mut root = nodes.peek_mut().unwrap();
-        internal_nodes.push((index1, root.1));
-        *root = Item(
-            frequency1 + root.0,
-            internal_nodes.len() as u16 + frequencies.len() as u16 - 1,
-        );
-    }
-
-    // Walk the tree to assign code lengths
     lengths.fill(0);
-    let mut stack = Vec::new();
-    stack.push((nodes.pop().unwrap().1, 0));
-    while let Some((node, depth)) = stack.pop() {
-        let node = node as usize;
-        if node < frequencies.len() {
-            lengths[node] = depth as u8;
-        } else {
-            let (left, right) = internal_nodes[node - frequencies.len()];
-            stack.push((left, depth + 1));
-            stack.push((right, depth + 1));
-        }
-    }
+    codes.fill(0);
 
-    // Limit the codes to length length_limit
-    let mut max_length = 0;
-    for &length in lengths.iter() {
-        max_length = max_length.max(length);
+    let mut symbols: Vec<(u64, usize)> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|(_, &frequency)| frequency > 0)
+        .map(|(i, &frequency)| (u64::from(frequency), i))
+        .collect();
+    if symbols.len() <= 1 {
+        return false;
     }
-    if max_length > length_limit {
-        let mut counts = [0u32; 16];
-        for &length in lengths.iter() {
-            counts[length.min(length_limit) as usize] += 1;
-        }
-
-        let mut total = 0;
-        for (i, count) in counts
+    symbols.sort_unstable();
+    assert!(symbols.len() <= 1 << length_limit);
+
+    // Each list holds the leaves merged with the packages of pairs of items of the previous list,
+    // sorted by weight. Leaves are stored as their index in `symbols`, packages as `None`.
+    let mut lists: Vec<Vec<Option<usize>>> = Vec::with_capacity(usize::from(length_limit));
+    let mut previous: Vec<(u64, Option<usize>)> = symbols
+        .iter()
+        .enumerate()
+        .map(|(i, &(weight, _))| (weight, Some(i)))
+        .collect();
+    lists.push(previous.iter().map(|&(_, item)| item).collect());
+    for _ in 1..length_limit {
+        let mut packages = previous
+            .chunks_exact(2)
+            .map(|pair| (pair[0].0 + pair[1].0, None))
+            .peekable();
+        let mut leaves = symbols
             .iter()
             .enumerate()
-            .skip(1)
-            .take(length_limit as usize)
-        {
-            total += count << (length_limit as usize - i);
-        }
-
-        while total > 1u32 << length_limit {
-            let mut i = length_limit as usize - 1;
-            while counts[i] == 0 {
-                i -= 1;
-            }
-            counts[i] -= 1;
-            counts[length_limit as usize] -= 1;
-            counts[i + 1] += 2;
-            total -= 1;
+            .map(|(i, &(weight, _))| (weight, Some(i)))
+            .peekable();
+
+        let mut list = Vec::with_capacity(symbols.len() * 2);
+        loop {
+            let item = match (leaves.peek(), packages.peek()) {
+                (Some(leaf), Some(package)) if leaf.0 <= package.0 => leaves.next(),
+                (_, Some(_)) => packages.next(),
+                (Some(_), None) => leaves.next(),
+                (None, None) => break,
+            };
+            list.push(item.unwrap());
         }
+        lists.push(list.iter().map(|&(_, item)| item).collect());
+        previous = list;
+    }
 
-        // assign new lengths
-        let mut len = length_limit;
-        let mut indexes = frequencies.iter().copied().enumerate().collect::<Vec<_>>();
-        indexes.sort_unstable_by_key(|&(_, frequency)| frequency);
-        for &(i, frequency) in &indexes {
-            if frequency > 0 {
-                while counts[len as usize] == 0 {
-                    len -= 1;
-                }
-                lengths[i] = len;
-                counts[len as usize] -= 1;
+    // The cheapest 2n - 2 items of the last list make up the code. Each time a leaf is selected,
+    // directly or through a package, its code gets one bit longer.
+    let mut count = symbols.len() * 2 - 2;
+    for list in lists.iter().rev() {
+        let mut num_packages = 0;
+        for item in &list[..count] {
+            match *item {
+                Some(i) => lengths[symbols[i].1] += 1,
+                None => num_packages += 1,
             }
         }
+        count = num_packages * 2;
     }
 
     // Assign codes
-    codes.fill(0);
     let mut code = 0u32;
     for len in 1..=length_limit {
         for (i, &length) in lengths.iter().enumerate() {
@@ -216,137 +304,221 @@ fn build_huffman_tree(
     true
 }
 
+/// Order in which the lengths of the code length code are written.
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// Splits a sequence of code lengths into symbols of the code length code and their extra bits.
+///
+/// Symbols 0 to 15 are literal lengths, 16 repeats the previous non-zero length 3 to 6 times,
+/// while 17 and 18 repeat zero 3 to 10 and 11 to 138 times.
+pub(crate) fn code_length_tokens(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut tokens = Vec::new();
+    let mut previous = 8;
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&l| l == length).count();
+        i += run;
+
+        if length == 0 {
+            while run >= 11 {
+                let repeat = run.min(138);
+                tokens.push((18, (repeat - 11) as u8));
+                run -= repeat;
+            }
+            if run >= 3 {
+                tokens.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            if length != previous {
+                tokens.push((length, 0));
+                previous = length;
+                run -= 1;
+            }
+            while run >= 3 {
+                let repeat = run.min(6);
+                tokens.push((16, (repeat - 3) as u8));
+                run -= repeat;
+            }
+        }
+        for _ in 0..run {
+            tokens.push((length, 0));
+        }
+    }
+    tokens
+}
+
+/// Number of extra bits following each symbol of the code length code.
+fn code_length_extra_bits(symbol: u8) -> u8 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Number of bits used to store `max_symbol`, which must be at least 2.
+fn max_symbol_nbits(max_symbol: usize) -> u8 {
+    let mut nbits = 2;
+    while max_symbol - 2 >= 1 << nbits {
+        nbits += 2;
+    }
+    nbits
+}
+
 fn write_huffman_tree<W: Write>(
     w: &mut BitWriter<W>,
     frequencies: &[u32],
     lengths: &mut [u8],
     codes: &mut [u16],
 ) -> io::Result<()> {
+    let mut single_symbol = None;
     if !build_huffman_tree(frequencies, lengths, codes, 15) {
         let symbol = frequencies
             .iter()
             .position(|&frequency| frequency > 0)
             .unwrap_or(0);
-        return write_single_entry_huffman_tree(w, symbol as u8);
-    }
+        if symbol < 256 {
+            return write_single_entry_huffman_tree(w, symbol as u8);
+        }
 
-    let mut code_length_lengths = [0u8; 16];
-    let mut code_length_codes = [0u16; 16];
-    let mut code_length_frequencies = [0u32; 16];
-    for &length in lengths.iter() {
-        code_length_frequencies[length as usize] += 1;
+        // Symbols past 255 can't be written as a simple code, but a normal code with only one
+        // symbol also takes zero bits per symbol.
+        lengths[symbol] = 1;
+        single_symbol = Some(symbol);
     }
-    let single_code_length_length = !build_huffman_tree(
-        &code_length_frequencies,
-        &mut code_length_lengths,
-        &mut code_length_codes,
-        7,
-    );
-
-    const CODE_LENGTH_ORDER: [usize; 19] = [
-        17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
-    ];
-
-    // Write the huffman tree
-    w.write_bits(0, 1)?; // normal huffman tree
-    w.write_bits(19 - 4, 4)?; // num_code_lengths - 4
 
-    for i in CODE_LENGTH_ORDER {
-        if i > 15 || code_length_frequencies[i] == 0 {
-            w.write_bits(0, 3)?;
-        } else if single_code_length_length {
-            w.write_bits(1, 3)?;
-        } else {
-            w.write_bits(u64::from(code_length_lengths[i]), 3)?;
+    // Two symbols below 256 can be written as a simple code. The first symbol gets code 0, as it
+    // does in the canonical code.
+    let mut used = lengths.iter().enumerate().filter(|(_, &length)| length > 0);
+    if let (Some((first, _)), Some((second, _)), None) = (used.next(), used.next(), used.next()) {
+        if second < 256 {
+            w.write_bits(0b11, 2)?;
+            if first <= 1 {
+                w.write_bits(0, 1)?;
+                w.write_bits(first as u64, 1)?;
+            } else {
+                w.write_bits(1, 1)?;
+                w.write_bits(first as u64, 8)?;
+            }
+            w.write_bits(second as u64, 8)?;
+            return Ok(());
         }
     }
 
-    match lengths.len() {
-        256 => {
-            w.write_bits(1, 1)?; // max_symbol is stored
-            w.write_bits(3, 3)?; // max_symbol_nbits / 2 - 2
-            w.write_bits(254, 8)?; // max_symbol - 2
+    // Trailing zero lengths can be left out by storing how many symbols of the code length code
+    // are used. Pick whichever is cheaper.
+    let all_tokens = code_length_tokens(lengths);
+    let used_tokens = all_tokens
+        .iter()
+        .rposition(|&(symbol, _)| symbol != 0 && symbol != 17 && symbol != 18)
+        .map_or(0, |i| i + 1)
+        .max(2);
+
+    let mut best = None;
+    let mut best_bits = u64::MAX;
+    for num_tokens in [all_tokens.len(), used_tokens] {
+        let tokens = &all_tokens[..num_tokens];
+        let mut code_length_frequencies = [0u32; 19];
+        for &(symbol, _) in tokens {
+            code_length_frequencies[usize::from(symbol)] += 1;
+        }
+        let mut code_length_lengths = [0u8; 19];
+        let mut code_length_codes = [0u16; 19];
+        let single_symbol = !build_huffman_tree(
+            &code_length_frequencies,
+            &mut code_length_lengths,
+            &mut code_length_codes,
+            7,
+        );
+        if single_symbol {
+            // A single symbol takes zero bits, but still needs a non-zero length.
+            code_length_lengths[usize::from(tokens[0].0)] = 1;
         }
-        280 => w.write_bits(0, 1)?,
-        _ => unreachable!(),
-    }
 
-    // Write the huffman codes
-    if !single_code_length_length {
-        for &len in lengths.iter() {
-            w.write_bits(
-                u64::from(code_length_codes[len as usize]),
-                code_length_lengths[len as usize],
-            )?;
+        let num_code_lengths = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&i| code_length_lengths[i] != 0)
+            .map_or(0, |i| i + 1)
+            .max(4);
+        let mut bits = 4 + 3 * num_code_lengths as u64 + 1;
+        if num_tokens < all_tokens.len() {
+            bits += 3 + u64::from(max_symbol_nbits(num_tokens));
+        }
+        for &(symbol, _) in tokens {
+            if !single_symbol {
+                bits += u64::from(code_length_lengths[usize::from(symbol)]);
+            }
+            bits += u64::from(code_length_extra_bits(symbol));
+        }
+
+        if bits < best_bits {
+            best_bits = bits;
+            best = Some((
+                num_tokens,
+                num_code_lengths,
+                single_symbol,
+                code_length_lengths,
+                code_length_codes,
+            ));
         }
     }
+    let (
+        num_tokens,
+        num_code_lengths,
+        single_code_length_symbol,
+        code_length_lengths,
+        code_length_codes,
+    ) = best.unwrap();
 
-    Ok(())
-}
+    // Write the huffman tree
+    w.write_bits(0, 1)?; // normal huffman tree
+    w.write_bits(num_code_lengths as u64 - 4, 4)?;
+    for &i in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        w.write_bits(u64::from(code_length_lengths[i]), 3)?;
+    }
 
-const fn length_to_symbol(len: u16) -> (u16, u8) {
-    let len = len - 1;
-    let highest_bit = len.ilog2() as u16;
-    let second_highest_bit = (len >> (highest_bit - 1)) & 1;
-    let extra_bits = highest_bit - 1;
-    let symbol = 2 * highest_bit + second_highest_bit;
-    (symbol, extra_bits as u8)
-}
-
-#[inline(always)]
-fn count_run(
-    pixel: &[u8],
-    it: &mut std::iter::Peekable<ChunksExact<u8>>,
-    frequencies1: &mut [u32; 280],
-) {
-    let mut run_length = 0;
-    while run_length < 4096 && it.peek() == Some(&pixel) {
-        run_length += 1;
-        it.next();
-    }
-    if run_length > 0 {
-        if run_length <= 4 {
-            let symbol = 256 + run_length - 1;
-            frequencies1[symbol] += 1;
-        } else {
-            let (symbol, _extra_bits) = length_to_symbol(run_length as u16);
-            frequencies1[256 + symbol as usize] += 1;
-        }
+    if num_tokens < all_tokens.len() {
+        let nbits = max_symbol_nbits(num_tokens);
+        w.write_bits(1, 1)?; // max_symbol is stored
+        w.write_bits(u64::from(nbits / 2 - 1), 3)?;
+        w.write_bits(num_tokens as u64 - 2, nbits)?;
+    } else {
+        w.write_bits(0, 1)?;
     }
-}
 
-#[inline(always)]
-fn write_run<W: Write>(
-    w: &mut BitWriter<W>,
-    pixel: &[u8],
-    it: &mut std::iter::Peekable<ChunksExact<u8>>,
-    codes1: &[u16; 280],
-    lengths1: &[u8; 280],
-) -> io::Result<()> {
-    let mut run_length = 0;
-    while run_length < 4096 && it.peek() == Some(&pixel) {
-        run_length += 1;
-        it.next();
-    }
-    if run_length > 0 {
-        if run_length <= 4 {
-            let symbol = 256 + run_length - 1;
-            w.write_bits(u64::from(codes1[symbol]), lengths1[symbol])?;
-        } else {
-            let (symbol, extra_bits) = length_to_symbol(run_length as u16);
-            w.write_bits(
-                u64::from(codes1[256 + symbol as usize]),
-                lengths1[256 + symbol as usize],
-            )?;
+    // Write the code lengths
+    for &(symbol, extra_value) in &all_tokens[..num_tokens] {
+        if !single_code_length_symbol {
+            let symbol = usize::from(symbol);
             w.write_bits(
-                (run_length as u64 - 1) & ((1 << extra_bits) - 1),
-                extra_bits,
+                u64::from(code_length_codes[symbol]),
+                code_length_lengths[symbol],
             )?;
         }
+        w.write_bits(u64::from(extra_value), code_length_extra_bits(symbol))?;
+    }
+
+    if let Some(symbol) = single_symbol {
+        lengths[symbol] = 0;
     }
+
     Ok(())
 }
 
+/// Largest color cache size tried, as a power of two.
+const MAX_CACHE_BITS: u8 = 11;
+
+/// Highest supported value of [`EncoderParams::method`].
+const MAX_METHOD: u8 = 6;
+
+/// Block size of the color transform, as a power of two.
+const COLOR_TRANSFORM_SIZE_BITS: u8 = 4;
+
 /// Allows fine-tuning some encoder parameters.
 ///
 /// Pass to [`WebPEncoder::set_params()`].
@@ -355,256 +527,1298 @@ fn write_run<W: Write>(
 pub struct EncoderParams {
     /// Use a predictor transform. Enabled by default.
     pub use_predictor_transform: bool,
+    /// Use a color transform to decorrelate the red and blue channels from the green one.
+    /// Enabled by default.
+    pub use_color_transform: bool,
+    /// Trade-off between encoding speed and size, from 0 (fastest) to 6 (smallest). Defaults to
+    /// 4.
+    ///
+    /// Methods 5 and 6 encode the image several times with different transforms and keep the
+    /// smallest result. Values above 6 are treated as 6.
+    pub method: u8,
+    /// Near-lossless level, from 0 (largest changes) to 100 (lossless). Defaults to 100.
+    ///
+    /// Below 100, the red, green and blue channels of each pixel may be changed by a bounded
+    /// amount to make the residuals of the predictor transform cheaper to code, so this has no
+    /// effect without that transform. The largest change is 1 for levels 80 to 99, 2 for levels
+    /// 60 to 79, 4 for levels 40 to 59, 8 for levels 20 to 39 and 16 for levels 0 to 19. Alpha
+    /// is always kept exact, as are images that are coded with a palette.
+    pub near_lossless: u8,
+    /// Preserve the red, green and blue values of fully transparent pixels. Disabled by default.
+    ///
+    /// When disabled, the color of fully transparent pixels is changed to whatever is cheapest
+    /// to code, since it isn't visible anyway.
+    pub exact: bool,
+    /// Number of threads to encode with, or zero to use one per available core. Defaults to zero.
+    ///
+    /// Only has an effect with the `threads` feature; otherwise encoding always happens on the
+    /// calling thread. The output is the same regardless of the number of threads.
+    pub threads: usize,
+    /// How 16-bit images are reduced to 8 bits per channel. Defaults to rounding.
+    pub dithering: Dithering,
+    /// The color channels of the input are premultiplied by alpha. Disabled by default.
+    ///
+    /// WebP stores straight alpha, so the colors are divided by alpha before encoding, rounding
+    /// to the nearest value. Pixels with an alpha of zero have no color left to recover, so they
+    /// become transparent black. Has no effect on color types without alpha.
+    pub premultiplied_alpha: bool,
+    /// Analyze the pixels before encoding instead of relying on the color type. Disabled by
+    /// default.
+    ///
+    /// Images that turn out to be fully opaque are encoded without alpha, and the cross-color
+    /// transform is skipped for grayscale images. See [`WebPEncoder::analyze()`] for the
+    /// properties that are detected.
+    pub analyze: bool,
+    /// Encode lossily at this quality, from 0 (smallest) to 100 (best), instead of losslessly.
+    /// Defaults to `None`.
+    ///
+    /// Lossy images are stored as VP8 keyframes, which are much smaller than lossless images for
+    /// photos. Transparency is still encoded losslessly with the other settings, in a separate
+    /// alpha chunk, and only if some pixels aren't fully opaque. Lossy images can be at most
+    /// 16383 pixels wide and high.
+    pub lossy: Option<u8>,
+    /// Number of segments, from 1 to 4, that lossy macroblocks are grouped into by how busy they
+    /// are. Defaults to 4.
+    ///
+    /// Each segment has its own quantizer and loop filter strength, so that flat areas, where
+    /// artifacts are easy to see, are quantized more finely than detailed ones. With 1, the whole
+    /// image is quantized alike. Only used for lossy encoding.
+    pub segments: u8,
+    /// Encode lossily at the highest quality that keeps the whole file within this many bytes,
+    /// instead of at a fixed quality. Defaults to `None`.
+    ///
+    /// The image is encoded several times, adjusting the quantizer until the file is at most 2%
+    /// below the size, starting at the quality of [`EncoderParams::lossy`] if set. If the file
+    /// is too large even at the lowest quality, it is encoded at that quality. Takes precedence
+    /// over [`EncoderParams::target_psnr`].
+    pub target_size: Option<usize>,
+    /// Encode lossily at the lowest quality that reaches this peak signal-to-noise ratio, in dB,
+    /// instead of at a fixed quality. Defaults to `None`.
+    ///
+    /// The ratio is measured on the luma and chroma planes the image is stored as. The image is
+    /// encoded several times, adjusting the quantizer until the ratio is at most 0.1 dB above the
+    /// target, starting at the quality of [`EncoderParams::lossy`] if set. If the ratio can't be
+    /// reached, the image is encoded at the highest quality.
+    pub target_psnr: Option<f32>,
+    /// Choose the quantized coefficients of lossy images by how many bits they take as well as
+    /// how close they are, instead of by rounding. Disabled by default.
+    ///
+    /// This drops or shrinks coefficients that cost more bits than they are worth, which makes
+    /// files smaller at the same quality, but encoding slower.
+    pub trellis: bool,
+    /// Loop filter level for lossy images, from 0 (off) to 63 (strongest). Defaults to `None`.
+    ///
+    /// The loop filter smooths over the edges between blocks after decoding, which hides
+    /// blockiness at low qualities but blurs details at high ones. When `None`, the level is
+    /// chosen for each segment by filtering the encoded image and comparing it with the original.
+    pub filter_level: Option<u8>,
+    /// Loop filter sharpness for lossy images, from 0 to 7, where higher values filter edges
+    /// within blocks less. Defaults to `None`, which chooses it like the level.
+    pub filter_sharpness: Option<u8>,
+    /// Use the simple loop filter, which only filters luma and is faster to decode, instead of
+    /// the normal one. Defaults to `None`, which chooses it like the level.
+    pub simple_filter: Option<bool>,
+}
+
+/// Properties of the content of an image, as found by [`WebPEncoder::analyze()`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageProperties {
+    /// Every pixel is fully opaque.
+    pub opaque: bool,
+    /// Every pixel has equal red, green and blue values.
+    pub grayscale: bool,
+    /// Number of distinct colors, counting pixels that differ only in alpha as distinct, or
+    /// `None` if there are more than 256.
+    ///
+    /// The encoder counts colors the same way, whether or not [`EncoderParams::analyze`] is set,
+    /// to store images that have few enough of them with a palette.
+    pub unique_colors: Option<usize>,
+}
+
+impl ImageProperties {
+    /// The 8-bit color type with the fewest channels that can hold the image.
+    pub fn color_type(&self) -> ColorType {
+        match (self.grayscale, self.opaque) {
+            (true, true) => ColorType::L8,
+            (true, false) => ColorType::La8,
+            (false, true) => ColorType::Rgb8,
+            (false, false) => ColorType::Rgba8,
+        }
+    }
+}
+
+/// How the values of 16-bit images are reduced to 8 bits.
+///
+/// The lowest and highest 16-bit values always map to the lowest and highest 8-bit values.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dithering {
+    /// Round every value to the nearest 8-bit value. Smooth gradients may show bands.
+    #[default]
+    Round,
+    /// Ordered dithering: offset values by a repeating 8x8 pattern before rounding down, so that
+    /// the average over an area matches the 16-bit values.
+    Ordered,
+    /// Floyd-Steinberg error diffusion: spread the rounding error of each value over the values
+    /// to its right and below it.
+    ErrorDiffusion,
 }
 
 impl Default for EncoderParams {
     fn default() -> Self {
         Self {
             use_predictor_transform: true,
+            use_color_transform: true,
+            method: 4,
+            near_lossless: 100,
+            exact: false,
+            threads: 0,
+            dithering: Dithering::Round,
+            premultiplied_alpha: false,
+            analyze: false,
+            lossy: None,
+            segments: 4,
+            target_size: None,
+            target_psnr: None,
+            trellis: false,
+            filter_level: None,
+            filter_sharpness: None,
+            simple_filter: None,
         }
     }
 }
 
-/// Encode image data with the indicated color type.
-///
-/// # Panics
-///
-/// Panics if the image data is not of the indicated dimensions.
-fn encode_frame<W: Write>(
-    writer: W,
-    data: &[u8],
-    width: u32,
-    height: u32,
-    color: ColorType,
-    params: EncoderParams,
-) -> Result<(), EncodingError> {
-    let w = &mut BitWriter {
-        writer,
-        buffer: 0,
-        nbits: 0,
-    };
-
-    let (is_color, is_alpha, bytes_per_pixel) = match color {
-        ColorType::L8 => (false, false, 1),
-        ColorType::La8 => (false, true, 2),
-        ColorType::Rgb8 => (true, false, 3),
-        ColorType::Rgba8 => (true, true, 4),
-    };
+/// Number of bits predictor residuals are quantized by at a near-lossless level. Pixels change by
+/// at most half of `1 << bits`.
+fn near_lossless_bits(level: u8) -> u8 {
+    5 - level.min(100) / 20
+}
 
-    assert_eq!(
-        (u64::from(width) * u64::from(height)).saturating_mul(bytes_per_pixel),
-        data.len() as u64
-    );
+/// How much effort to put into each step of encoding, as derived from
+/// [`EncoderParams::method`], and how many threads to spread it across.
+#[derive(Clone, Copy, Debug)]
+struct MethodSettings {
+    /// Choose a predictor for each block instead of using the same one for the whole image.
+    choose_predictors: bool,
+    /// Block size of the predictor transform, as a power of two.
+    predictor_size_bits: u8,
+    /// Allow the cross-color transform.
+    use_color_transform: bool,
+    /// Number of earlier occurrences of a pair of pixels checked for each backward reference.
+    /// Zero only allows copies from the pixels directly to the left and above.
+    max_chain_length: usize,
+    /// Largest color cache size tried, as a power of two.
+    max_cache_bits: u8,
+    /// Number of tile sizes of the entropy image tried. Zero disables meta-Huffman codes.
+    huffman_bits_tries: u8,
+    /// Encode the image with several combinations of transforms.
+    try_transforms: bool,
+    /// Also try combinations where transforms are left out.
+    try_all_transforms: bool,
+    /// Number of threads to use.
+    threads: usize,
+}
 
-    if width == 0 || width > 16384 || height == 0 || height > 16384 {
-        return Err(EncodingError::InvalidDimensions);
+impl MethodSettings {
+    fn new(params: &EncoderParams) -> Self {
+        let method = params.method.min(MAX_METHOD);
+        Self {
+            choose_predictors: method >= 1,
+            predictor_size_bits: match method {
+                0 => 9,
+                1..=4 => 3,
+                _ => 2,
+            },
+            use_color_transform: method >= 2,
+            max_chain_length: match method {
+                0 | 1 => 0,
+                2 => 8,
+                3 => 16,
+                4 => 32,
+                5 => 64,
+                _ => 128,
+            },
+            max_cache_bits: if method >= 1 { MAX_CACHE_BITS } else { 0 },
+            huffman_bits_tries: match method {
+                0 | 1 => 0,
+                2..=4 => 1,
+                _ => 3,
+            },
+            try_transforms: method >= 5,
+            try_all_transforms: method >= 6,
+            threads: parallel::thread_count(params.threads),
+        }
     }
+}
 
-    w.write_bits(0x2f, 8)?; // signature
-    w.write_bits(u64::from(width) - 1, 14)?;
-    w.write_bits(u64::from(height) - 1, 14)?;
-
-    w.write_bits(u64::from(is_alpha), 1)?; // alpha used
-    w.write_bits(0x0, 3)?; // version
+/// Chooses how to code the pixels of an image.
+///
+/// Long distance matches don't always pay off, so both backward references found through hash
+/// chains and ones that only copy from the pixels directly to the left and above are tried, each
+/// with every color cache size allowed by `settings`. Returns the combination estimated to be the
+/// cheapest, as the symbols to code, the number of color cache bits, and the histogram of the
+/// symbols.
+fn choose_image_coding(
+    pixels: &[u8],
+    width: u16,
+    settings: &MethodSettings,
+) -> (Vec<PixOrCopy>, u8, Histogram) {
+    let mut chain_lengths = vec![settings.max_chain_length];
+    if settings.max_chain_length > 0 {
+        chain_lengths.push(0);
+    }
 
-    // subtract green transform
-    w.write_bits(0b101, 3)?;
+    // Each set of backward references keeps only the histogram of its best cache size, and the
+    // tokens for that size are only built once the best references are known.
+    let num_pixels = pixels.len() / 4;
+    let threads = parallel::threads_for(num_pixels * chain_lengths.len(), settings.threads);
+    let mut candidates = parallel::map(&chain_lengths, threads, |&max_chain_length| {
+        let references = compute_backward_references(pixels, width, max_chain_length);
+        let histograms = Histogram::for_cache_sizes(pixels, &references, settings.max_cache_bits);
+        let (cache_bits, histogram, bits) = (0..)
+            .zip(histograms)
+            .map(|(cache_bits, histogram)| {
+                let bits = histogram.estimate_bits();
+                (cache_bits, histogram, bits)
+            })
+            .reduce(|best, candidate| {
+                if candidate.2 < best.2 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+            .unwrap();
+        (references, cache_bits, histogram, bits)
+    });
 
-    // predictor transform
-    if params.use_predictor_transform {
-        w.write_bits(0b111001, 6)?;
-        w.write_bits(0x0, 1)?; // no color cache
-        write_single_entry_huffman_tree(w, 2)?;
-        for _ in 0..4 {
-            write_single_entry_huffman_tree(w, 0)?;
+    let mut best = 0;
+    for (i, candidate) in candidates.iter().enumerate() {
+        if candidate.3 < candidates[best].3 {
+            best = i;
         }
     }
+    let (references, cache_bits, histogram, _) = candidates.swap_remove(best);
+    drop(candidates);
+    let tokens = if cache_bits > 0 {
+        apply_color_cache(pixels, &references, cache_bits)
+    } else {
+        references
+    };
+    (tokens, cache_bits, histogram)
+}
 
-    // transforms done
-    w.write_bits(0x0, 1)?;
+/// Huffman codes for the five alphabets of an entropy coded image.
+struct HuffmanCodes {
+    lengths: [Vec<u8>; 5],
+    codes: [Vec<u16>; 5],
+}
 
-    // color cache
-    w.write_bits(0x0, 1)?;
+impl HuffmanCodes {
+    /// Builds codes for the symbols counted in `histogram` and writes them.
+    fn write<W: Write>(w: &mut BitWriter<W>, histogram: &Histogram) -> io::Result<Self> {
+        let alphabets = histogram.alphabets();
+        let mut lengths = alphabets.map(|alphabet| vec![0u8; alphabet.len()]);
+        let mut codes = alphabets.map(|alphabet| vec![0u16; alphabet.len()]);
+        for i in 0..5 {
+            write_huffman_tree(w, alphabets[i], &mut lengths[i], &mut codes[i])?;
+        }
+        Ok(Self { lengths, codes })
+    }
 
-    // meta-huffman codes
-    w.write_bits(0x0, 1)?;
+    fn write_symbol<W: Write>(
+        &self,
+        w: &mut BitWriter<W>,
+        alphabet: usize,
+        symbol: usize,
+    ) -> io::Result<()> {
+        w.write_bits(
+            u64::from(self.codes[alphabet][symbol]),
+            self.lengths[alphabet][symbol],
+        )
+    }
 
-    // expand to RGBA
-    let mut pixels = match color {
-        ColorType::L8 => data.iter().flat_map(|&p| [p, p, p, 255]).collect(),
-        ColorType::La8 => data
-            .chunks_exact(2)
-            .flat_map(|p| [p[0], p[0], p[0], p[1]])
-            .collect(),
-        ColorType::Rgb8 => data
-            .chunks_exact(3)
-            .flat_map(|p| [p[0], p[1], p[2], 255])
-            .collect(),
-        ColorType::Rgba8 => data.to_vec(),
-    };
+    fn write_token<W: Write>(&self, w: &mut BitWriter<W>, token: PixOrCopy) -> io::Result<()> {
+        let [green, red, blue, alpha, distance] = [0, 1, 2, 3, 4];
+        match token {
+            PixOrCopy::Literal(pixel) => {
+                let len1 = self.lengths[green][pixel[1] as usize];
+                let len0 = self.lengths[red][pixel[0] as usize];
+                let len2 = self.lengths[blue][pixel[2] as usize];
+                let len3 = self.lengths[alpha][pixel[3] as usize];
 
-    // compute subtract green transform
-    for pixel in pixels.chunks_exact_mut(4) {
-        pixel[0] = pixel[0].wrapping_sub(pixel[1]);
-        pixel[2] = pixel[2].wrapping_sub(pixel[1]);
-    }
+                let code = u64::from(self.codes[green][pixel[1] as usize])
+                    | (u64::from(self.codes[red][pixel[0] as usize]) << len1)
+                    | (u64::from(self.codes[blue][pixel[2] as usize]) << (len1 + len0))
+                    | (u64::from(self.codes[alpha][pixel[3] as usize]) << (len1 + len0 + len2));
 
-    // compute predictor transform
-    if params.use_predictor_transform {
-        let row_bytes = width as usize * 4;
-        for y in (1..height as usize).rev() {
-            let (prev, current) =
-                pixels[(y - 1) * row_bytes..][..row_bytes * 2].split_at_mut(row_bytes);
-            for (c, p) in current.iter_mut().zip(prev) {
-                *c = c.wrapping_sub(*p);
+                w.write_bits(code, len1 + len0 + len2 + len3)?;
+            }
+            PixOrCopy::Copy {
+                length,
+                distance_code,
+            } => {
+                let (symbol, extra_bits, extra_value) = prefix_encode(u32::from(length));
+                self.write_symbol(w, green, 256 + symbol as usize)?;
+                w.write_bits(u64::from(extra_value), extra_bits)?;
+
+                let (symbol, extra_bits, extra_value) = prefix_encode(distance_code);
+                self.write_symbol(w, distance, symbol as usize)?;
+                w.write_bits(u64::from(extra_value), extra_bits)?;
+            }
+            PixOrCopy::CacheIndex(index) => {
+                self.write_symbol(w, green, 280 + usize::from(index))?;
             }
         }
-        for i in (4..row_bytes).rev() {
-            pixels[i] = pixels[i].wrapping_sub(pixels[i - 4]);
-        }
-        pixels[3] = pixels[3].wrapping_sub(255);
+        Ok(())
     }
+}
 
-    // compute frequencies
-    let mut frequencies0 = [0u32; 256];
-    let mut frequencies1 = [0u32; 280];
-    let mut frequencies2 = [0u32; 256];
-    let mut frequencies3 = [0u32; 256];
-    let mut it = pixels.chunks_exact(4).peekable();
-    match color {
-        ColorType::L8 => {
-            frequencies0[0] = 1;
-            frequencies2[0] = 1;
-            frequencies3[0] = 1;
-            while let Some(pixel) = it.next() {
-                frequencies1[pixel[1] as usize] += 1;
-                count_run(pixel, &mut it, &mut frequencies1);
-            }
-        }
-        ColorType::La8 => {
-            frequencies0[0] = 1;
-            frequencies2[0] = 1;
-            while let Some(pixel) = it.next() {
-                frequencies1[pixel[1] as usize] += 1;
-                frequencies3[pixel[3] as usize] += 1;
-                count_run(pixel, &mut it, &mut frequencies1);
-            }
+/// Smallest tile size of the entropy image, as a power of two.
+const MIN_HUFFMAN_BITS: u8 = 3;
+
+/// Most tiles the entropy image may have before the tiles are made larger.
+const MAX_HUFFMAN_IMAGE_SIZE: usize = 2600;
+
+/// Groups of Huffman codes for different parts of an image.
+struct HuffmanGroups {
+    /// Tile size of the entropy image, as a power of two.
+    bits: u8,
+    /// Index of the group of every tile.
+    tile_groups: Vec<u16>,
+    /// The entropy image, which stores the group index of every tile in its red and green
+    /// channels.
+    entropy_image: Vec<u8>,
+    /// Combined histogram of the tiles of each group.
+    histograms: Vec<Histogram>,
+}
+
+/// Splits the image into tiles that are coded with different groups of Huffman codes, if that is
+/// estimated to be cheaper than coding the whole image with one group.
+///
+/// `settings` determines how many tile sizes are tried.
+fn choose_huffman_groups(
+    tokens: &[PixOrCopy],
+    cache_bits: u8,
+    histogram: &Histogram,
+    width: u16,
+    height: u16,
+    settings: &MethodSettings,
+) -> Option<HuffmanGroups> {
+    let mut min_bits = MIN_HUFFMAN_BITS;
+    while min_bits < 9
+        && usize::from(subsample_size(width, min_bits))
+            * usize::from(subsample_size(height, min_bits))
+            > MAX_HUFFMAN_IMAGE_SIZE
+    {
+        min_bits += 1;
+    }
+
+    // The tile sizes are tried side by side, each with its share of the threads.
+    let tile_bits: Vec<u8> = (min_bits..=9)
+        .take(usize::from(settings.huffman_bits_tries))
+        .collect();
+    let threads = settings.threads;
+    let settings = &MethodSettings {
+        threads: (settings.threads / tile_bits.len().max(1)).max(1),
+        ..*settings
+    };
+    let tries = parallel::map(&tile_bits, threads, |&bits| {
+        let xsize = subsample_size(width, bits);
+        let ysize = subsample_size(height, bits);
+
+        let mut tiles = vec![Histogram::new(cache_bits); usize::from(xsize) * usize::from(ysize)];
+        let mut index = 0;
+        for token in tokens {
+            let x = index % usize::from(width);
+            let y = index / usize::from(width);
+            tiles[(y >> bits) * usize::from(xsize) + (x >> bits)].add(token);
+            index += token.num_pixels();
         }
-        ColorType::Rgb8 => {
-            frequencies3[0] = 1;
-            while let Some(pixel) = it.next() {
-                frequencies0[pixel[0] as usize] += 1;
-                frequencies1[pixel[1] as usize] += 1;
-                frequencies2[pixel[2] as usize] += 1;
-                count_run(pixel, &mut it, &mut frequencies1);
-            }
+
+        let (tile_groups, histograms) = cluster_histograms(&tiles, cache_bits, settings.threads);
+        if histograms.len() <= 1 {
+            return None;
         }
-        ColorType::Rgba8 => {
-            while let Some(pixel) = it.next() {
-                frequencies0[pixel[0] as usize] += 1;
-                frequencies1[pixel[1] as usize] += 1;
-                frequencies2[pixel[2] as usize] += 1;
-                frequencies3[pixel[3] as usize] += 1;
-                count_run(pixel, &mut it, &mut frequencies1);
-            }
+
+        let entropy_image: Vec<u8> = tile_groups
+            .iter()
+            .flat_map(|&group| [(group >> 8) as u8, group as u8, 0, 0])
+            .collect();
+        let grouped_bits = estimate_image_size(&entropy_image, xsize, settings)
+            + histograms
+                .iter()
+                .map(Histogram::estimate_coded_bits)
+                .sum::<f32>();
+        Some((
+            grouped_bits,
+            HuffmanGroups {
+                bits,
+                tile_groups,
+                entropy_image,
+                histograms,
+            },
+        ))
+    });
+
+    let mut best = None;
+    let mut best_bits = histogram.estimate_coded_bits();
+    for (grouped_bits, groups) in tries.into_iter().flatten() {
+        if grouped_bits < best_bits {
+            best_bits = grouped_bits;
+            best = Some(groups);
         }
     }
+    best
+}
 
-    // compute and write huffman codes
-    let mut lengths0 = [0u8; 256];
-    let mut lengths1 = [0u8; 280];
-    let mut lengths2 = [0u8; 256];
-    let mut lengths3 = [0u8; 256];
-    let mut codes0 = [0u16; 256];
-    let mut codes1 = [0u16; 280];
-    let mut codes2 = [0u16; 256];
-    let mut codes3 = [0u16; 256];
-    write_huffman_tree(w, &frequencies1, &mut lengths1, &mut codes1)?;
-    if is_color {
-        write_huffman_tree(w, &frequencies0, &mut lengths0, &mut codes0)?;
-        write_huffman_tree(w, &frequencies2, &mut lengths2, &mut codes2)?;
+/// Writes an entropy coded image, starting with its color cache info.
+///
+/// This is used both for the main image and for the sub-images of transforms. Only the main
+/// image, also called the ARGB image, may use meta-Huffman codes, in which case the image is
+/// split into tiles that each use one of several groups of Huffman codes.
+fn write_image_stream<W: Write>(
+    w: &mut BitWriter<W>,
+    pixels: &[u8],
+    width: u16,
+    is_argb_img: bool,
+    settings: &MethodSettings,
+) -> io::Result<()> {
+    let (tokens, cache_bits, histogram) = choose_image_coding(pixels, width, settings);
+
+    // color cache
+    if cache_bits > 0 {
+        w.write_bits(1, 1)?;
+        w.write_bits(u64::from(cache_bits), 4)?;
     } else {
-        write_single_entry_huffman_tree(w, 0)?;
-        write_single_entry_huffman_tree(w, 0)?;
+        w.write_bits(0, 1)?;
     }
-    if is_alpha {
-        write_huffman_tree(w, &frequencies3, &mut lengths3, &mut codes3)?;
-    } else if params.use_predictor_transform {
-        write_single_entry_huffman_tree(w, 0)?;
-    } else {
-        write_single_entry_huffman_tree(w, 255)?;
+
+    // meta-huffman codes
+    let mut groups = None;
+    if is_argb_img {
+        let height = (pixels.len() / 4 / usize::from(width)) as u16;
+        groups = choose_huffman_groups(&tokens, cache_bits, &histogram, width, height, settings);
+        if let Some(groups) = &groups {
+            w.write_bits(1, 1)?;
+            w.write_bits(u64::from(groups.bits - 2), 3)?;
+            let xsize = subsample_size(width, groups.bits);
+            write_image_stream(w, &groups.entropy_image, xsize, false, settings)?;
+        } else {
+            w.write_bits(0, 1)?;
+        }
     }
-    write_single_entry_huffman_tree(w, 1)?;
 
-    // Write image data
-    let mut it = pixels.chunks_exact(4).peekable();
-    match color {
-        ColorType::L8 => {
-            while let Some(pixel) = it.next() {
-                w.write_bits(
-                    u64::from(codes1[pixel[1] as usize]),
-                    lengths1[pixel[1] as usize],
-                )?;
-                write_run(w, pixel, &mut it, &codes1, &lengths1)?;
-            }
+    // compute and write huffman codes
+    let Some(groups) = groups else {
+        let codes = HuffmanCodes::write(w, &histogram)?;
+        for token in tokens {
+            codes.write_token(w, token)?;
         }
-        ColorType::La8 => {
-            while let Some(pixel) = it.next() {
-                let len1 = lengths1[pixel[1] as usize];
-                let len3 = lengths3[pixel[3] as usize];
+        return Ok(());
+    };
 
-                let code = u64::from(codes1[pixel[1] as usize])
-                    | (u64::from(codes3[pixel[3] as usize]) << len1);
+    let mut codes = Vec::with_capacity(groups.histograms.len());
+    for histogram in &groups.histograms {
+        codes.push(HuffmanCodes::write(w, histogram)?);
+    }
 
-                w.write_bits(code, len1 + len3)?;
-                write_run(w, pixel, &mut it, &codes1, &lengths1)?;
-            }
-        }
-        ColorType::Rgb8 => {
-            while let Some(pixel) = it.next() {
-                let len1 = lengths1[pixel[1] as usize];
-                let len0 = lengths0[pixel[0] as usize];
-                let len2 = lengths2[pixel[2] as usize];
+    // Write image data
+    let xsize = usize::from(subsample_size(width, groups.bits));
+    let mut index = 0;
+    for token in tokens {
+        let x = index % usize::from(width);
+        let y = index / usize::from(width);
+        let group = groups.tile_groups[(y >> groups.bits) * xsize + (x >> groups.bits)];
+        codes[usize::from(group)].write_token(w, token)?;
+        index += token.num_pixels();
+    }
 
-                let code = u64::from(codes1[pixel[1] as usize])
-                    | (u64::from(codes0[pixel[0] as usize]) << len1)
-                    | (u64::from(codes2[pixel[2] as usize]) << (len1 + len0));
+    Ok(())
+}
 
-                w.write_bits(code, len1 + len0 + len2)?;
-                write_run(w, pixel, &mut it, &codes1, &lengths1)?;
-            }
+/// Estimates how expensive it is to code `residuals` after the residuals counted in
+/// `accumulated`.
+///
+/// This is the number of bits the residuals would take with codes built from both histograms,
+/// minus a small bonus for residuals close to zero as they tend to remain cheap in the blocks
+/// that follow.
+///
+/// `histogram` is scratch space and must be all zeros. It is left that way on return.
+fn predictor_cost(
+    residuals: &[[u8; 4]],
+    histogram: &mut [[u32; 256]; 4],
+    accumulated: &[[u32; 256]; 4],
+    accumulated_total: u32,
+) -> f32 {
+    for residual in residuals {
+        for i in 0..4 {
+            histogram[i][residual[i] as usize] += 1;
         }
-        ColorType::Rgba8 => {
-            while let Some(pixel) = it.next() {
-                let len1 = lengths1[pixel[1] as usize];
-                let len0 = lengths0[pixel[0] as usize];
-                let len2 = lengths2[pixel[2] as usize];
-                let len3 = lengths3[pixel[3] as usize];
-
-                let code = u64::from(codes1[pixel[1] as usize])
-                    | (u64::from(codes0[pixel[0] as usize]) << len1)
-                    | (u64::from(codes2[pixel[2] as usize]) << (len1 + len0))
-                    | (u64::from(codes3[pixel[3] as usize]) << (len1 + len0 + len2));
+    }
 
-                w.write_bits(code, len1 + len0 + len2 + len3)?;
-                write_run(w, pixel, &mut it, &codes1, &lengths1)?;
+    let total_bits = ((accumulated_total as usize + residuals.len()) as f32).log2();
+    let mut cost = 0.0;
+    for residual in residuals {
+        for i in 0..4 {
+            let r = residual[i] as usize;
+            let x = histogram[i][r];
+            if x != 0 {
+                let y = accumulated[i][r];
+                cost += x as f32 * (total_bits - ((x + y) as f32).log2() - RESIDUAL_BIAS[r]);
+                histogram[i][r] = 0;
             }
         }
     }
-
-    w.flush()?;
-    Ok(())
+    cost
 }
 
-const fn chunk_size(inner_bytes: usize) -> u32 {
-    if inner_bytes % 2 == 1 {
-        (inner_bytes + 1) as u32 + 8
-    } else {
+/// Bonus given to small residuals by `predictor_cost`, in bits.
+const RESIDUAL_BIAS: [f32; 256] = {
+    let mut bias = [0.0; 256];
+    let mut weight = 1.5;
+    let mut i = 0;
+    while i < 16 {
+        bias[i] = weight;
+        bias[(256 - i) % 256] = weight;
+        weight *= 0.6;
+        i += 1;
+    }
+    bias
+};
+
+/// Chooses the predictor for each `1 << size_bits` sized block of the image.
+///
+/// All 14 predictors are tried on every block and the one with the lowest `predictor_cost` is
+/// kept. Blocks are only compared against the blocks of the rows above them, so the choices
+/// within a row are independent of each other and are spread across up to `threads` threads.
+/// Returns the predictor sub-image, with the selected mode stored in the green channel.
+fn choose_predictors(
+    pixels: &[u8],
+    width: u16,
+    height: u16,
+    size_bits: u8,
+    threads: usize,
+) -> Vec<u8> {
+    let block_xsize = usize::from(subsample_size(width, size_bits));
+    let block_ysize = usize::from(subsample_size(height, size_bits));
+    let width = usize::from(width);
+    let height = usize::from(height);
+
+    // Each block is predicted in all 14 ways.
+    let threads = parallel::threads_for((width << size_bits) * 14, threads);
+    let block_xs: Vec<usize> = (0..block_xsize).collect();
+
+    let mut predictor_data = vec![0; block_xsize * block_ysize * 4];
+    let mut accumulated = Box::new([[0u32; 256]; 4]);
+    let mut accumulated_total = 0;
+    for block_y in 0..block_ysize {
+        // The first row and column of the image ignore the predictor, so skip them.
+        let ys = (block_y << size_bits).max(1)..((block_y + 1) << size_bits).min(height);
+
+        let init = || (Box::new([[0u32; 256]; 4]), Vec::new());
+        let choices = parallel::map_with(&block_xs, threads, init, |scratch, &block_x| {
+            let (histogram, residuals) = scratch;
+            let xs = (block_x << size_bits).max(1)..((block_x + 1) << size_bits).min(width);
+
+            let mut best_mode = 0;
+            let mut best_cost = f32::INFINITY;
+            let mut best_residuals = Vec::new();
+            for mode in 0..14 {
+                residuals.clear();
+                for y in ys.clone() {
+                    for x in xs.clone() {
+                        let index = (y * width + x) * 4;
+                        let prediction = predict_pixel(pixels, index, width, mode);
+                        residuals.push([
+                            pixels[index].wrapping_sub(prediction[0]),
+                            pixels[index + 1].wrapping_sub(prediction[1]),
+                            pixels[index + 2].wrapping_sub(prediction[2]),
+                            pixels[index + 3].wrapping_sub(prediction[3]),
+                        ]);
+                    }
+                }
+
+                let cost = predictor_cost(residuals, histogram, &accumulated, accumulated_total);
+                if cost < best_cost {
+                    best_mode = mode;
+                    best_cost = cost;
+                    std::mem::swap(residuals, &mut best_residuals);
+                }
+            }
+            (best_mode, best_residuals)
+        });
+
+        for (block_x, (mode, residuals)) in choices.into_iter().enumerate() {
+            predictor_data[(block_y * block_xsize + block_x) * 4 + 1] = mode;
+            accumulated_total += residuals.len() as u32;
+            for residual in residuals {
+                for i in 0..4 {
+                    accumulated[i][residual[i] as usize] += 1;
+                }
+            }
+        }
+    }
+
+    predictor_data
+}
+
+/// Estimates the number of bits needed to code the pixels of the image.
+fn estimate_image_size(pixels: &[u8], width: u16, settings: &MethodSettings) -> f32 {
+    choose_image_coding(pixels, width, settings)
+        .2
+        .estimate_bits()
+}
+
+/// Finds the multiplier that minimizes `cost`, trying steps of decreasing size around the best
+/// multiplier found so far.
+fn search_multiplier(mut cost: impl FnMut(i8) -> f32) -> i8 {
+    let mut best = 0i8;
+    let mut best_cost = cost(best);
+    let mut step = 64;
+    while step > 0 {
+        let center = best;
+        for candidate in [center.saturating_sub(step), center.saturating_add(step)] {
+            let candidate_cost = cost(candidate);
+            if candidate_cost < best_cost {
+                best = candidate;
+                best_cost = candidate_cost;
+            }
+        }
+        step /= 2;
+    }
+    best
+}
+
+/// Chooses the color transform multipliers for each `1 << size_bits` sized block of the image.
+///
+/// The multipliers of each block are picked to minimize the entropy of the red and blue channels
+/// of the block after the transform. Blocks are independent of each other, so they are spread
+/// across up to `threads` threads. Returns the color transform sub-image.
+fn choose_color_transforms(
+    pixels: &[u8],
+    width: u16,
+    height: u16,
+    size_bits: u8,
+    threads: usize,
+) -> Vec<u8> {
+    let block_xsize = usize::from(subsample_size(width, size_bits));
+    let block_ysize = usize::from(subsample_size(height, size_bits));
+    let width = usize::from(width);
+    let height = usize::from(height);
+
+    let threads = parallel::threads_for(width * height, threads);
+    let blocks: Vec<usize> = (0..block_xsize * block_ysize).collect();
+    let multipliers = parallel::map_with(&blocks, threads, Vec::new, |block, &index| {
+        let (block_y, block_x) = (index / block_xsize, index % block_xsize);
+        let ys = (block_y << size_bits)..((block_y + 1) << size_bits).min(height);
+        let xs = (block_x << size_bits)..((block_x + 1) << size_bits).min(width);
+
+        block.clear();
+        for y in ys {
+            let row = &pixels[(y * width + xs.start) * 4..(y * width + xs.end) * 4];
+            block.extend(
+                row.chunks_exact(4)
+                    .map(|p| [p[0] as i8, p[1] as i8, p[2] as i8]),
+            );
+        }
+
+        let mut histogram = [0u32; 256];
+        let mut cost = |delta: &dyn Fn(&[i8; 3]) -> u8| {
+            histogram.fill(0);
+            for pixel in block.iter() {
+                histogram[delta(pixel) as usize] += 1;
+            }
+            estimate_entropy(&histogram)
+        };
+
+        let green_to_red = search_multiplier(|g2r| {
+            cost(&|p| (p[0] as u8).wrapping_sub(color_transform_delta(g2r, p[1]) as u8))
+        });
+        let blue = |g2b: i8, r2b: i8| {
+            move |p: &[i8; 3]| {
+                (p[2] as u8)
+                    .wrapping_sub(color_transform_delta(g2b, p[1]) as u8)
+                    .wrapping_sub(color_transform_delta(r2b, p[0]) as u8)
+            }
+        };
+        let green_to_blue = search_multiplier(|g2b| cost(&blue(g2b, 0)));
+        let red_to_blue = search_multiplier(|r2b| cost(&blue(green_to_blue, r2b)));
+
+        [
+            red_to_blue as u8,
+            green_to_blue as u8,
+            green_to_red as u8,
+            255,
+        ]
+    });
+
+    multipliers.concat()
+}
+
+/// Returns whether every pixel of the image is fully opaque, and whether every pixel has equal
+/// red, green and blue values.
+fn find_opaque_grayscale(pixels: &[u8]) -> (bool, bool) {
+    let mut opaque = true;
+    let mut grayscale = true;
+    for pixel in pixels.chunks_exact(4) {
+        opaque &= pixel[3] == 255;
+        grayscale &= pixel[0] == pixel[1] && pixel[1] == pixel[2];
+    }
+    (opaque, grayscale)
+}
+
+/// Returns the distinct colors of the image, or `None` if there are more than 256 of them.
+fn find_palette(pixels: &[u8]) -> Option<Vec<[u8; 4]>> {
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut last_color = None;
+    for pixel in pixels.chunks_exact(4) {
+        let color = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        if last_color == Some(color) {
+            continue;
+        }
+        last_color = Some(color);
+
+        if let Err(position) = palette.binary_search(&color) {
+            if palette.len() == 256 {
+                return None;
+            }
+            palette.insert(position, color);
+        }
+    }
+    Some(palette)
+}
+
+/// Orders the palette so that consecutive entries are similar.
+///
+/// The palette is delta coded, so this keeps the deltas small. It also tends to give similar
+/// colors similar indices, which helps any transforms applied to the index image afterwards.
+fn order_palette(palette: &mut [[u8; 4]]) {
+    fn distance(a: &[u8; 4], b: &[u8; 4]) -> u32 {
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| {
+                let delta = a.wrapping_sub(b);
+                u32::from(delta.min(delta.wrapping_neg()))
+            })
+            .sum()
+    }
+
+    palette.sort_unstable_by_key(|c| u32::from_be_bytes([c[3], c[0], c[1], c[2]]));
+    for i in 1..palette.len() {
+        let previous = palette[i - 1];
+        let (next, _) = palette[i..]
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, color)| distance(&previous, color))
+            .unwrap();
+        palette[i..].swap(0, next);
+    }
+}
+
+/// Returns the length of the rows of a tightly packed image, after checking that `data` holds
+/// exactly the image.
+fn packed_stride(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> Result<usize, EncodingError> {
+    check_dimensions(width, height)?;
+    let row_bytes = width as usize * color.bytes_per_pixel();
+    if row_bytes * height as usize != data.len() {
+        return Err(EncodingError::InvalidBufferSize);
+    }
+    Ok(row_bytes)
+}
+
+/// Expands image data with the indicated color type to RGBA, where each row starts `stride`
+/// bytes after the previous one.
+pub(crate) fn convert_frame(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    color: ColorType,
+    params: &EncoderParams,
+) -> Result<Vec<u8>, EncodingError> {
+    check_dimensions(width, height)?;
+
+    let row_bytes = width as usize * color.bytes_per_pixel();
+    if stride < row_bytes {
+        return Err(EncodingError::InvalidStride);
+    }
+    // The last row doesn't need to be padded.
+    let min_len = (height as usize - 1)
+        .checked_mul(stride)
+        .and_then(|len| len.checked_add(row_bytes));
+    if min_len.map_or(true, |min_len| data.len() < min_len) {
+        return Err(EncodingError::InvalidBufferSize);
+    }
+
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    let mut converter = RowConverter::new(color, params);
+    for y in 0..height as usize {
+        converter.append_row(&mut pixels, &data[y * stride..][..row_bytes]);
+    }
+    Ok(pixels)
+}
+
+/// Returns an error if the image dimensions are not allowed by the WebP format.
+fn check_dimensions(width: u32, height: u32) -> Result<(), EncodingError> {
+    if width == 0 || width > 16384 || height == 0 || height > 16384 {
+        return Err(EncodingError::InvalidDimensions);
+    }
+    Ok(())
+}
+
+/// Bayer matrix used for ordered dithering, with entries from 0 to 63.
+const BAYER_MATRIX: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Expands rows of input to RGBA, one after the other, reducing 16-bit values to 8 bits.
+struct RowConverter {
+    color: ColorType,
+    exact: bool,
+    dithering: Dithering,
+    /// Divide the color channels by alpha.
+    unpremultiply: bool,
+    /// Index of the next row.
+    y: usize,
+    /// Values of a 16-bit row.
+    values: Vec<u32>,
+    /// Row reduced to 8 bits per channel.
+    reduced: Vec<u8>,
+    /// Rounding errors diffused into the current and the next row, in sixteenths of a 16-bit
+    /// step.
+    errors: [Vec<i32>; 2],
+}
+
+impl RowConverter {
+    fn new(color: ColorType, params: &EncoderParams) -> Self {
+        Self {
+            color,
+            exact: params.exact,
+            dithering: params.dithering,
+            unpremultiply: params.premultiplied_alpha && color.has_alpha(),
+            y: 0,
+            values: Vec::new(),
+            reduced: Vec::new(),
+            errors: [Vec::new(), Vec::new()],
+        }
+    }
+
+    /// Appends the pixels of the next row of the image to `pixels`, expanded to RGBA.
+    fn append_row(&mut self, pixels: &mut Vec<u8>, row: &[u8]) {
+        let Some(reduced_color) = self.color.reduced() else {
+            let start = pixels.len();
+            append_rgba(pixels, row, self.color, self.exact);
+            if self.unpremultiply {
+                for pixel in pixels[start..].chunks_exact_mut(4) {
+                    let alpha = u32::from(pixel[3]);
+                    for value in &mut pixel[..3] {
+                        *value = unpremultiply(u32::from(*value), alpha, 255) as u8;
+                    }
+                }
+            }
+            return;
+        };
+
+        let channels = reduced_color.bytes_per_pixel();
+        self.values.clear();
+        self.values.extend(
+            row.chunks_exact(2)
+                .map(|v| u32::from(u16::from_ne_bytes([v[0], v[1]]))),
+        );
+        if self.unpremultiply {
+            for pixel in self.values.chunks_exact_mut(channels) {
+                let (alpha, colors) = pixel.split_last_mut().unwrap();
+                for value in colors {
+                    *value = unpremultiply(*value, *alpha, 65535);
+                }
+            }
+        }
+
+        let values = self.values.iter().copied();
+        self.reduced.clear();
+        match self.dithering {
+            // 65535 / 255 = 257
+            Dithering::Round => self.reduced.extend(values.map(|v| ((v + 128) / 257) as u8)),
+            Dithering::Ordered => {
+                let pattern = &BAYER_MATRIX[self.y % 8];
+                self.reduced.extend(values.enumerate().map(|(i, v)| {
+                    let offset = 2 * u32::from(pattern[i / channels % 8]) + 1;
+                    ((v * 128 + offset * 257) / (257 * 128)) as u8
+                }));
+            }
+            Dithering::ErrorDiffusion => {
+                let len = row.len() / 2;
+                let [current, next] = &mut self.errors;
+                current.resize(len, 0);
+                next.clear();
+                next.resize(len, 0);
+                for (i, v) in values.enumerate() {
+                    let target = v as i32 + current[i] / 16;
+                    let value = ((target + 128).div_euclid(257)).clamp(0, 255);
+                    self.reduced.push(value as u8);
+
+                    let error = target - value * 257;
+                    let (first, last) = (i < channels, i + channels >= len);
+                    if !last {
+                        current[i + channels] += error * 7;
+                        next[i + channels] += error;
+                    }
+                    if !first {
+                        next[i - channels] += error * 3;
+                    }
+                    next[i] += error * 5;
+                }
+                self.errors.swap(0, 1);
+            }
+        }
+        self.y += 1;
+
+        append_rgba(pixels, &self.reduced, reduced_color, self.exact);
+    }
+}
+
+/// Divides a color value premultiplied by `alpha` by it, where `max` is both the largest value
+/// and full opacity. Rounds to the nearest value.
+fn unpremultiply(value: u32, alpha: u32, max: u32) -> u32 {
+    if alpha == 0 {
+        return 0;
+    }
+    let value = (u64::from(value) * u64::from(max) + u64::from(alpha / 2)) / u64::from(alpha);
+    value.min(u64::from(max)) as u32
+}
+
+/// Appends the pixels in `data` to `pixels`, expanded to RGBA.
+///
+/// Unless `exact` is set, fully transparent pixels are all given the same color, since it can't
+/// be seen anyway.
+fn append_rgba(pixels: &mut Vec<u8>, data: &[u8], color: ColorType, exact: bool) {
+    let start = pixels.len();
+    match color {
+        ColorType::L8 => pixels.extend(data.iter().flat_map(|&p| [p, p, p, 255])),
+        ColorType::La8 => {
+            pixels.extend(data.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]))
+        }
+        ColorType::Rgb8 => {
+            pixels.extend(data.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]))
+        }
+        ColorType::Rgba8 => pixels.extend_from_slice(data),
+        ColorType::Bgr8 => {
+            pixels.extend(data.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0], 255]))
+        }
+        ColorType::Bgra8 => {
+            pixels.extend(data.chunks_exact(4).flat_map(|p| [p[2], p[1], p[0], p[3]]))
+        }
+        ColorType::Argb32 => pixels.extend(data.chunks_exact(4).flat_map(|p| {
+            let [a, r, g, b] = u32::from_ne_bytes(p.try_into().unwrap()).to_be_bytes();
+            [r, g, b, a]
+        })),
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+            unreachable!("16-bit rows are reduced by RowConverter")
+        }
+    }
+
+    if color.has_alpha() && !exact {
+        for pixel in pixels[start..]
+            .chunks_exact_mut(4)
+            .filter(|pixel| pixel[3] == 0)
+        {
+            pixel.fill(0);
+        }
+    }
+}
+
+/// Encodes an image with the indicated color type that has already been expanded to RGBA.
+///
+/// Returns whether the alpha channel is used.
+pub(crate) fn encode_rgba_frame<W: Write>(
+    writer: W,
+    pixels: Vec<u8>,
+    width: u16,
+    height: u16,
+    color: ColorType,
+    params: &EncoderParams,
+) -> Result<bool, EncodingError> {
+    let (is_alpha, is_grayscale) = if params.analyze {
+        let (opaque, grayscale) = find_opaque_grayscale(&pixels);
+        (!opaque, grayscale)
+    } else {
+        (color.has_alpha(), color.is_grayscale())
+    };
+
+    let w = &mut BitWriter {
+        writer,
+        buffer: 0,
+        nbits: 0,
+    };
+
+    w.write_bits(0x2f, 8)?; // signature
+    w.write_bits(u64::from(width) - 1, 14)?;
+    w.write_bits(u64::from(height) - 1, 14)?;
+
+    w.write_bits(u64::from(is_alpha), 1)?; // alpha used
+    w.write_bits(0x0, 3)?; // version
+
+    let settings = MethodSettings::new(params);
+
+    let palette = find_palette(&pixels).map(|mut palette| {
+        order_palette(&mut palette);
+        palette
+    });
+    // Coding the palette itself isn't worth it if most pixels have a color of their own.
+    let use_palette = palette
+        .as_ref()
+        .is_some_and(|p| p.len() * 2 <= pixels.len() / 4);
+
+    let default_transforms = Transforms {
+        color_indexing: false,
+        subtract_green: true,
+        predictor: params.use_predictor_transform,
+        // Gray pixels have nothing left to decorrelate after subtracting green.
+        cross_color: params.use_color_transform && settings.use_color_transform && !is_grayscale,
+        near_lossless_bits: near_lossless_bits(params.near_lossless),
+        exact: params.exact,
+    };
+    let palette_transforms = Transforms {
+        color_indexing: true,
+        subtract_green: false,
+        predictor: params.use_predictor_transform,
+        cross_color: false,
+        near_lossless_bits: 0,
+        exact: true,
+    };
+
+    let mut candidates = Vec::new();
+    if settings.try_transforms {
+        if palette.is_some() {
+            candidates.push(palette_transforms);
+        }
+        candidates.push(default_transforms);
+        if settings.try_all_transforms {
+            for subtract_green in [true, false] {
+                for predictor in [default_transforms.predictor, false] {
+                    for cross_color in [default_transforms.cross_color, false] {
+                        let transforms = Transforms {
+                            subtract_green,
+                            predictor,
+                            cross_color,
+                            ..default_transforms
+                        };
+                        if !candidates.contains(&transforms) {
+                            candidates.push(transforms);
+                        }
+                    }
+                }
+            }
+        }
+    } else if use_palette {
+        candidates.push(palette_transforms);
+    } else {
+        candidates.push(default_transforms);
+    }
+
+    let palette = palette.as_deref().unwrap_or_default();
+    if let [transforms] = candidates[..] {
+        write_transforms_and_image(
+            w,
+            Cow::Owned(pixels),
+            width,
+            height,
+            palette,
+            transforms,
+            &settings,
+        )?;
+    } else {
+        // The header ends on a byte boundary, so each candidate can be written to its own buffer.
+        // They are encoded side by side, each with its share of the threads, and only the
+        // smallest one so far is kept.
+        debug_assert_eq!(w.nbits % 8, 0);
+        let candidate_settings = MethodSettings {
+            threads: (settings.threads / candidates.len()).max(1),
+            ..settings
+        };
+        let encode = |&transforms: &Transforms| {
+            let mut candidate = BitWriter {
+                writer: Vec::new(),
+                buffer: 0,
+                nbits: 0,
+            };
+            write_transforms_and_image(
+                &mut candidate,
+                Cow::Borrowed(&pixels),
+                width,
+                height,
+                palette,
+                transforms,
+                &candidate_settings,
+            )?;
+            candidate.flush()?;
+            Ok::<_, io::Error>(candidate.writer)
+        };
+        let smaller = |a: io::Result<Vec<u8>>, b: io::Result<Vec<u8>>| match (a, b) {
+            (Ok(a), Ok(b)) => Ok(if b.len() < a.len() { b } else { a }),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+        let best = parallel::map_reduce(&candidates, settings.threads, encode, smaller).unwrap()?;
+        for byte in best {
+            w.write_bits(u64::from(byte), 8)?;
+        }
+    }
+
+    w.flush()?;
+    Ok(is_alpha)
+}
+
+/// Transforms applied to an image before entropy coding it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Transforms {
+    /// Replace colors by indices into the palette. Excludes the subtract green and cross-color
+    /// transforms.
+    color_indexing: bool,
+    subtract_green: bool,
+    predictor: bool,
+    cross_color: bool,
+    /// Number of bits the residuals of the predictor transform may be quantized by.
+    near_lossless_bits: u8,
+    /// Keep the color of fully transparent pixels.
+    exact: bool,
+}
+
+/// Predictor used for every block when predictors aren't chosen per block.
+const FIXED_PREDICTOR: u8 = 11;
+
+/// Applies `transforms` to the image and writes them, followed by the entropy coded image.
+///
+/// The pixels are only copied if they are borrowed and a transform changes them in place.
+fn write_transforms_and_image<W: Write>(
+    w: &mut BitWriter<W>,
+    mut pixels: Cow<'_, [u8]>,
+    mut width: u16,
+    height: u16,
+    palette: &[[u8; 4]],
+    transforms: Transforms,
+    settings: &MethodSettings,
+) -> io::Result<()> {
+    if transforms.color_indexing {
+        // color indexing transform
+        w.write_bits(0b111, 3)?;
+        w.write_bits(palette.len() as u64 - 1, 8)?;
+        let mut palette_data = Vec::with_capacity(palette.len() * 4);
+        let mut previous = [0; 4];
+        for color in palette.iter() {
+            for i in 0..4 {
+                palette_data.push(color[i].wrapping_sub(previous[i]));
+            }
+            previous = *color;
+        }
+        write_image_stream(w, &palette_data, palette.len() as u16, false, settings)?;
+
+        let mut indices: Vec<_> = palette
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (u32::from_be_bytes([c[3], c[0], c[1], c[2]]), i as u8))
+            .collect();
+        indices.sort_unstable();
+        let indexed;
+        (indexed, width) = forward_color_indexing_transform(&pixels, width, height, &indices);
+        pixels = Cow::Owned(indexed);
+    }
+
+    if transforms.subtract_green {
+        w.write_bits(0b101, 3)?;
+        for pixel in pixels.to_mut().chunks_exact_mut(4) {
+            pixel[0] = pixel[0].wrapping_sub(pixel[1]);
+            pixel[2] = pixel[2].wrapping_sub(pixel[1]);
+        }
+    }
+
+    // predictor transform
+    if transforms.predictor && (!transforms.color_indexing || palette.len() > 16) {
+        let size_bits = settings.predictor_size_bits;
+        let predictor_data = if settings.choose_predictors {
+            choose_predictors(&pixels, width, height, size_bits, settings.threads)
+        } else {
+            let num_blocks = usize::from(subsample_size(width, size_bits))
+                * usize::from(subsample_size(height, size_bits));
+            [0, FIXED_PREDICTOR, 0, 0].repeat(num_blocks)
+        };
+
+        // Indices of unrelated colors often predict poorly, so only keep the transform for
+        // palette images if it makes the image cheaper to code. Other images are transformed in
+        // place.
+        let mut residuals = if transforms.color_indexing {
+            pixels.to_vec()
+        } else {
+            std::mem::take(&mut pixels).into_owned()
+        };
+        if transforms.near_lossless_bits > 0 || !transforms.exact {
+            forward_lossy_predictor_transform(
+                &mut residuals,
+                width,
+                height,
+                size_bits,
+                &predictor_data,
+                transforms.subtract_green,
+                transforms.near_lossless_bits,
+                transforms.exact,
+            );
+        } else {
+            forward_predictor_transform(&mut residuals, width, height, size_bits, &predictor_data);
+        }
+        if !transforms.color_indexing
+            || estimate_image_size(&residuals, width, settings)
+                < estimate_image_size(&pixels, width, settings)
+        {
+            w.write_bits(0b001, 3)?;
+            w.write_bits(u64::from(size_bits - 2), 3)?;
+            let xsize = subsample_size(width, size_bits);
+            write_image_stream(w, &predictor_data, xsize, false, settings)?;
+            pixels = Cow::Owned(residuals);
+        }
+    }
+
+    // color transform
+    if transforms.cross_color {
+        let size_bits = COLOR_TRANSFORM_SIZE_BITS;
+        let transform_data =
+            choose_color_transforms(&pixels, width, height, size_bits, settings.threads);
+
+        w.write_bits(0b011, 3)?;
+        w.write_bits(u64::from(size_bits - 2), 3)?;
+        let xsize = subsample_size(width, size_bits);
+        write_image_stream(w, &transform_data, xsize, false, settings)?;
+
+        forward_color_transform(pixels.to_mut(), width, size_bits, &transform_data);
+    }
+
+    // transforms done
+    w.write_bits(0x0, 1)?;
+
+    write_image_stream(w, &pixels, width, true, settings)
+}
+
+pub(crate) const fn chunk_size(inner_bytes: usize) -> u32 {
+    if inner_bytes % 2 == 1 {
+        (inner_bytes + 1) as u32 + 8
+    } else {
         inner_bytes as u32 + 8
     }
 }
 
-fn write_chunk<W: Write>(mut w: W, name: &[u8], data: &[u8]) -> io::Result<()> {
+pub(crate) fn write_chunk<W: Write>(mut w: W, name: &[u8], data: &[u8]) -> io::Result<()> {
     debug_assert!(name.len() == 4);
 
     w.write_all(name)?;
@@ -616,6 +1830,50 @@ fn write_chunk<W: Write>(mut w: W, name: &[u8], data: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
+/// Largest width and height of a lossy image.
+const MAX_LOSSY_DIMENSION: u32 = 16383;
+
+/// Quality that a search for a target size or PSNR starts at, if none is set.
+const DEFAULT_LOSSY_QUALITY: u8 = 75;
+
+/// An encoded image, as the contents of the chunks that hold it, except for the frame which is
+/// written separately.
+struct EncodedImage {
+    /// Size of the contents of the `VP8L` chunk, or the `VP8 ` chunk of a lossy image.
+    frame_len: usize,
+    lossy: bool,
+    /// The contents of the `ALPH` chunk of a lossy image with transparency.
+    alpha: Option<Vec<u8>>,
+    is_alpha: bool,
+}
+
+/// Encodes the alpha channel of an image as the contents of an `ALPH` chunk: a lossless image
+/// without the header, with the alpha values stored in green.
+fn encode_alpha(
+    pixels: &[u8],
+    width: u16,
+    height: u16,
+    params: &EncoderParams,
+) -> Result<Vec<u8>, EncodingError> {
+    let alpha = pixels
+        .chunks_exact(4)
+        .flat_map(|p| [0, p[3], 0, 255])
+        .collect();
+    let params = EncoderParams {
+        near_lossless: 100,
+        analyze: false,
+        ..params.clone()
+    };
+    let mut frame = Vec::new();
+    encode_rgba_frame(&mut frame, alpha, width, height, ColorType::L8, &params)?;
+
+    // The header takes exactly 5 bytes, so the image data starts on a byte boundary. It is
+    // preceded by a byte that selects lossless compression without filtering or preprocessing.
+    let mut chunk = vec![1];
+    chunk.extend_from_slice(&frame[5..]);
+    Ok(chunk)
+}
+
 /// WebP Encoder.
 pub struct WebPEncoder<W> {
     writer: W,
@@ -628,7 +1886,7 @@ pub struct WebPEncoder<W> {
 impl<W: Write> WebPEncoder<W> {
     /// Create a new encoder that writes its output to `w`.
     ///
-    /// Only supports "VP8L" lossless encoding.
+    /// Images are encoded losslessly, unless [`EncoderParams::lossy`] is set.
     pub fn new(w: W) -> Self {
         Self {
             writer: w,
@@ -661,71 +1919,256 @@ impl<W: Write> WebPEncoder<W> {
 
     /// Encode image data with the indicated color type.
     ///
-    /// # Panics
-    ///
-    /// Panics if the image data is not of the indicated dimensions.
+    /// The rows of the image must be tightly packed. Returns
+    /// [`EncodingError::InvalidBufferSize`] if the image data is not of the indicated dimensions.
     pub fn encode(
-        mut self,
+        self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        color: ColorType,
+    ) -> Result<(), EncodingError> {
+        let stride = packed_stride(data, width, height, color)?;
+        self.encode_with_stride(data, width, height, stride, color)
+    }
+
+    /// Encode image data with the indicated color type, where each row starts `stride` bytes
+    /// after the previous one.
+    ///
+    /// Any padding at the end of rows is ignored, and the last row doesn't need to be padded.
+    /// Returns [`EncodingError::InvalidStride`] if `stride` is shorter than a row of pixels and
+    /// [`EncodingError::InvalidBufferSize`] if the image data is too short to hold every row.
+    pub fn encode_with_stride(
+        self,
         data: &[u8],
         width: u32,
         height: u32,
+        stride: usize,
         color: ColorType,
     ) -> Result<(), EncodingError> {
+        let pixels = convert_frame(data, width, height, stride, color, &self.params)?;
         let mut frame = Vec::new();
-        encode_frame(&mut frame, data, width, height, color, self.params)?;
+        let image = self.encode_image(&mut frame, pixels, width, height, color)?;
+        self.write_container(&image, &frame, width, height)
+    }
 
-        // If the image has no metadata, it can be encoded with the "simple" WebP container format.
-        if self.icc_profile.is_empty()
-            && self.exif_metadata.is_empty()
-            && self.xmp_metadata.is_empty()
-        {
-            self.writer.write_all(b"RIFF")?;
-            self.writer
-                .write_all(&(chunk_size(frame.len()) + 4).to_le_bytes())?;
-            self.writer.write_all(b"WEBP")?;
-            write_chunk(&mut self.writer, b"VP8L", &frame)?;
-        } else {
-            let mut total_bytes = 22 + chunk_size(frame.len());
-            if !self.icc_profile.is_empty() {
-                total_bytes += chunk_size(self.icc_profile.len());
-            }
-            if !self.exif_metadata.is_empty() {
-                total_bytes += chunk_size(self.exif_metadata.len());
-            }
-            if !self.xmp_metadata.is_empty() {
-                total_bytes += chunk_size(self.xmp_metadata.len());
-            }
+    /// Compute the number of bytes [`WebPEncoder::encode()`] would write for an image, without
+    /// writing anything.
+    ///
+    /// The image is fully encoded, so this takes as long as encoding, but the output is only
+    /// counted instead of stored.
+    pub fn estimate_size(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        color: ColorType,
+    ) -> Result<u64, EncodingError> {
+        let stride = packed_stride(data, width, height, color)?;
+        let pixels = convert_frame(data, width, height, stride, color, &self.params)?;
+        let image = self.encode_image(io::sink(), pixels, width, height, color)?;
+        // The RIFF header isn't included in the size it stores.
+        Ok(8 + u64::from(self.riff_size(&image)))
+    }
 
-            let mut flags = 0;
-            if !self.xmp_metadata.is_empty() {
-                flags |= 1 << 2;
-            }
-            if !self.exif_metadata.is_empty() {
-                flags |= 1 << 3;
-            }
-            if let ColorType::La8 | ColorType::Rgba8 = color {
-                flags |= 1 << 4;
-            }
-            if !self.icc_profile.is_empty() {
-                flags |= 1 << 5;
-            }
+    /// Find the properties of an image, as it would be encoded with the current parameters.
+    ///
+    /// This takes the same arguments as [`WebPEncoder::encode()`] and only takes a pass over the
+    /// pixels to find whether they are opaque and gray, and another to count colors that stops
+    /// after 256. So it is much cheaper than encoding, and can be used to log what
+    /// [`EncoderParams::analyze`] detects.
+    pub fn analyze(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        color: ColorType,
+    ) -> Result<ImageProperties, EncodingError> {
+        let stride = packed_stride(data, width, height, color)?;
+        let pixels = convert_frame(data, width, height, stride, color, &self.params)?;
+        let (opaque, grayscale) = find_opaque_grayscale(&pixels);
+        Ok(ImageProperties {
+            opaque,
+            grayscale,
+            unique_colors: find_palette(&pixels).map(|palette| palette.len()),
+        })
+    }
 
-            self.writer.write_all(b"RIFF")?;
-            self.writer.write_all(&total_bytes.to_le_bytes())?;
-            self.writer.write_all(b"WEBP")?;
+    /// Start encoding an image that is passed one row at a time, for example straight from a
+    /// renderer or a decoder, instead of all at once.
+    ///
+    /// Rows are expanded to RGBA as they arrive, with 16-bit rows reduced to 8 bits, so no copy of
+    /// the input is kept: the encoder holds 4 bytes per pixel for the rows passed so far.
+    ///
+    /// Nothing can be compressed before the whole frame is there. Lossless (VP8L) images store
+    /// their transforms and Huffman codes ahead of the pixels, and these are chosen from the
+    /// entire image, as are the segments and loop filter of lossy images. So the image is
+    /// compressed and written once all rows have been passed to the returned [`WebPRowEncoder`]
+    /// and [`WebPRowEncoder::finish()`] is called. Memory use peaks at the 4 bytes per pixel of
+    /// the frame plus what compressing it takes, which is the same as with
+    /// [`WebPEncoder::encode()`] without the input image.
+    pub fn encode_rows(
+        self,
+        width: u32,
+        height: u32,
+        color: ColorType,
+    ) -> Result<WebPRowEncoder<W>, EncodingError> {
+        check_dimensions(width, height)?;
+        Ok(WebPRowEncoder {
+            pixels: Vec::with_capacity(width as usize * height as usize * 4),
+            converter: RowConverter::new(color, &self.params),
+            encoder: self,
+            width,
+            height,
+            color,
+        })
+    }
 
-            let mut vp8x = Vec::new();
-            vp8x.write_all(&[flags])?; // flags
-            vp8x.write_all(&[0; 3])?; // reserved
-            vp8x.write_all(&(width - 1).to_le_bytes()[..3])?; // canvas width
-            vp8x.write_all(&(height - 1).to_le_bytes()[..3])?; // canvas height
-            write_chunk(&mut self.writer, b"VP8X", &vp8x)?;
+    /// Encodes an image that has already been expanded to RGBA, as set by the parameters, and
+    /// writes its frame to `frame`.
+    fn encode_image<F: Write>(
+        &self,
+        frame: F,
+        pixels: Vec<u8>,
+        width: u32,
+        height: u32,
+        color: ColorType,
+    ) -> Result<EncodedImage, EncodingError> {
+        let params = &self.params;
+        if params.lossy.is_none() && params.target_size.is_none() && params.target_psnr.is_none() {
+            let mut counter = ByteCounter {
+                writer: frame,
+                count: 0,
+            };
+            let is_alpha = encode_rgba_frame(
+                &mut counter,
+                pixels,
+                width as u16,
+                height as u16,
+                color,
+                params,
+            )?;
+            return Ok(EncodedImage {
+                frame_len: counter.count,
+                lossy: false,
+                alpha: None,
+                is_alpha,
+            });
+        }
+
+        if width > MAX_LOSSY_DIMENSION || height > MAX_LOSSY_DIMENSION {
+            return Err(EncodingError::InvalidDimensions);
+        }
+        let alpha = if pixels.chunks_exact(4).any(|p| p[3] != 255) {
+            Some(encode_alpha(&pixels, width as u16, height as u16, params)?)
+        } else {
+            None
+        };
+        let mut image = EncodedImage {
+            frame_len: 0,
+            lossy: true,
+            is_alpha: alpha.is_some(),
+            alpha,
+        };
+
+        let quality = params.lossy.unwrap_or(DEFAULT_LOSSY_QUALITY);
+        let target = if let Some(size) = params.target_size {
+            // Everything but the frame takes the same space whatever the frame holds, and the
+            // frame is padded to an even size.
+            let rest = 8 + self.riff_size(&image) as usize;
+            vp8_encoder::Target::Size(size.saturating_sub(rest) & !1, quality)
+        } else if let Some(psnr) = params.target_psnr {
+            vp8_encoder::Target::Psnr(f64::from(psnr), quality)
+        } else {
+            vp8_encoder::Target::Quality(quality)
+        };
+        image.frame_len =
+            vp8_encoder::encode_frame(frame, &pixels, width as u16, height as u16, target, params)?;
+        Ok(image)
+    }
+
+    /// Whether the image needs the extended WebP container format, for metadata or for the
+    /// alpha channel of a lossy image.
+    fn is_extended(&self, image: &EncodedImage) -> bool {
+        !self.icc_profile.is_empty()
+            || !self.exif_metadata.is_empty()
+            || !self.xmp_metadata.is_empty()
+            || image.alpha.is_some()
+    }
+
+    /// Size of the RIFF chunk that holds an encoded image, along with any metadata, as stored in
+    /// the RIFF header.
+    fn riff_size(&self, image: &EncodedImage) -> u32 {
+        // Otherwise, the image can be encoded with the "simple" WebP container format.
+        if !self.is_extended(image) {
+            return chunk_size(image.frame_len) + 4;
+        }
+
+        let mut total_bytes = 22 + chunk_size(image.frame_len);
+        if let Some(alpha) = &image.alpha {
+            total_bytes += chunk_size(alpha.len());
+        }
+        if !self.icc_profile.is_empty() {
+            total_bytes += chunk_size(self.icc_profile.len());
+        }
+        if !self.exif_metadata.is_empty() {
+            total_bytes += chunk_size(self.exif_metadata.len());
+        }
+        if !self.xmp_metadata.is_empty() {
+            total_bytes += chunk_size(self.xmp_metadata.len());
+        }
+        total_bytes
+    }
+
+    /// Wraps an encoded image, with its `frame`, in a WebP container, along with any metadata.
+    fn write_container(
+        mut self,
+        image: &EncodedImage,
+        frame: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<(), EncodingError> {
+        let total_bytes = self.riff_size(image);
+        let frame_chunk = if image.lossy { b"VP8 " } else { b"VP8L" };
+        if !self.is_extended(image) {
+            self.writer.write_all(b"RIFF")?;
+            self.writer.write_all(&total_bytes.to_le_bytes())?;
+            self.writer.write_all(b"WEBP")?;
+            write_chunk(&mut self.writer, frame_chunk, frame)?;
+        } else {
+            let mut flags = 0;
+            if !self.xmp_metadata.is_empty() {
+                flags |= 1 << 2;
+            }
+            if !self.exif_metadata.is_empty() {
+                flags |= 1 << 3;
+            }
+            if image.is_alpha {
+                flags |= 1 << 4;
+            }
+            if !self.icc_profile.is_empty() {
+                flags |= 1 << 5;
+            }
+
+            self.writer.write_all(b"RIFF")?;
+            self.writer.write_all(&total_bytes.to_le_bytes())?;
+            self.writer.write_all(b"WEBP")?;
+
+            let mut vp8x = Vec::new();
+            vp8x.write_all(&[flags])?; // flags
+            vp8x.write_all(&[0; 3])?; // reserved
+            vp8x.write_all(&(width - 1).to_le_bytes()[..3])?; // canvas width
+            vp8x.write_all(&(height - 1).to_le_bytes()[..3])?; // canvas height
+            write_chunk(&mut self.writer, b"VP8X", &vp8x)?;
 
             if !self.icc_profile.is_empty() {
                 write_chunk(&mut self.writer, b"ICCP", &self.icc_profile)?;
             }
 
-            write_chunk(&mut self.writer, b"VP8L", &frame)?;
+            if let Some(alpha) = &image.alpha {
+                write_chunk(&mut self.writer, b"ALPH", alpha)?;
+            }
+            write_chunk(&mut self.writer, frame_chunk, frame)?;
 
             if !self.exif_metadata.is_empty() {
                 write_chunk(&mut self.writer, b"EXIF", &self.exif_metadata)?;
@@ -740,6 +2183,57 @@ impl<W: Write> WebPEncoder<W> {
     }
 }
 
+/// Encoder for an image that is passed one row at a time.
+///
+/// Created by [`WebPEncoder::encode_rows()`].
+pub struct WebPRowEncoder<W> {
+    encoder: WebPEncoder<W>,
+    width: u32,
+    height: u32,
+    color: ColorType,
+    converter: RowConverter,
+    /// The rows passed so far, expanded to RGBA.
+    pixels: Vec<u8>,
+}
+
+impl<W: Write> WebPRowEncoder<W> {
+    /// Pass the next row of the image, with the color type given to
+    /// [`WebPEncoder::encode_rows()`].
+    pub fn write_row(&mut self, row: &[u8]) -> Result<(), EncodingError> {
+        if row.len() as u64 != u64::from(self.width) * self.color.bytes_per_pixel() as u64 {
+            return Err(EncodingError::InvalidRowLength);
+        }
+        if self.rows_remaining() == 0 {
+            return Err(EncodingError::RowCountMismatch);
+        }
+        self.converter.append_row(&mut self.pixels, row);
+        Ok(())
+    }
+
+    /// Number of rows that still need to be passed.
+    pub fn rows_remaining(&self) -> u32 {
+        self.height - (self.pixels.len() / 4 / self.width as usize) as u32
+    }
+
+    /// Encode the image and write it out, once all rows have been passed.
+    pub fn finish(self) -> Result<(), EncodingError> {
+        if self.rows_remaining() != 0 {
+            return Err(EncodingError::RowCountMismatch);
+        }
+
+        let mut frame = Vec::new();
+        let image = self.encoder.encode_image(
+            &mut frame,
+            self.pixels,
+            self.width,
+            self.height,
+            self.color,
+        )?;
+        self.encoder
+            .write_container(&image, &frame, self.width, self.height)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::RngCore;
@@ -752,7 +2246,12 @@ mod tests {
         rand::thread_rng().fill_bytes(&mut img);
 
         let mut output = Vec::new();
-        WebPEncoder::new(&mut output)
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_params(EncoderParams {
+            exact: true,
+            ..Default::default()
+        });
+        encoder
             .encode(&img, 256, 256, crate::ColorType::Rgba8)
             .unwrap();
 
@@ -787,13 +2286,1164 @@ mod tests {
         assert_eq!(Some(exif), exif2);
     }
 
+    #[test]
+    fn write_rows() {
+        let (width, height) = (37, 21);
+        let mut img = vec![0; width * height * 2];
+        rand::thread_rng().fill_bytes(&mut img);
+        for pixel in img.chunks_exact_mut(2).step_by(3) {
+            pixel[1] = 0;
+        }
+
+        let mut expected = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut expected);
+        encoder.set_icc_profile(vec![1; 5]);
+        encoder
+            .encode(&img, width as u32, height as u32, crate::ColorType::La8)
+            .unwrap();
+
+        let mut output = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_icc_profile(vec![1; 5]);
+        let mut rows = encoder
+            .encode_rows(width as u32, height as u32, crate::ColorType::La8)
+            .unwrap();
+        assert!(matches!(
+            rows.write_row(&img[..width]),
+            Err(EncodingError::InvalidRowLength)
+        ));
+        for row in img.chunks_exact(width * 2) {
+            assert!(rows.rows_remaining() > 0);
+            rows.write_row(row).unwrap();
+        }
+        assert_eq!(rows.rows_remaining(), 0);
+        assert!(matches!(
+            rows.write_row(&img[..width * 2]),
+            Err(EncodingError::RowCountMismatch)
+        ));
+        rows.finish().unwrap();
+        assert_eq!(output, expected);
+
+        // 16-bit rows are reduced as they arrive, which diffuses errors like a whole image.
+        let mut wide = vec![0; width * height * 8];
+        rand::thread_rng().fill_bytes(&mut wide);
+        let params = EncoderParams {
+            dithering: Dithering::ErrorDiffusion,
+            premultiplied_alpha: true,
+            ..Default::default()
+        };
+        let mut expected = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut expected);
+        encoder.set_params(params.clone());
+        encoder
+            .encode(&wide, width as u32, height as u32, crate::ColorType::Rgba16)
+            .unwrap();
+
+        let mut output = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_params(params);
+        let mut rows = encoder
+            .encode_rows(width as u32, height as u32, crate::ColorType::Rgba16)
+            .unwrap();
+        for row in wide.chunks_exact(width * 8) {
+            rows.write_row(row).unwrap();
+        }
+        rows.finish().unwrap();
+        assert_eq!(output, expected);
+
+        let encoder = WebPEncoder::new(Vec::new());
+        let mut rows = encoder
+            .encode_rows(width as u32, height as u32, crate::ColorType::La8)
+            .unwrap();
+        rows.write_row(&img[..width * 2]).unwrap();
+        assert!(matches!(
+            rows.finish(),
+            Err(EncodingError::RowCountMismatch)
+        ));
+    }
+
+    #[test]
+    fn write_strided_layouts() {
+        let (width, height) = (29, 17);
+        let mut img = vec![0; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut img);
+
+        let encode = |data: &[u8], stride: usize, color| {
+            let mut output = Vec::new();
+            let encoder = WebPEncoder::new(&mut output);
+            encoder
+                .encode_with_stride(data, width as u32, height as u32, stride, color)
+                .map(|()| output)
+        };
+        let expected = encode(&img, width * 4, crate::ColorType::Rgba8).unwrap();
+
+        // Padded rows, with the last row left unpadded.
+        let stride = width * 4 + 13;
+        let mut bgra = vec![0xaa; stride * height];
+        let mut argb = vec![0xaa; stride * height];
+        for (y, row) in img.chunks_exact(width * 4).enumerate() {
+            for (x, p) in row.chunks_exact(4).enumerate() {
+                let index = y * stride + x * 4;
+                bgra[index..][..4].copy_from_slice(&[p[2], p[1], p[0], p[3]]);
+                let value = u32::from_be_bytes([p[3], p[0], p[1], p[2]]);
+                argb[index..][..4].copy_from_slice(&value.to_ne_bytes());
+            }
+        }
+        bgra.truncate(stride * (height - 1) + width * 4);
+        assert_eq!(
+            encode(&bgra, stride, crate::ColorType::Bgra8).unwrap(),
+            expected
+        );
+        assert_eq!(
+            encode(&argb, stride, crate::ColorType::Argb32).unwrap(),
+            expected
+        );
+
+        let rgb: Vec<u8> = img
+            .chunks_exact(4)
+            .flat_map(|p| [p[0], p[1], p[2]])
+            .collect();
+        let bgr: Vec<u8> = img
+            .chunks_exact(4)
+            .flat_map(|p| [p[2], p[1], p[0]])
+            .collect();
+        assert_eq!(
+            encode(&bgr, width * 3, crate::ColorType::Bgr8).unwrap(),
+            encode(&rgb, width * 3, crate::ColorType::Rgb8).unwrap()
+        );
+
+        assert!(matches!(
+            encode(&bgra[1..], stride, crate::ColorType::Bgra8),
+            Err(EncodingError::InvalidBufferSize)
+        ));
+        assert!(matches!(
+            encode(&bgra, width * 4 - 1, crate::ColorType::Bgra8),
+            Err(EncodingError::InvalidStride)
+        ));
+        assert!(matches!(
+            WebPEncoder::new(Vec::new()).encode(
+                &img[4..],
+                width as u32,
+                height as u32,
+                crate::ColorType::Rgba8
+            ),
+            Err(EncodingError::InvalidBufferSize)
+        ));
+    }
+
+    #[test]
+    fn write_16_bit() {
+        // A flat color halfway between two 8-bit values, and the extremes.
+        let (width, height) = (64, 32);
+        let mut img = Vec::new();
+        for i in 0..width * height {
+            let value: u16 = match i % width {
+                0..=7 => 0,
+                8..=15 => 65535,
+                _ => 100 * 257 + 128,
+            };
+            img.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        for dithering in [
+            Dithering::Round,
+            Dithering::Ordered,
+            Dithering::ErrorDiffusion,
+        ] {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                dithering,
+                ..Default::default()
+            });
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::L16)
+                .unwrap();
+
+            let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+            assert!(!decoder.has_alpha());
+            let mut decoded = vec![0; width * height * 3];
+            decoder.read_image(&mut decoded).unwrap();
+
+            let mut sum = 0;
+            for (i, pixel) in decoded.chunks_exact(3).enumerate() {
+                assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+                match i % width {
+                    0..=7 => assert_eq!(pixel[0], 0),
+                    8..=15 => assert_eq!(pixel[0], 255),
+                    _ => {
+                        assert!((100..=101).contains(&pixel[0]), "{dithering:?}");
+                        sum += u32::from(pixel[0]);
+                    }
+                }
+            }
+            let mean = sum as f32 / ((width - 16) * height) as f32;
+            match dithering {
+                Dithering::Round => assert_eq!(mean, 100.0),
+                _ => assert!((mean - 100.5).abs() < 0.05, "{dithering:?} {mean}"),
+            }
+        }
+
+        // Every 16-bit color type matches its 8-bit counterpart when the low bytes don't matter.
+        let mut img8 = vec![0; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut img8);
+        let img16: Vec<u8> = img8
+            .iter()
+            .flat_map(|&v| (u16::from(v) * 257).to_ne_bytes())
+            .collect();
+        for (color8, color16) in [
+            (crate::ColorType::L8, crate::ColorType::L16),
+            (crate::ColorType::La8, crate::ColorType::La16),
+            (crate::ColorType::Rgb8, crate::ColorType::Rgb16),
+            (crate::ColorType::Rgba8, crate::ColorType::Rgba16),
+        ] {
+            let len = width * height * color8.bytes_per_pixel();
+            let encode = |data: &[u8], color| {
+                let mut output = Vec::new();
+                let mut encoder = WebPEncoder::new(&mut output);
+                encoder.set_params(EncoderParams {
+                    dithering: Dithering::ErrorDiffusion,
+                    ..Default::default()
+                });
+                encoder
+                    .encode(data, width as u32, height as u32, color)
+                    .unwrap();
+                output
+            };
+            assert_eq!(
+                encode(&img8[..len], color8),
+                encode(&img16[..len * 2], color16)
+            );
+        }
+    }
+
+    #[test]
+    fn write_premultiplied() {
+        let premultiply = |value: u32, alpha: u32, max: u32| (value * alpha + max / 2) / max;
+        for alpha in 0..=255 {
+            for value in 0..=alpha {
+                let straight = unpremultiply(value, alpha, 255);
+                assert_eq!(premultiply(straight, alpha, 255), value);
+            }
+        }
+
+        let (width, height) = (32, 16);
+        let mut straight = vec![0; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut straight);
+        for (i, pixel) in straight.chunks_exact_mut(4).enumerate() {
+            match i % 4 {
+                0 => pixel[3] = 0,
+                1 => pixel[3] = 255,
+                _ => {}
+            }
+        }
+        let premultiplied: Vec<u8> = straight
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let alpha = u32::from(p[3]);
+                let [r, g, b] = [0, 1, 2].map(|i| premultiply(u32::from(p[i]), alpha, 255) as u8);
+                [r, g, b, p[3]]
+            })
+            .collect();
+        let premultiplied16: Vec<u8> = premultiplied
+            .iter()
+            .flat_map(|&v| (u16::from(v) * 257).to_ne_bytes())
+            .collect();
+
+        for (img, color) in [
+            (&premultiplied, crate::ColorType::Rgba8),
+            (&premultiplied16, crate::ColorType::Rgba16),
+        ] {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                premultiplied_alpha: true,
+                exact: true,
+                ..Default::default()
+            });
+            encoder
+                .encode(img, width as u32, height as u32, color)
+                .unwrap();
+
+            let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+            let mut decoded = vec![0; width * height * 4];
+            decoder.read_image(&mut decoded).unwrap();
+            for ((pixel, original), input) in decoded
+                .chunks_exact(4)
+                .zip(straight.chunks_exact(4))
+                .zip(premultiplied.chunks_exact(4))
+            {
+                let alpha = u32::from(original[3]);
+                assert_eq!(pixel[3], original[3]);
+                match alpha {
+                    0 => assert_eq!(pixel, [0; 4]),
+                    255 => assert_eq!(pixel, original),
+                    _ => {
+                        for i in 0..3 {
+                            let value = premultiply(u32::from(pixel[i]), alpha, 255);
+                            assert_eq!(value, u32::from(input[i]), "{color:?}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn analyze_content() {
+        let (width, height) = (48, 40);
+        let mut noise = vec![0; width * height];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let gray: Vec<u8> = noise.iter().flat_map(|&v| [v, v, v, 255]).collect();
+        let gray_alpha: Vec<u8> = noise.iter().flat_map(|&v| [v, v, v, v | 1]).collect();
+        let color: Vec<u8> = noise.iter().flat_map(|&v| [v, 0, 0, 255]).collect();
+        let unique_colors = {
+            let mut values = noise.clone();
+            values.sort_unstable();
+            values.dedup();
+            values.len()
+        };
+
+        for (img, expected) in [
+            (gray, crate::ColorType::L8),
+            (gray_alpha, crate::ColorType::La8),
+            (color, crate::ColorType::Rgb8),
+        ] {
+            let mut encoder = WebPEncoder::new(Vec::new());
+            encoder.set_icc_profile(vec![0; 4]);
+            let properties = encoder
+                .analyze(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                .unwrap();
+            assert_eq!(properties.color_type(), expected);
+            assert_eq!(properties.unique_colors, Some(unique_colors));
+
+            let mut sizes = Vec::new();
+            for analyze in [false, true] {
+                let mut output = Vec::new();
+                let mut encoder = WebPEncoder::new(&mut output);
+                encoder.set_icc_profile(vec![0; 4]);
+                encoder.set_params(EncoderParams {
+                    analyze,
+                    ..Default::default()
+                });
+                encoder
+                    .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                    .unwrap();
+
+                let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+                let opaque = !properties.color_type().has_alpha();
+                assert_eq!(decoder.has_alpha(), !(analyze && opaque));
+                let mut decoded = vec![0; decoder.output_buffer_size().unwrap()];
+                decoder.read_image(&mut decoded).unwrap();
+                if decoder.has_alpha() {
+                    assert_eq!(decoded, img);
+                } else {
+                    let rgb: Vec<u8> = img
+                        .chunks_exact(4)
+                        .flat_map(|p| [p[0], p[1], p[2]])
+                        .collect();
+                    assert_eq!(decoded, rgb);
+                }
+                sizes.push(output.len());
+            }
+            assert!(sizes[1] <= sizes[0], "{expected:?} {sizes:?}");
+        }
+
+        // Colors are only counted up to the size of a palette.
+        let colors: Vec<u8> = noise
+            .iter()
+            .zip(0..)
+            .flat_map(|(&v, i)| [v, i as u8, 0, 255])
+            .collect();
+        let properties = WebPEncoder::new(Vec::new())
+            .analyze(
+                &colors,
+                width as u32,
+                height as u32,
+                crate::ColorType::Rgba8,
+            )
+            .unwrap();
+        assert_eq!(properties.unique_colors, None);
+    }
+
+    #[test]
+    fn estimate_size_matches_output() {
+        let (width, height) = (45, 31);
+        let mut img = vec![0; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut img);
+        let palette: Vec<u8> = img.iter().map(|v| v & 0xc0).collect();
+        let opaque: Vec<u8> = img
+            .chunks_exact(4)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect();
+
+        for (img, exif, method, analyze, lossy) in [
+            (&img, vec![], 4, false, None),
+            (&img, vec![7; 3], 4, true, None),
+            (&palette, vec![], 6, false, None),
+            (&palette, vec![7; 10], 0, true, None),
+            (&opaque, vec![], 4, false, Some(75)),
+            (&img, vec![7; 3], 4, false, Some(30)),
+        ] {
+            let mut encoder = WebPEncoder::new(Vec::new());
+            encoder.set_exif_metadata(exif.clone());
+            encoder.set_params(EncoderParams {
+                method,
+                analyze,
+                lossy,
+                ..Default::default()
+            });
+            let estimate = encoder
+                .estimate_size(img, width as u32, height as u32, crate::ColorType::Rgba8)
+                .unwrap();
+
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_exif_metadata(exif);
+            encoder.set_params(EncoderParams {
+                method,
+                analyze,
+                lossy,
+                ..Default::default()
+            });
+            encoder
+                .encode(img, width as u32, height as u32, crate::ColorType::Rgba8)
+                .unwrap();
+            assert_eq!(estimate, output.len() as u64);
+            assert_eq!(
+                u32::from_le_bytes(output[4..8].try_into().unwrap()),
+                estimate as u32 - 8
+            );
+        }
+    }
+
+    /// Peak signal-to-noise ratio of the color channels, in dB.
+    fn psnr(a: &[u8], b: &[u8], bytes_per_pixel: usize) -> f64 {
+        let mut sse = 0.0;
+        for (a, b) in a
+            .chunks_exact(bytes_per_pixel)
+            .zip(b.chunks_exact(bytes_per_pixel))
+        {
+            for c in 0..3 {
+                sse += (f64::from(a[c]) - f64::from(b[c])).powi(2);
+            }
+        }
+        let mse = sse / (a.len() / bytes_per_pixel * 3) as f64;
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+
+    #[test]
+    fn lossy_roundtrip() {
+        let (width, height) = (100, 75);
+        let img: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                let ripple = ((x as f64 / 5.0).sin() * 20.0) as i32;
+                [
+                    (x * 2 + 30) as u8,
+                    (y * 3 + ripple as usize % 8) as u8,
+                    (128 + ripple) as u8,
+                ]
+            })
+            .collect();
+
+        let mut previous = (0, 0.0);
+        for quality in [10, 50, 90] {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                lossy: Some(quality),
+                ..Default::default()
+            });
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::Rgb8)
+                .unwrap();
+            assert_eq!(&output[12..16], b"VP8 ");
+
+            let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+            assert!(decoder.is_lossy());
+            assert!(!decoder.has_alpha());
+            let mut decoded = vec![0; img.len()];
+            decoder.read_image(&mut decoded).unwrap();
+            let quality_psnr = psnr(&img, &decoded, 3);
+
+            let libwebp = webp::Decoder::new(&output).decode().unwrap();
+            assert!(psnr(&img, &libwebp, 3) > 25.0);
+
+            assert!(output.len() > previous.0, "{quality}");
+            assert!(
+                quality_psnr > previous.1 && quality_psnr > 28.0,
+                "{quality}"
+            );
+            previous = (output.len(), quality_psnr);
+        }
+        // Lossless would be much larger.
+        assert!(previous.0 < img.len() / 4);
+    }
+
+    #[test]
+    fn lossy_alpha() {
+        let (width, height) = (40, 30);
+        let mut img = vec![0; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut img);
+        for (i, pixel) in img.chunks_exact_mut(4).enumerate() {
+            pixel[3] = if i % width < 20 {
+                255
+            } else {
+                (i % 7 * 40) as u8
+            };
+        }
+
+        let mut output = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_params(EncoderParams {
+            lossy: Some(75),
+            ..Default::default()
+        });
+        encoder
+            .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+            .unwrap();
+        assert_eq!(&output[12..16], b"VP8X");
+        assert_eq!(&output[30..34], b"ALPH");
+
+        let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        assert!(decoder.is_lossy());
+        assert!(decoder.has_alpha());
+        let mut decoded = vec![0; img.len()];
+        decoder.read_image(&mut decoded).unwrap();
+        let alpha = |pixels: &[u8]| pixels.chunks_exact(4).map(|p| p[3]).collect::<Vec<_>>();
+        assert_eq!(alpha(&decoded), alpha(&img));
+
+        let libwebp = webp::Decoder::new(&output).decode().unwrap();
+        assert_eq!(alpha(&libwebp), alpha(&img));
+
+        // Opaque images don't need an alpha chunk, whatever their color type.
+        let opaque: Vec<u8> = img
+            .chunks_exact(4)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect();
+        let mut output = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_params(EncoderParams {
+            lossy: Some(75),
+            ..Default::default()
+        });
+        encoder
+            .encode(
+                &opaque,
+                width as u32,
+                height as u32,
+                crate::ColorType::Rgba8,
+            )
+            .unwrap();
+        assert_eq!(&output[12..16], b"VP8 ");
+    }
+
+    #[test]
+    fn lossy_target_size() {
+        let (width, height) = (120, 90);
+        let img: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 2) as u8, (y * 3) as u8, (x * y / 16) as u8]
+            })
+            .collect();
+        let encode = |params: EncoderParams| {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_exif_metadata(vec![7; 101]);
+            encoder.set_params(params);
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::Rgb8)
+                .unwrap();
+            output
+        };
+
+        for target_size in [1200, 2500] {
+            let output = encode(EncoderParams {
+                target_size: Some(target_size),
+                ..Default::default()
+            });
+            assert!(output.len() <= target_size, "{target_size}");
+            assert!(output.len() > target_size * 9 / 10, "{target_size}");
+            assert_eq!(&output[30..34], b"VP8 ");
+        }
+
+        // Too small a target gives the lowest quality.
+        let output = encode(EncoderParams {
+            target_size: Some(100),
+            ..Default::default()
+        });
+        let lowest = encode(EncoderParams {
+            lossy: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(output, lowest);
+    }
+
+    #[test]
+    fn lossy_dimensions() {
+        let mut encoder = WebPEncoder::new(Vec::new());
+        encoder.set_params(EncoderParams {
+            lossy: Some(75),
+            ..Default::default()
+        });
+        assert!(matches!(
+            encoder.encode(&[0; 16384], 16384, 1, crate::ColorType::L8),
+            Err(EncodingError::InvalidDimensions)
+        ));
+    }
+
+    #[test]
+    fn huffman_lengths_optimal_and_limited() {
+        // Unlimited Huffman code cost, from repeatedly merging the two lightest nodes.
+        fn huffman_cost(frequencies: &[u32]) -> u64 {
+            let mut nodes: Vec<u64> = frequencies
+                .iter()
+                .filter(|&&f| f > 0)
+                .map(|&f| u64::from(f))
+                .collect();
+            let mut cost = 0;
+            while nodes.len() > 1 {
+                nodes.sort_unstable_by(|a, b| b.cmp(a));
+                let merged = nodes.pop().unwrap() + nodes.pop().unwrap();
+                cost += merged;
+                nodes.push(merged);
+            }
+            cost
+        }
+
+        let mut fibonacci = vec![1u32, 1];
+        while fibonacci.len() < 30 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+        let mut random = vec![0u8; 280];
+        rand::thread_rng().fill_bytes(&mut random);
+        let random: Vec<u32> = random
+            .iter()
+            .map(|&r| u32::from(r) * (r % 3) as u32)
+            .collect();
+
+        for frequencies in [fibonacci, random, vec![5, 0, 0, 7, 1]] {
+            let mut lengths = vec![0; frequencies.len()];
+            let mut codes = vec![0; frequencies.len()];
+            assert!(build_huffman_tree(
+                &frequencies,
+                &mut lengths,
+                &mut codes,
+                15
+            ));
+
+            assert!(lengths.iter().all(|&l| l <= 15));
+            let kraft: u32 = lengths
+                .iter()
+                .filter(|&&l| l > 0)
+                .map(|&l| 1 << (15 - l))
+                .sum();
+            assert_eq!(kraft, 1 << 15);
+
+            let cost: u64 = frequencies
+                .iter()
+                .zip(&lengths)
+                .map(|(&f, &l)| u64::from(f) * u64::from(l))
+                .sum();
+            let max_length = *lengths.iter().max().unwrap();
+            if max_length < 15 {
+                assert_eq!(cost, huffman_cost(&frequencies));
+            } else {
+                assert!(cost >= huffman_cost(&frequencies));
+            }
+        }
+    }
+
+    #[test]
+    fn code_length_tokens_roundtrip() {
+        let mut lengths = vec![0u8; 400];
+        for (i, length) in lengths.iter_mut().enumerate() {
+            *length = match i {
+                0..=2 => 8,
+                3..=20 => 5,
+                21..=160 => 0,
+                161..=163 => 0,
+                164 => 3,
+                165..=170 => 0,
+                171..=190 => (i % 3) as u8 + 4,
+                _ => 0,
+            };
+        }
+
+        let tokens = code_length_tokens(&lengths);
+        assert!(tokens.len() < 40);
+
+        let mut decoded = Vec::new();
+        let mut previous = 8;
+        for (symbol, extra_value) in tokens {
+            match symbol {
+                0..=15 => {
+                    decoded.push(symbol);
+                    if symbol != 0 {
+                        previous = symbol;
+                    }
+                }
+                16 => {
+                    decoded.extend(std::iter::repeat(previous).take(3 + usize::from(extra_value)))
+                }
+                17 => decoded.extend(std::iter::repeat(0).take(3 + usize::from(extra_value))),
+                18 => decoded.extend(std::iter::repeat(0).take(11 + usize::from(extra_value))),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(decoded, lengths);
+    }
+
     #[test]
     fn roundtrip_libwebp() {
-        roundtrip_libwebp_params(EncoderParams::default());
+        roundtrip_libwebp_params(EncoderParams {
+            exact: true,
+            ..Default::default()
+        });
+        roundtrip_libwebp_params(EncoderParams {
+            use_predictor_transform: false,
+            exact: true,
+            ..Default::default()
+        });
+        roundtrip_libwebp_params(EncoderParams {
+            use_color_transform: false,
+            exact: true,
+            ..Default::default()
+        });
         roundtrip_libwebp_params(EncoderParams {
             use_predictor_transform: false,
+            use_color_transform: false,
+            exact: true,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn palette_roundtrip() {
+        // Covers each amount of pixel bundling, with widths that don't divide evenly.
+        for num_colors in [1, 2, 3, 4, 11, 16, 17, 200, 256] {
+            let mut colors = vec![0u8; num_colors * 4];
+            rand::thread_rng().fill_bytes(&mut colors);
+
+            let (width, height) = (37, 29);
+            let mut img = Vec::with_capacity(width * height * 4);
+            for i in 0..width * height {
+                let color = (i / 3 + i % 7) % num_colors;
+                img.extend_from_slice(&colors[color * 4..][..4]);
+            }
+
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                exact: true,
+                ..Default::default()
+            });
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                .unwrap();
+
+            let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+            let mut img2 = vec![0; img.len()];
+            decoder.read_image(&mut img2).unwrap();
+            assert_eq!(img, img2);
+
+            let decoded = webp::Decoder::new(&output).decode().unwrap();
+            assert_eq!(img, *decoded);
+        }
+    }
+
+    #[test]
+    fn backward_references_repeated_content() {
+        // Random 16x16 tiles repeated across the image, as with icons in a screenshot.
+        let mut tiles = vec![0u8; 3 * 16 * 16 * 4];
+        rand::thread_rng().fill_bytes(&mut tiles);
+
+        let (width, height) = (16 * 21, 16 * 9);
+        let mut img = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let tile = (x / 16 + y / 16) % 3;
+                let index = (tile * 256 + (y % 16) * 16 + x % 16) * 4;
+                img.extend_from_slice(&tiles[index..][..4]);
+            }
+        }
+
+        let mut output = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_params(EncoderParams {
+            exact: true,
             ..Default::default()
         });
+        encoder
+            .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+            .unwrap();
+        assert!(output.len() < img.len() / 8);
+
+        let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        let mut img2 = vec![0; img.len()];
+        decoder.read_image(&mut img2).unwrap();
+        assert_eq!(img, img2);
+
+        let decoded = webp::Decoder::new(&output).decode().unwrap();
+        assert_eq!(img, *decoded);
+    }
+
+    #[test]
+    fn color_cache_scattered_colors() {
+        // Too many colors for a palette, but few enough to be found in a color cache.
+        let mut colors = vec![0u8; 400 * 4];
+        rand::thread_rng().fill_bytes(&mut colors);
+        let mut choices = vec![0u8; 200 * 150 * 2];
+        rand::thread_rng().fill_bytes(&mut choices);
+
+        let img: Vec<u8> = choices
+            .chunks_exact(2)
+            .flat_map(|c| {
+                let color = usize::from(u16::from_le_bytes([c[0], c[1]]) % 400);
+                colors[color * 4..][..4].to_vec()
+            })
+            .collect();
+
+        let mut output = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut output);
+        encoder.set_params(EncoderParams {
+            exact: true,
+            ..Default::default()
+        });
+        encoder
+            .encode(&img, 200, 150, crate::ColorType::Rgba8)
+            .unwrap();
+
+        let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        let mut img2 = vec![0; img.len()];
+        decoder.read_image(&mut img2).unwrap();
+        assert_eq!(img, img2);
+
+        let decoded = webp::Decoder::new(&output).decode().unwrap();
+        assert_eq!(img, *decoded);
+    }
+
+    #[test]
+    fn meta_huffman_distinct_regions() {
+        // Noise on the left, much quieter noise on the right.
+        let (width, height) = (160, 120);
+        let mut img = vec![0u8; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut img);
+        for (i, pixel) in img.chunks_exact_mut(4).enumerate() {
+            if i % width >= width / 2 {
+                for value in &mut pixel[..3] {
+                    *value = 100 + (*value & 3);
+                }
+            }
+            pixel[3] = 255;
+        }
+
+        let settings = MethodSettings::new(&EncoderParams::default());
+        let (tokens, cache_bits, histogram) = choose_image_coding(&img, width as u16, &settings);
+        let groups = choose_huffman_groups(
+            &tokens,
+            cache_bits,
+            &histogram,
+            width as u16,
+            height as u16,
+            &settings,
+        )
+        .unwrap();
+        assert!(groups.histograms.len() >= 2);
+
+        let mut output = Vec::new();
+        WebPEncoder::new(&mut output)
+            .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+            .unwrap();
+
+        let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+        let mut img2 = vec![0; img.len()];
+        decoder.read_image(&mut img2).unwrap();
+        assert_eq!(img, img2);
+
+        let decoded = webp::Decoder::new(&output).decode().unwrap();
+        assert_eq!(img, *decoded);
+    }
+
+    #[test]
+    fn methods_roundtrip() {
+        let (width, height) = (64, 48);
+        let mut noise = vec![0u8; width * height];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let smooth: Vec<u8> = noise
+            .iter()
+            .enumerate()
+            .flat_map(|(i, n)| {
+                let value = (i % width + i / width) as u8;
+                [value, value.wrapping_add(n & 7), value / 2, 255 - (n & 1)]
+            })
+            .collect();
+        let palette: Vec<u8> = noise
+            .iter()
+            .flat_map(|n| [n & 0xc0, 20, n & 0x30, 255])
+            .collect();
+
+        for img in [smooth, palette] {
+            let mut sizes = Vec::new();
+            for method in 0..=7 {
+                let mut output = Vec::new();
+                let mut encoder = WebPEncoder::new(&mut output);
+                encoder.set_params(EncoderParams {
+                    method,
+                    ..Default::default()
+                });
+                encoder
+                    .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                    .unwrap();
+
+                let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+                let mut img2 = vec![0; img.len()];
+                decoder.read_image(&mut img2).unwrap();
+                assert_eq!(img, img2, "method {method}");
+
+                let decoded = webp::Decoder::new(&output).decode().unwrap();
+                assert_eq!(img, *decoded, "method {method}");
+
+                sizes.push(output.len());
+            }
+
+            // Method 6 tries every candidate of method 5, and higher values act like 6.
+            assert!(sizes[6] <= sizes[5]);
+            assert_eq!(sizes[6], sizes[7]);
+        }
+    }
+
+    #[test]
+    fn near_lossless_error_bound() {
+        // A noisy gradient with some hard edges and varying alpha.
+        let (width, height) = (96, 64);
+        let mut noise = vec![0u8; width * height];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let img: Vec<u8> = noise
+            .iter()
+            .enumerate()
+            .flat_map(|(i, n)| {
+                let (x, y) = (i % width, i / width);
+                let edge = if x > 60 { 200 } else { 0 };
+                [
+                    (x * 2 + (n & 15) as usize) as u8,
+                    (y * 3) as u8 ^ edge,
+                    (x + y) as u8 ^ (n >> 4),
+                    if y > 50 { n | 0x80 } else { 255 },
+                ]
+            })
+            .collect();
+
+        for color in [crate::ColorType::Rgba8, crate::ColorType::Rgb8] {
+            let img: Vec<u8> = match color {
+                crate::ColorType::Rgb8 => img
+                    .chunks_exact(4)
+                    .flat_map(|p| [p[0], p[1], p[2]])
+                    .collect(),
+                _ => img.clone(),
+            };
+
+            let mut sizes = Vec::new();
+            for near_lossless in [0, 10, 20, 40, 60, 80, 99, 100] {
+                let mut output = Vec::new();
+                let mut encoder = WebPEncoder::new(&mut output);
+                encoder.set_params(EncoderParams {
+                    near_lossless,
+                    ..Default::default()
+                });
+                encoder
+                    .encode(&img, width as u32, height as u32, color)
+                    .unwrap();
+
+                let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+                let mut decoded = vec![0; decoder.output_buffer_size().unwrap()];
+                decoder.read_image(&mut decoded).unwrap();
+                assert_eq!(decoded.len(), img.len());
+
+                let max_error = (1 << near_lossless_bits(near_lossless)) / 2;
+                let channels = img.len() / (width * height);
+                for (i, (&a, &b)) in img.iter().zip(&decoded).enumerate() {
+                    let error = a.abs_diff(b);
+                    if i % channels == 3 {
+                        assert_eq!(error, 0, "alpha changed at level {near_lossless}");
+                    } else {
+                        assert!(error <= max_error, "error {error} at level {near_lossless}");
+                    }
+                }
+
+                sizes.push(output.len());
+            }
+            assert!(sizes[0] < sizes[7]);
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_cleanup() {
+        // Random colors under fully transparent pixels, around and scattered inside an opaque
+        // gradient.
+        let (width, height) = (100, 80);
+        let mut img = vec![0u8; width * height * 4];
+        rand::thread_rng().fill_bytes(&mut img);
+        for (i, pixel) in img.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % width, i / width);
+            if (20..70).contains(&x) && (10..60).contains(&y) && i % 7 != 0 {
+                pixel.copy_from_slice(&[x as u8, y as u8, (x + y) as u8, 255]);
+            } else {
+                pixel[3] = 0;
+            }
+        }
+
+        let mut sizes = Vec::new();
+        for exact in [false, true] {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                exact,
+                ..Default::default()
+            });
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                .unwrap();
+
+            let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+            let mut img2 = vec![0; img.len()];
+            decoder.read_image(&mut img2).unwrap();
+            if exact {
+                assert_eq!(img, img2);
+            } else {
+                for (a, b) in img.chunks_exact(4).zip(img2.chunks_exact(4)) {
+                    assert_eq!(a[3], b[3]);
+                    if a[3] != 0 {
+                        assert_eq!(a, b);
+                    }
+                }
+            }
+
+            sizes.push(output.len());
+        }
+        assert!(sizes[0] * 4 < sizes[1]);
+    }
+
+    #[test]
+    fn color_transform_correlated_channels() {
+        // Red and blue follow green with different scales, plus some noise.
+        let (width, height) = (128, 96);
+        let mut noise = vec![0u8; width * height];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let mut img = Vec::with_capacity(width * height * 3);
+        for (i, n) in noise.iter().enumerate() {
+            let green = (i % width + (i / width) * 2) as u8 ^ (n & 0x3f);
+            img.extend_from_slice(&[
+                green.wrapping_mul(3) / 2,
+                green,
+                (green / 2).wrapping_add(n >> 6),
+            ]);
+        }
+
+        let mut sizes = Vec::new();
+        for use_color_transform in [true, false] {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                use_color_transform,
+                ..Default::default()
+            });
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::Rgb8)
+                .unwrap();
+
+            let decoded = webp::Decoder::new(&output).decode().unwrap();
+            assert_eq!(img, *decoded);
+
+            sizes.push(output.len());
+        }
+        assert!(sizes[0] < sizes[1]);
+    }
+
+    #[test]
+    fn threads_same_output() {
+        // Large enough for every step to be split across threads.
+        let (width, height) = (384, 64);
+        let mut noise = vec![0u8; width * height];
+        rand::thread_rng().fill_bytes(&mut noise);
+        let img: Vec<u8> = noise
+            .iter()
+            .enumerate()
+            .flat_map(|(i, n)| {
+                let (x, y) = (i % width, i / width);
+                let value = if x < width / 2 { x ^ y } else { x + y } as u8;
+                [value, value.wrapping_add(n & 3), n & 0xf0, 255]
+            })
+            .collect();
+
+        for method in [4, 5] {
+            let mut outputs = Vec::new();
+            for threads in [1, 2, 3, 8] {
+                let mut output = Vec::new();
+                let mut encoder = WebPEncoder::new(&mut output);
+                encoder.set_params(EncoderParams {
+                    method,
+                    threads,
+                    ..Default::default()
+                });
+                encoder
+                    .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                    .unwrap();
+                outputs.push(output);
+            }
+            assert!(
+                outputs.iter().all(|output| *output == outputs[0]),
+                "method {method}"
+            );
+
+            let decoded = webp::Decoder::new(&outputs[0]).decode().unwrap();
+            assert_eq!(img, *decoded);
+        }
+    }
+
+    #[test]
+    fn predictor_transform_smooth_image() {
+        // A mix of gradients, where different parts of the image favor different predictors.
+        let (width, height) = (300, 200);
+        let mut img = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let (r, g, b) = match (x / 100, y / 100) {
+                    (0, _) => (x, y, x + y),
+                    (1, 0) => (y * 2, 7, 255 - y),
+                    (1, _) => (x ^ y, x, y),
+                    _ => ((x * y) / 64, x / 3, 0),
+                };
+                img.extend_from_slice(&[r as u8, g as u8, b as u8, (x / 2) as u8]);
+            }
+        }
+
+        let mut sizes = Vec::new();
+        for use_predictor_transform in [true, false] {
+            let mut output = Vec::new();
+            let mut encoder = WebPEncoder::new(&mut output);
+            encoder.set_params(EncoderParams {
+                use_predictor_transform,
+                exact: true,
+                ..Default::default()
+            });
+            encoder
+                .encode(&img, width as u32, height as u32, crate::ColorType::Rgba8)
+                .unwrap();
+
+            let mut decoder = crate::WebPDecoder::new(std::io::Cursor::new(&output)).unwrap();
+            let mut img2 = vec![0; img.len()];
+            decoder.read_image(&mut img2).unwrap();
+            assert_eq!(img, img2);
+
+            let decoded = webp::Decoder::new(&output).decode().unwrap();
+            assert_eq!(img, *decoded);
+
+            sizes.push(output.len());
+        }
+        assert!(sizes[0] < sizes[1]);
     }
 
     fn roundtrip_libwebp_params(params: EncoderParams) {
@@ -0,0 +1,498 @@
+//! Symbol histograms of entropy coded images, and their clustering into groups that share a set
+//! of Huffman codes.
+
+use crate::backward_references::{color_cache_hash, prefix_encode, PixOrCopy};
+use crate::encoder::{code_length_tokens, CODE_LENGTH_ORDER};
+use crate::parallel;
+
+/// Number of ranges the average literal cost of each channel is split into when histograms are
+/// first binned together.
+const NUM_PARTITIONS: usize = 4;
+
+/// Number of times tiles are moved to the cluster that suits them best after clustering.
+const REMAP_ITERATIONS: usize = 2;
+
+/// How often each symbol of the five alphabets is used to code an image, or part of one.
+#[derive(Clone)]
+pub(crate) struct Histogram {
+    pub(crate) green: Vec<u32>,
+    pub(crate) red: [u32; 256],
+    pub(crate) blue: [u32; 256],
+    pub(crate) alpha: [u32; 256],
+    pub(crate) distance: [u32; 40],
+}
+
+impl Histogram {
+    /// Creates an empty histogram for an image with a color cache of `cache_bits` bits.
+    pub(crate) fn new(cache_bits: u8) -> Self {
+        let cache_size = if cache_bits > 0 { 1 << cache_bits } else { 0 };
+        Self {
+            green: vec![0; 280 + cache_size],
+            red: [0; 256],
+            blue: [0; 256],
+            alpha: [0; 256],
+            distance: [0; 40],
+        }
+    }
+
+    /// Counts the symbols needed to code `tokens` of the image `pixels` with a color cache of each
+    /// size up to `max_cache_bits` bits, indexed by the cache bits.
+    ///
+    /// This gives the same histograms as coding the tokens with
+    /// [`crate::backward_references::apply_color_cache()`] for each size, in a single pass and
+    /// without storing the coded tokens.
+    pub(crate) fn for_cache_sizes(
+        pixels: &[u8],
+        tokens: &[PixOrCopy],
+        max_cache_bits: u8,
+    ) -> Vec<Self> {
+        let mut histograms: Vec<Self> = (0..=max_cache_bits).map(Self::new).collect();
+        let mut caches: Vec<Vec<[u8; 4]>> = (0..=max_cache_bits)
+            .map(|cache_bits| vec![[0; 4]; 1 << cache_bits])
+            .collect();
+
+        let mut index = 0;
+        for token in tokens {
+            match *token {
+                PixOrCopy::Literal(pixel) => {
+                    histograms[0].add(token);
+                    let hash = color_cache_hash(&pixel);
+                    for cache_bits in 1..=max_cache_bits {
+                        let key = (hash >> (32 - cache_bits)) as usize;
+                        let cache = &mut caches[usize::from(cache_bits)];
+                        let histogram = &mut histograms[usize::from(cache_bits)];
+                        if cache[key] == pixel {
+                            histogram.add(&PixOrCopy::CacheIndex(key as u16));
+                        } else {
+                            cache[key] = pixel;
+                            histogram.add(token);
+                        }
+                    }
+                    index += 1;
+                }
+                PixOrCopy::Copy { length, .. } => {
+                    for histogram in &mut histograms {
+                        histogram.add(token);
+                    }
+                    for pixel in pixels[index * 4..][..usize::from(length) * 4].chunks_exact(4) {
+                        let hash = color_cache_hash(pixel);
+                        for cache_bits in 1..=max_cache_bits {
+                            let key = (hash >> (32 - cache_bits)) as usize;
+                            caches[usize::from(cache_bits)][key].copy_from_slice(pixel);
+                        }
+                    }
+                    index += usize::from(length);
+                }
+                PixOrCopy::CacheIndex(_) => unreachable!(),
+            }
+        }
+        histograms
+    }
+
+    /// Counts the symbols needed to code `token`.
+    pub(crate) fn add(&mut self, token: &PixOrCopy) {
+        match *token {
+            PixOrCopy::Literal(pixel) => {
+                self.red[pixel[0] as usize] += 1;
+                self.green[pixel[1] as usize] += 1;
+                self.blue[pixel[2] as usize] += 1;
+                self.alpha[pixel[3] as usize] += 1;
+            }
+            PixOrCopy::Copy {
+                length,
+                distance_code,
+            } => {
+                let (symbol, _, _) = prefix_encode(u32::from(length));
+                self.green[256 + symbol as usize] += 1;
+                let (symbol, _, _) = prefix_encode(distance_code);
+                self.distance[symbol as usize] += 1;
+            }
+            PixOrCopy::CacheIndex(index) => {
+                self.green[280 + usize::from(index)] += 1;
+            }
+        }
+    }
+
+    /// Adds the counts of `other` to this histogram.
+    fn merge(&mut self, other: &Self) {
+        let add = |a: &mut [u32], b: &[u32]| a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+        add(&mut self.green, &other.green);
+        add(&mut self.red, &other.red);
+        add(&mut self.blue, &other.blue);
+        add(&mut self.alpha, &other.alpha);
+        add(&mut self.distance, &other.distance);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.green.iter().all(|&count| count == 0)
+    }
+
+    /// The histograms of the five alphabets, in the order their Huffman codes are written.
+    pub(crate) fn alphabets(&self) -> [&[u32]; 5] {
+        [
+            &self.green,
+            &self.red,
+            &self.blue,
+            &self.alpha,
+            &self.distance,
+        ]
+    }
+
+    /// Estimates the number of bits needed to code the symbols, including their extra bits.
+    pub(crate) fn estimate_bits(&self) -> f32 {
+        // Prefix symbols 4 and up are followed by extra bits.
+        let extra_bits = |histogram: &[u32]| -> f32 {
+            histogram
+                .iter()
+                .enumerate()
+                .skip(4)
+                .map(|(symbol, &count)| count as f32 * ((symbol - 2) >> 1) as f32)
+                .sum()
+        };
+
+        self.alphabets()
+            .iter()
+            .map(|histogram| estimate_entropy(histogram))
+            .sum::<f32>()
+            + extra_bits(&self.green[256..280])
+            + extra_bits(&self.distance)
+    }
+
+    /// Estimates the number of bits needed to code the symbols along with the Huffman codes
+    /// themselves.
+    pub(crate) fn estimate_coded_bits(&self) -> f32 {
+        self.estimate_bits()
+            + self
+                .alphabets()
+                .iter()
+                .map(|histogram| estimate_huffman_tree_bits(histogram))
+                .sum::<f32>()
+    }
+
+    /// Estimates how many more bits the symbols of `other` take to code with codes built for
+    /// both histograms combined than the symbols of this histogram alone.
+    ///
+    /// The cost of the Huffman codes and of the extra bits are left out.
+    fn estimate_added_bits(&self, other: &Self) -> f32 {
+        self.alphabets()
+            .iter()
+            .zip(other.alphabets())
+            .map(|(histogram, added)| {
+                let total: u32 = histogram.iter().sum();
+                let added_total: u32 = added.iter().sum();
+                let mut bits = xlog2(total + added_total) - xlog2(total);
+                for (&count, &added_count) in histogram.iter().zip(added) {
+                    if added_count != 0 {
+                        bits -= xlog2(count + added_count) - xlog2(count);
+                    }
+                }
+                bits
+            })
+            .sum()
+    }
+
+    /// Average number of bits per literal needed to code the green, red and blue channels.
+    fn literal_costs(&self) -> [f32; 3] {
+        let literals = self.red.iter().sum::<u32>().max(1) as f32;
+        [
+            estimate_entropy(&self.green[..256]) / literals,
+            estimate_entropy(&self.red) / literals,
+            estimate_entropy(&self.blue) / literals,
+        ]
+    }
+}
+
+fn xlog2(x: u32) -> f32 {
+    if x == 0 {
+        0.0
+    } else {
+        x as f32 * (x as f32).log2()
+    }
+}
+
+/// Estimates the number of bits needed to entropy code the values counted in `histogram`.
+pub(crate) fn estimate_entropy(histogram: &[u32]) -> f32 {
+    let mut total = 0;
+    let mut sum = 0.0;
+    for &count in histogram.iter().filter(|&&count| count != 0) {
+        total += count;
+        sum += xlog2(count);
+    }
+    xlog2(total) - sum
+}
+
+/// Estimates the number of bits needed to write a Huffman code for the symbols counted in
+/// `histogram`.
+pub(crate) fn estimate_huffman_tree_bits(histogram: &[u32]) -> f32 {
+    let mut total = 0;
+    let mut num_symbols = 0;
+    for &count in histogram.iter().filter(|&&count| count != 0) {
+        total += count;
+        num_symbols += 1;
+    }
+    if num_symbols <= 2 {
+        return 20.0;
+    }
+
+    // Code lengths are roughly the information content of each symbol.
+    let lengths: Vec<u8> = histogram
+        .iter()
+        .map(|&count| {
+            if count == 0 {
+                0
+            } else {
+                ((total as f32 / count as f32).log2().round() as u8).clamp(1, 15)
+            }
+        })
+        .collect();
+
+    // Trailing zeros are usually left out.
+    let mut tokens = code_length_tokens(&lengths);
+    while let Some((0 | 17 | 18, _)) = tokens.last() {
+        tokens.pop();
+    }
+
+    let mut token_histogram = [0u32; 19];
+    let mut extra_bits = 0;
+    for &(symbol, _) in &tokens {
+        token_histogram[usize::from(symbol)] += 1;
+        extra_bits += match symbol {
+            16 => 2,
+            17 => 3,
+            18 => 7,
+            _ => 0,
+        };
+    }
+    let num_code_lengths = CODE_LENGTH_ORDER
+        .iter()
+        .rposition(|&i| token_histogram[i] != 0)
+        .map_or(0, |i| i + 1)
+        .max(4);
+
+    // The header holds the code length code and the number of code lengths used.
+    let header_bits = 4 + 3 * num_code_lengths + 12;
+    header_bits as f32 + estimate_entropy(&token_histogram) + extra_bits as f32
+}
+
+/// Groups histograms so that the histograms of each group can share Huffman codes.
+///
+/// Histograms with similar literal costs are first binned together, then the groups are merged
+/// pairwise for as long as that saves bits. Finally, every histogram is moved to the group whose
+/// codes suit it best. Returns the group of each histogram and the combined histogram of each
+/// group.
+///
+/// All histograms must be for an image with a color cache of `cache_bits` bits. Up to `threads`
+/// threads are used to compare histograms.
+pub(crate) fn cluster_histograms(
+    histograms: &[Histogram],
+    cache_bits: u8,
+    threads: usize,
+) -> (Vec<u16>, Vec<Histogram>) {
+    // Bin the histograms by the average cost of their literals.
+    let costs: Vec<_> = histograms.iter().map(Histogram::literal_costs).collect();
+    let mut min_costs = [f32::INFINITY; 3];
+    let mut max_costs = [0f32; 3];
+    for (histogram, cost) in histograms.iter().zip(&costs) {
+        if !histogram.is_empty() {
+            for i in 0..3 {
+                min_costs[i] = min_costs[i].min(cost[i]);
+                max_costs[i] = max_costs[i].max(cost[i]);
+            }
+        }
+    }
+    let mut bins: Vec<Option<usize>> = vec![None; NUM_PARTITIONS * NUM_PARTITIONS * NUM_PARTITIONS];
+    let mut clusters: Vec<Histogram> = Vec::new();
+    for (histogram, cost) in histograms.iter().zip(&costs) {
+        if histogram.is_empty() {
+            continue;
+        }
+        let mut bin = 0;
+        for i in 0..3 {
+            let range = max_costs[i] - min_costs[i];
+            let partition = if range > 0.0 {
+                ((cost[i] - min_costs[i]) / range * NUM_PARTITIONS as f32) as usize
+            } else {
+                0
+            };
+            bin = bin * NUM_PARTITIONS + partition.min(NUM_PARTITIONS - 1);
+        }
+        match bins[bin] {
+            Some(cluster) => clusters[cluster].merge(histogram),
+            None => {
+                bins[bin] = Some(clusters.len());
+                clusters.push(histogram.clone());
+            }
+        }
+    }
+    if clusters.is_empty() {
+        return (vec![0; histograms.len()], vec![Histogram::new(cache_bits)]);
+    }
+
+    // Greedily merge the pair of clusters that saves the most bits.
+    let merge_cost = |a: &Histogram, b: &Histogram, bits: &[f32], i: usize, j: usize| {
+        let mut merged = a.clone();
+        merged.merge(b);
+        merged.estimate_coded_bits() - bits[i] - bits[j]
+    };
+    let mut bits: Vec<f32> = clusters
+        .iter()
+        .map(Histogram::estimate_coded_bits)
+        .collect();
+    let indices: Vec<usize> = (0..clusters.len()).collect();
+    let mut pair_costs = parallel::map(&indices, threads, |&i| {
+        let mut costs = vec![0.0; clusters.len()];
+        for j in i + 1..clusters.len() {
+            costs[j] = merge_cost(&clusters[i], &clusters[j], &bits, i, j);
+        }
+        costs
+    });
+    let mut alive: Vec<usize> = (0..clusters.len()).collect();
+    while alive.len() > 1 {
+        let mut best = (0, 0);
+        let mut best_cost = 0.0;
+        for (n, &i) in alive.iter().enumerate() {
+            for &j in &alive[n + 1..] {
+                if pair_costs[i][j] < best_cost {
+                    best_cost = pair_costs[i][j];
+                    best = (i, j);
+                }
+            }
+        }
+        if best_cost >= 0.0 {
+            break;
+        }
+
+        let (i, j) = best;
+        let other = std::mem::replace(&mut clusters[j], Histogram::new(cache_bits));
+        clusters[i].merge(&other);
+        bits[i] = clusters[i].estimate_coded_bits();
+        alive.retain(|&k| k != j);
+        for &k in &alive {
+            if k != i {
+                let (a, b) = (k.min(i), k.max(i));
+                pair_costs[a][b] = merge_cost(&clusters[a], &clusters[b], &bits, a, b);
+            }
+        }
+    }
+    let mut clusters: Vec<Histogram> = alive
+        .into_iter()
+        .map(|i| std::mem::replace(&mut clusters[i], Histogram::new(cache_bits)))
+        .collect();
+
+    // Move each histogram to the cluster it adds the fewest bits to.
+    let mut assignments = vec![0u16; histograms.len()];
+    for _ in 0..REMAP_ITERATIONS {
+        let best_clusters = parallel::map(histograms, threads, |histogram| {
+            if histogram.is_empty() {
+                return None;
+            }
+            let mut best = 0;
+            let mut best_bits = f32::INFINITY;
+            for (i, cluster) in clusters.iter().enumerate() {
+                let bits = cluster.estimate_added_bits(histogram);
+                if bits < best_bits {
+                    best_bits = bits;
+                    best = i as u16;
+                }
+            }
+            Some(best)
+        });
+        for (assignment, best) in assignments.iter_mut().zip(best_clusters) {
+            if let Some(best) = best {
+                *assignment = best;
+            }
+        }
+
+        let mut remapped = vec![Histogram::new(cache_bits); clusters.len()];
+        for (&assignment, histogram) in assignments.iter().zip(histograms) {
+            remapped[usize::from(assignment)].merge(histogram);
+        }
+
+        // Drop the clusters that lost all their histograms.
+        let mut renumbering = vec![0u16; clusters.len()];
+        clusters.clear();
+        for (i, cluster) in remapped.into_iter().enumerate() {
+            if !cluster.is_empty() {
+                renumbering[i] = clusters.len() as u16;
+                clusters.push(cluster);
+            }
+        }
+        for assignment in &mut assignments {
+            *assignment = renumbering[usize::from(*assignment)];
+        }
+    }
+
+    (assignments, clusters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backward_references::{apply_color_cache, compute_backward_references};
+
+    fn histogram_of_tokens(tokens: &[PixOrCopy], cache_bits: u8) -> Histogram {
+        let mut histogram = Histogram::new(cache_bits);
+        for token in tokens {
+            histogram.add(token);
+        }
+        histogram
+    }
+
+    fn histogram_of(pixels: &[[u8; 4]]) -> Histogram {
+        let tokens: Vec<_> = pixels.iter().map(|&p| PixOrCopy::Literal(p)).collect();
+        histogram_of_tokens(&tokens, 0)
+    }
+
+    #[test]
+    fn cache_size_histograms_match_coded_tokens() {
+        let mut state = 1u32;
+        let pixels: Vec<u8> = (0..4000)
+            .flat_map(|i| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let color = (state >> 28) as u8;
+                if i % 7 < 3 {
+                    [color, color * 3, 0, 255]
+                } else {
+                    [i as u8 % 5, color, 9, 255]
+                }
+            })
+            .collect();
+        let tokens = compute_backward_references(&pixels, 50, 16);
+
+        let histograms = Histogram::for_cache_sizes(&pixels, &tokens, 10);
+        assert_eq!(histograms.len(), 11);
+        for (cache_bits, histogram) in (0..).zip(&histograms) {
+            let expected = if cache_bits > 0 {
+                histogram_of_tokens(&apply_color_cache(&pixels, &tokens, cache_bits), cache_bits)
+            } else {
+                histogram_of_tokens(&tokens, 0)
+            };
+            assert_eq!(histogram.alphabets(), expected.alphabets(), "{cache_bits}");
+        }
+    }
+
+    #[test]
+    fn clusters_separate_distinct_content() {
+        let smooth: Vec<[u8; 4]> = (0..256).map(|i| [i as u8 % 3, 0, 1, 255]).collect();
+        let noisy: Vec<[u8; 4]> = (0..256u32)
+            .map(|i| {
+                let x = i.wrapping_mul(2654435761) >> 8;
+                [x as u8, (x >> 8) as u8, (x >> 16) as u8, 255]
+            })
+            .collect();
+
+        let mut histograms = Vec::new();
+        for i in 0..16 {
+            histograms.push(histogram_of(if i % 2 == 0 { &smooth } else { &noisy }));
+        }
+        histograms.push(Histogram::new(0));
+
+        let (assignments, clusters) = cluster_histograms(&histograms, 0, 1);
+        assert_eq!(clusters.len(), 2);
+        for i in 0..16 {
+            assert_eq!(assignments[i], assignments[i % 2]);
+        }
+        assert_ne!(assignments[0], assignments[1]);
+        assert!(usize::from(assignments[16]) < clusters.len());
+    }
+}
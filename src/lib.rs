@@ -10,17 +10,27 @@
 extern crate test;
 
 pub use self::decoder::{DecodingError, LoopCount, WebPDecoder};
-pub use self::encoder::{ColorType, EncoderParams, EncodingError, WebPEncoder};
+pub use self::encoder::{
+    ColorType, Dithering, EncoderParams, EncodingError, ImageProperties, WebPEncoder,
+    WebPRowEncoder,
+};
+pub use self::reoptimize::{reoptimize, ReoptimizeError, Reoptimized};
 
 mod alpha_blending;
+mod backward_references;
 mod decoder;
 mod encoder;
 mod extended;
+mod histogram;
 mod huffman;
 mod loop_filter;
 mod lossless;
 mod lossless_transform;
+mod parallel;
+mod reoptimize;
 mod transform;
 mod vp8_arithmetic_decoder;
+mod vp8_arithmetic_encoder;
+mod vp8_encoder;
 
 pub mod vp8;
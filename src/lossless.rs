@@ -20,7 +20,7 @@ const CODE_LENGTH_CODE_ORDER: [usize; CODE_LENGTH_CODES] = [
 ];
 
 #[rustfmt::skip]
-const DISTANCE_MAP: [(i8, i8); 120] = [
+pub(crate) const DISTANCE_MAP: [(i8, i8); 120] = [
     (0, 1),  (1, 0),  (1, 1),  (-1, 1), (0, 2),  (2, 0),  (1, 2),  (-1, 2),
     (2, 1),  (-2, 1), (2, 2),  (-2, 2), (0, 3),  (3, 0),  (1, 3),  (-1, 3),
     (3, 1),  (-3, 1), (2, 3),  (-2, 3), (3, 2),  (-3, 2), (0, 4),  (4, 0),
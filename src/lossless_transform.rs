@@ -340,6 +340,262 @@ pub fn apply_predictor_transform_13(image_data: &mut [u8], range: Range<usize>,
     }
 }
 
+/// Computes the value that predictor `mode` predicts for the pixel at `index`.
+///
+/// Only valid for pixels that are neither in the first row nor in the first column. Mirrors
+/// the `apply_predictor_transform_*` functions above, so the values computed here are exactly
+/// the ones the decoder adds back.
+pub(crate) fn predict_pixel(image_data: &[u8], index: usize, width: usize, mode: u8) -> [u8; 4] {
+    let left = &image_data[index - 4..][..4];
+    let top = &image_data[index - width * 4..][..4];
+    let top_left = &image_data[index - width * 4 - 4..][..4];
+    let top_right = &image_data[index - width * 4 + 4..][..4];
+
+    let mut prediction = [0; 4];
+    match mode {
+        0 => prediction = [0, 0, 0, 0xff],
+        1 => prediction.copy_from_slice(left),
+        2 => prediction.copy_from_slice(top),
+        3 => prediction.copy_from_slice(top_right),
+        4 => prediction.copy_from_slice(top_left),
+        5 => {
+            for i in 0..4 {
+                prediction[i] = average2(average2(left[i], top_right[i]), top[i]);
+            }
+        }
+        6 => {
+            for i in 0..4 {
+                prediction[i] = average2(left[i], top_left[i]);
+            }
+        }
+        7 => {
+            for i in 0..4 {
+                prediction[i] = average2(left[i], top[i]);
+            }
+        }
+        8 => {
+            for i in 0..4 {
+                prediction[i] = average2(top_left[i], top[i]);
+            }
+        }
+        9 => {
+            for i in 0..4 {
+                prediction[i] = average2(top[i], top_right[i]);
+            }
+        }
+        10 => {
+            for i in 0..4 {
+                prediction[i] = average2(
+                    average2(left[i], top_left[i]),
+                    average2(top[i], top_right[i]),
+                );
+            }
+        }
+        11 => {
+            let mut predict_left = 0;
+            let mut predict_top = 0;
+            for i in 0..4 {
+                let predict = i16::from(left[i]) + i16::from(top[i]) - i16::from(top_left[i]);
+                predict_left += i16::abs(predict - i16::from(left[i]));
+                predict_top += i16::abs(predict - i16::from(top[i]));
+            }
+            if predict_left < predict_top {
+                prediction.copy_from_slice(left);
+            } else {
+                prediction.copy_from_slice(top);
+            }
+        }
+        12 => {
+            for i in 0..4 {
+                prediction[i] = clamp_add_subtract_full(
+                    i16::from(left[i]),
+                    i16::from(top[i]),
+                    i16::from(top_left[i]),
+                );
+            }
+        }
+        13 => {
+            for i in 0..4 {
+                prediction[i] = clamp_add_subtract_half(
+                    (i16::from(left[i]) + i16::from(top[i])) / 2,
+                    i16::from(top_left[i]),
+                );
+            }
+        }
+        _ => {}
+    }
+    prediction
+}
+
+/// Replaces each pixel of the image by its residual from the prediction of the predictor
+/// selected for its block.
+///
+/// This is the inverse of `apply_predictor_transform`. The mode of each block is read from the
+/// green channel of `predictor_data`.
+pub(crate) fn forward_predictor_transform(
+    image_data: &mut [u8],
+    width: u16,
+    height: u16,
+    size_bits: u8,
+    predictor_data: &[u8],
+) {
+    let block_xsize = usize::from(subsample_size(width, size_bits));
+    let width = usize::from(width);
+    let height = usize::from(height);
+
+    // Walk the image backwards so that the neighbours of every pixel still hold their original
+    // values when the pixel is predicted.
+    for y in (1..height).rev() {
+        for x in (1..width).rev() {
+            let block_index = (y >> size_bits) * block_xsize + (x >> size_bits);
+            let mode = predictor_data[block_index * 4 + 1];
+            let index = (y * width + x) * 4;
+            let prediction = predict_pixel(image_data, index, width, mode);
+            for i in 0..4 {
+                image_data[index + i] = image_data[index + i].wrapping_sub(prediction[i]);
+            }
+        }
+
+        // The first column is always predicted from the pixel above.
+        for i in 0..4 {
+            image_data[y * width * 4 + i] =
+                image_data[y * width * 4 + i].wrapping_sub(image_data[(y - 1) * width * 4 + i]);
+        }
+    }
+
+    // The first row is always predicted from the pixel to the left.
+    for i in (4..width * 4).rev() {
+        image_data[i] = image_data[i].wrapping_sub(image_data[i - 4]);
+    }
+    image_data[3] = image_data[3].wrapping_sub(255);
+}
+
+/// Like `forward_predictor_transform`, but allows the red, green and blue channels to change by up
+/// to `(1 << quantization_bits) / 2` if that rounds the residual to a multiple of a power of two.
+/// Unless `exact` is set, the red, green and blue channels of fully transparent pixels are also
+/// replaced by their prediction.
+///
+/// Pixels are predicted from the values the decoder will reconstruct, so the errors don't add up.
+/// Pixels in smooth areas are quantized less, to avoid banding. If `subtract_green` is set, the
+/// image has been through the subtract green transform, and the error bound holds after that
+/// transform is undone. Alpha is kept exact.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn forward_lossy_predictor_transform(
+    image_data: &mut [u8],
+    width: u16,
+    height: u16,
+    size_bits: u8,
+    predictor_data: &[u8],
+    subtract_green: bool,
+    quantization_bits: u8,
+    exact: bool,
+) {
+    let block_xsize = usize::from(subsample_size(width, size_bits));
+    let width = usize::from(width);
+    let height = usize::from(height);
+
+    let original = image_data.to_vec();
+    let mut reconstructed = vec![0; image_data.len()];
+    let max_step = 1i16 << quantization_bits;
+
+    for y in 0..height {
+        for x in 0..width {
+            let index = (y * width + x) * 4;
+            let prediction = match (x, y) {
+                (0, 0) => [0, 0, 0, 255],
+                (_, 0) => reconstructed[index - 4..][..4].try_into().unwrap(),
+                (0, _) => reconstructed[index - width * 4..][..4].try_into().unwrap(),
+                _ => {
+                    let block_index = (y >> size_bits) * block_xsize + (x >> size_bits);
+                    let mode = predictor_data[block_index * 4 + 1];
+                    predict_pixel(&reconstructed, index, width, mode)
+                }
+            };
+
+            // Quantize less where the neighbours are close in value.
+            let mut max_diff = 0;
+            let neighbors = [
+                (x > 0).then(|| index - 4),
+                (x + 1 < width).then(|| index + 4),
+                (y > 0).then(|| index - width * 4),
+                (y + 1 < height).then(|| index + width * 4),
+            ];
+            for neighbor in neighbors.into_iter().flatten() {
+                for i in 0..3 {
+                    let diff = i16::from(original[index + i]) - i16::from(original[neighbor + i]);
+                    max_diff = max_diff.max(diff.abs());
+                }
+            }
+            let mut step = max_step;
+            while step > 1 && step >= max_diff {
+                step >>= 1;
+            }
+
+            // Rounds the residual between `value` and `predicted`, both taken after adding
+            // `offset`, to a multiple of `step` unless that would leave the range of a byte.
+            let quantize = |value: u8, predicted: u8, offset: u8| -> u8 {
+                let value = i16::from(value.wrapping_add(offset));
+                let predicted = i16::from(predicted.wrapping_add(offset));
+                let residual = i16::from((value - predicted) as i8);
+                let quantized = ((residual + step / 2) >> step.trailing_zeros()) * step;
+                let result = (predicted + quantized).rem_euclid(256);
+                if (result - value).abs() <= step / 2 {
+                    quantized as u8
+                } else {
+                    residual as u8
+                }
+            };
+
+            let pixel = &original[index..][..4];
+            if !exact && pixel[3] == 0 {
+                image_data[index..][..4].copy_from_slice(&[
+                    0,
+                    0,
+                    0,
+                    0u8.wrapping_sub(prediction[3]),
+                ]);
+                reconstructed[index..][..4].copy_from_slice(&[
+                    prediction[0],
+                    prediction[1],
+                    prediction[2],
+                    0,
+                ]);
+                continue;
+            }
+
+            let green = quantize(pixel[1], prediction[1], 0);
+            let original_green = if subtract_green { pixel[1] } else { 0 };
+            let new_green = if subtract_green {
+                prediction[1].wrapping_add(green)
+            } else {
+                0
+            };
+            // `value + original_green` is the channel without subtract green, and the decoder
+            // adds `new_green` to the predicted value plus the residual.
+            let red = quantize(
+                pixel[0]
+                    .wrapping_add(original_green)
+                    .wrapping_sub(new_green),
+                prediction[0],
+                new_green,
+            );
+            let blue = quantize(
+                pixel[2]
+                    .wrapping_add(original_green)
+                    .wrapping_sub(new_green),
+                prediction[2],
+                new_green,
+            );
+            let residual = [red, green, blue, pixel[3].wrapping_sub(prediction[3])];
+
+            for i in 0..4 {
+                image_data[index + i] = residual[i];
+                reconstructed[index + i] = prediction[i].wrapping_add(residual[i]);
+            }
+        }
+    }
+}
+
 pub(crate) fn apply_color_transform(
     image_data: &mut [u8],
     width: u16,
@@ -372,6 +628,44 @@ pub(crate) fn apply_color_transform(
     }
 }
 
+/// Subtracts the color transform deltas from the red and blue channels of each pixel.
+///
+/// This is the inverse of `apply_color_transform`, with the multipliers of each block read from
+/// `transform_data` in the same layout.
+pub(crate) fn forward_color_transform(
+    image_data: &mut [u8],
+    width: u16,
+    size_bits: u8,
+    transform_data: &[u8],
+) {
+    let block_xsize = usize::from(subsample_size(width, size_bits));
+    let width = usize::from(width);
+
+    for (y, row) in image_data.chunks_exact_mut(width * 4).enumerate() {
+        for (block_x, block) in row.chunks_mut(4 << size_bits).enumerate() {
+            let block_index = (y >> size_bits) * block_xsize + block_x;
+            let red_to_blue = transform_data[block_index * 4];
+            let green_to_blue = transform_data[block_index * 4 + 1];
+            let green_to_red = transform_data[block_index * 4 + 2];
+
+            for pixel in block.chunks_exact_mut(4) {
+                let green = pixel[1] as i8;
+                let red = pixel[0] as i8;
+                let mut temp_red = u32::from(pixel[0]);
+                let mut temp_blue = u32::from(pixel[2]);
+
+                temp_red = temp_red.wrapping_sub(color_transform_delta(green_to_red as i8, green));
+                temp_blue =
+                    temp_blue.wrapping_sub(color_transform_delta(green_to_blue as i8, green));
+                temp_blue = temp_blue.wrapping_sub(color_transform_delta(red_to_blue as i8, red));
+
+                pixel[0] = (temp_red & 0xff) as u8;
+                pixel[2] = (temp_blue & 0xff) as u8;
+            }
+        }
+    }
+}
+
 pub(crate) fn apply_subtract_green_transform(image_data: &mut [u8]) {
     for pixel in image_data.chunks_exact_mut(4) {
         pixel[0] = pixel[0].wrapping_add(pixel[1]);
@@ -464,8 +758,57 @@ fn clamp_add_subtract_half(a: i16, b: i16) -> u8 {
     (a + (a - b) / 2).max(0).min(255) as u8
 }
 
+/// Replaces each pixel of the image by its index in `palette`, bundling several pixels into one
+/// when the palette has 16 or fewer colors.
+///
+/// This is the inverse of `apply_color_indexing_transform`. Every color of the image must be
+/// present in `palette`, which is given as ARGB values sorted in increasing order alongside the
+/// index each of them is coded as. Returns the index image, along with its width.
+pub(crate) fn forward_color_indexing_transform(
+    image_data: &[u8],
+    width: u16,
+    height: u16,
+    palette: &[(u32, u8)],
+) -> (Vec<u8>, u16) {
+    let width_bits: u8 = if palette.len() <= 2 {
+        3
+    } else if palette.len() <= 4 {
+        2
+    } else if palette.len() <= 16 {
+        1
+    } else {
+        0
+    };
+    let bits_per_entry = 8 >> width_bits;
+
+    let index_image_width = subsample_size(width, width_bits);
+    let mut index_image = vec![0; usize::from(index_image_width) * usize::from(height) * 4];
+    for pixel in index_image.chunks_exact_mut(4) {
+        pixel[3] = 255;
+    }
+
+    let mut last_color = None;
+    let mut last_index = 0;
+    for (y, row) in image_data.chunks_exact(usize::from(width) * 4).enumerate() {
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let color = u32::from_be_bytes([pixel[3], pixel[0], pixel[1], pixel[2]]);
+            if last_color != Some(color) {
+                let position = palette.binary_search_by_key(&color, |&(c, _)| c).unwrap();
+                last_color = Some(color);
+                last_index = palette[position].1;
+            }
+
+            let output_index = (y * usize::from(index_image_width) + (x >> width_bits)) * 4 + 1;
+            let shift = (x & ((1 << width_bits) - 1)) * bits_per_entry;
+            index_image[output_index] |= last_index << shift;
+        }
+    }
+
+    (index_image, index_image_width)
+}
+
 /// Does color transform on 2 numbers
-fn color_transform_delta(t: i8, c: i8) -> u32 {
+pub(crate) fn color_transform_delta(t: i8, c: i8) -> u32 {
     (i32::from(t) * i32::from(c)) as u32 >> 5
 }
 
@@ -0,0 +1,135 @@
+//! Spreading independent pieces of work across threads.
+//!
+//! Threads are only used with the `threads` feature. Work is always split and combined in the same
+//! order, so results never depend on the number of threads.
+
+/// Smallest number of pixels worth handing to a thread of its own.
+const MIN_PIXELS_PER_THREAD: usize = 1 << 14;
+
+/// Number of threads to use when `requested` threads were asked for, where zero means one per
+/// available core. Always one without the `threads` feature.
+pub(crate) fn thread_count(requested: usize) -> usize {
+    if !cfg!(feature = "threads") {
+        1
+    } else if requested == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        requested
+    }
+}
+
+/// Limits `threads` for work that visits `num_pixels` pixels in total, so that small images
+/// aren't slowed down by starting threads.
+pub(crate) fn threads_for(num_pixels: usize, threads: usize) -> usize {
+    threads.min(num_pixels / MIN_PIXELS_PER_THREAD).max(1)
+}
+
+/// Returns `f` applied to each item, in order.
+pub(crate) fn map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    map_with(items, threads, || (), |_, item| f(item))
+}
+
+/// Returns `f` applied to each item, in order, passing along scratch space created by `init`.
+///
+/// With the `threads` feature and more than one thread, the items are split into up to `threads`
+/// contiguous runs which are processed on scoped threads. Each run gets its own scratch space.
+pub(crate) fn map_with<T, S, R, I, F>(items: &[T], threads: usize, init: I, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    I: Fn() -> S + Sync,
+    F: Fn(&mut S, &T) -> R + Sync,
+{
+    let run = |run: &[T]| {
+        let mut scratch = init();
+        run.iter()
+            .map(|item| f(&mut scratch, item))
+            .collect::<Vec<_>>()
+    };
+
+    #[cfg(feature = "threads")]
+    if threads > 1 && items.len() > 1 {
+        let run = &run;
+        let run_size = items.len().div_ceil(threads);
+        return std::thread::scope(|scope| {
+            let handles: Vec<_> = items
+                .chunks(run_size)
+                .map(|items| scope.spawn(move || run(items)))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+    }
+
+    let _ = threads;
+    run(items)
+}
+
+/// Returns `f` applied to each item and combined in order by `combine`, or `None` if there are
+/// no items.
+///
+/// Results are combined as soon as they are ready, so each thread only keeps the combination of
+/// its run so far besides the result it is working on. The result doesn't depend on the number of
+/// threads as long as `combine` is associative.
+pub(crate) fn map_reduce<T, R, F, C>(items: &[T], threads: usize, f: F, combine: C) -> Option<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+    C: Fn(R, R) -> R + Sync,
+{
+    let run = |run: &[T]| run.iter().map(&f).reduce(&combine);
+
+    #[cfg(feature = "threads")]
+    if threads > 1 && items.len() > 1 {
+        let run = &run;
+        let run_size = items.len().div_ceil(threads);
+        return std::thread::scope(|scope| {
+            let handles: Vec<_> = items
+                .chunks(run_size)
+                .map(|items| scope.spawn(move || run(items)))
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().unwrap())
+                .reduce(&combine)
+        });
+    }
+
+    let _ = threads;
+    run(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_in_order() {
+        let items: Vec<u32> = (0..100).collect();
+        for threads in [1, 2, 3, 7, 100, 1000] {
+            let squares = map(&items, threads, |&x| x * x);
+            assert_eq!(squares, items.iter().map(|&x| x * x).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn reduced_in_order() {
+        let items: Vec<u32> = (0..100).collect();
+        for threads in [1, 2, 3, 7, 100, 1000] {
+            // Keeps the first of the items with the smallest remainder.
+            let first = map_reduce(&items, threads, |&x| (x % 7, x), |a, b| a.min(b));
+            assert_eq!(first, Some((0, 0)));
+            let joined = map_reduce(&items, threads, |&x| vec![x], |a, b| [a, b].concat());
+            assert_eq!(joined, Some(items.clone()));
+        }
+        assert_eq!(map_reduce(&[] as &[u32], 4, |&x| x, |a, _| a), None);
+    }
+}
@@ -0,0 +1,416 @@
+//! Re-encoding existing WebP files with more effort, without changing how they decode.
+
+use std::io::{self, Cursor, Write};
+
+use quick_error::quick_error;
+
+use crate::decoder::{DecodingError, LoopCount, WebPDecoder};
+use crate::encoder::{
+    chunk_size, convert_frame, encode_rgba_frame, write_chunk, ColorType, EncoderParams,
+    EncodingError, WebPEncoder,
+};
+
+quick_error! {
+    /// Error that can occur while re-optimizing a file.
+    #[derive(Debug)]
+    #[non_exhaustive]
+    pub enum ReoptimizeError {
+        /// The file couldn't be decoded.
+        Decoding(err: DecodingError) {
+            from()
+            display("Decoding error: {}", err)
+            source(err)
+        }
+
+        /// The file couldn't be re-encoded.
+        Encoding(err: EncodingError) {
+            from()
+            display("Encoding error: {}", err)
+            source(err)
+        }
+
+        /// The re-encoded file doesn't decode to the same pixels, timing or metadata as the
+        /// original.
+        Mismatch {
+            display("Re-encoded file doesn't match the original")
+        }
+    }
+}
+
+impl From<io::Error> for ReoptimizeError {
+    fn from(err: io::Error) -> Self {
+        ReoptimizeError::Encoding(EncodingError::IoError(err))
+    }
+}
+
+/// Outcome of [`reoptimize()`].
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Reoptimized {
+    /// The re-encoded file, which is smaller than the original and decodes to exactly the same
+    /// frames.
+    Smaller(Vec<u8>),
+    /// Re-encoding didn't make the file smaller, so the original should be kept.
+    NotSmaller,
+    /// The file holds a lossy still image, which lossless encoding can't make smaller, so the
+    /// original should be kept.
+    Lossy,
+}
+
+/// Re-encodes a lossless or animated WebP file with `params`, keeping its ICC profile, EXIF and
+/// XMP metadata, and for animations the frame durations, loop count and background color.
+///
+/// Settings of `params` that would change pixels are overridden: encoding is always exact and
+/// lossless, and the color type of the original is kept. The re-encoded file is only returned if
+/// it is smaller than `original`, after checking that it decodes to exactly the same frames.
+///
+/// Animation frames are re-encoded from the decoded frames, so frames that were lossy are
+/// re-encoded losslessly. Each frame only holds the region that changed since the previous
+/// frame.
+pub fn reoptimize(original: &[u8], params: &EncoderParams) -> Result<Reoptimized, ReoptimizeError> {
+    let mut decoder = WebPDecoder::new(Cursor::new(original))?;
+    if decoder.is_lossy() && !decoder.is_animated() {
+        return Ok(Reoptimized::Lossy);
+    }
+
+    let params = EncoderParams {
+        near_lossless: 100,
+        exact: true,
+        premultiplied_alpha: false,
+        analyze: false,
+        lossy: None,
+        target_size: None,
+        target_psnr: None,
+        ..params.clone()
+    };
+    let encoded = if decoder.is_animated() {
+        encode_animation(&mut decoder, &params)?
+    } else {
+        encode_still(&mut decoder, &params)?
+    };
+
+    if encoded.len() >= original.len() {
+        return Ok(Reoptimized::NotSmaller);
+    }
+    verify(original, &encoded)?;
+    Ok(Reoptimized::Smaller(encoded))
+}
+
+/// Metadata of a decoded file, in the order it is stored.
+fn read_metadata<R: io::BufRead + io::Seek>(
+    decoder: &mut WebPDecoder<R>,
+) -> Result<[Vec<u8>; 3], DecodingError> {
+    Ok([
+        decoder.icc_profile()?.unwrap_or_default(),
+        decoder.exif_metadata()?.unwrap_or_default(),
+        decoder.xmp_metadata()?.unwrap_or_default(),
+    ])
+}
+
+fn buffer<R: io::BufRead + io::Seek>(decoder: &WebPDecoder<R>) -> Result<Vec<u8>, DecodingError> {
+    let size = decoder
+        .output_buffer_size()
+        .ok_or(DecodingError::ImageTooLarge)?;
+    Ok(vec![0; size])
+}
+
+fn color_type<R: io::BufRead + io::Seek>(decoder: &WebPDecoder<R>) -> ColorType {
+    if decoder.has_alpha() {
+        ColorType::Rgba8
+    } else {
+        ColorType::Rgb8
+    }
+}
+
+fn encode_still(
+    decoder: &mut WebPDecoder<Cursor<&[u8]>>,
+    params: &EncoderParams,
+) -> Result<Vec<u8>, ReoptimizeError> {
+    let (width, height) = decoder.dimensions();
+    let mut pixels = buffer(decoder)?;
+    decoder.read_image(&mut pixels)?;
+    let [icc_profile, exif_metadata, xmp_metadata] = read_metadata(decoder)?;
+
+    let mut output = Vec::new();
+    let mut encoder = WebPEncoder::new(&mut output);
+    encoder.set_icc_profile(icc_profile);
+    encoder.set_exif_metadata(exif_metadata);
+    encoder.set_xmp_metadata(xmp_metadata);
+    encoder.set_params(params.clone());
+    encoder.encode(&pixels, width, height, color_type(decoder))?;
+    Ok(output)
+}
+
+/// Returns the smallest region, as `(x, y, width, height)`, that holds every pixel that differs
+/// between `a` and `b`. The region starts at even coordinates, as frame offsets must be even.
+fn changed_region(a: &[u8], b: &[u8], width: u32, bytes_per_pixel: usize) -> (u32, u32, u32, u32) {
+    let row_bytes = width as usize * bytes_per_pixel;
+    let mut region: Option<(usize, usize, usize, usize)> = None;
+    for (y, (row_a, row_b)) in a
+        .chunks_exact(row_bytes)
+        .zip(b.chunks_exact(row_bytes))
+        .enumerate()
+    {
+        let pixels = || {
+            row_a
+                .chunks_exact(bytes_per_pixel)
+                .zip(row_b.chunks_exact(bytes_per_pixel))
+        };
+        let Some(first) = pixels().position(|(a, b)| a != b) else {
+            continue;
+        };
+        let last =
+            row_a.len() / bytes_per_pixel - 1 - pixels().rev().position(|(a, b)| a != b).unwrap();
+        region = Some(match region {
+            None => (first, y, last, y),
+            Some((x0, y0, x1, _)) => (x0.min(first), y0, x1.max(last), y),
+        });
+    }
+
+    // Frames can't be empty, so an unchanged frame repeats a single pixel.
+    let (x0, y0, x1, y1) = region.unwrap_or((0, 0, 0, 0));
+    let (x0, y0) = (x0 & !1, y0 & !1);
+    (
+        x0 as u32,
+        y0 as u32,
+        (x1 - x0 + 1) as u32,
+        (y1 - y0 + 1) as u32,
+    )
+}
+
+fn encode_animation(
+    decoder: &mut WebPDecoder<Cursor<&[u8]>>,
+    params: &EncoderParams,
+) -> Result<Vec<u8>, ReoptimizeError> {
+    let (width, height) = decoder.dimensions();
+    let color = color_type(decoder);
+    let bytes_per_pixel = if decoder.has_alpha() { 4 } else { 3 };
+    let stride = width as usize * bytes_per_pixel;
+
+    let mut frames = Vec::new();
+    let mut canvas = buffer(decoder)?;
+    let mut previous: Option<Vec<u8>> = None;
+    for _ in 0..decoder.num_frames() {
+        let duration = decoder.read_frame(&mut canvas)?;
+
+        // The first frame covers the whole canvas, as not every decoder clears the canvas to the
+        // background color first.
+        let (x, y, frame_width, frame_height) = match &previous {
+            Some(previous) => changed_region(previous, &canvas, width, bytes_per_pixel),
+            None => (0, 0, width, height),
+        };
+        let start = y as usize * stride + x as usize * bytes_per_pixel;
+        let pixels = convert_frame(
+            &canvas[start..],
+            frame_width,
+            frame_height,
+            stride,
+            color,
+            params,
+        )?;
+        let mut frame = Vec::new();
+        encode_rgba_frame(
+            &mut frame,
+            pixels,
+            frame_width as u16,
+            frame_height as u16,
+            color,
+            params,
+        )?;
+
+        let mut anmf = Vec::new();
+        anmf.write_all(&(x / 2).to_le_bytes()[..3])?;
+        anmf.write_all(&(y / 2).to_le_bytes()[..3])?;
+        anmf.write_all(&(frame_width - 1).to_le_bytes()[..3])?;
+        anmf.write_all(&(frame_height - 1).to_le_bytes()[..3])?;
+        anmf.write_all(&duration.to_le_bytes()[..3])?;
+        // Frames replace the region they cover, and are never disposed.
+        anmf.write_all(&[0b10])?;
+        write_chunk(&mut anmf, b"VP8L", &frame)?;
+        frames.push(anmf);
+
+        match &mut previous {
+            Some(previous) => previous.copy_from_slice(&canvas),
+            None => previous = Some(canvas.clone()),
+        }
+    }
+
+    let [icc_profile, exif_metadata, xmp_metadata] = read_metadata(decoder)?;
+    let mut anim = decoder.background_color().unwrap().to_vec();
+    let loop_count = match decoder.loop_count() {
+        LoopCount::Forever => 0,
+        LoopCount::Times(n) => n.get(),
+    };
+    anim.extend_from_slice(&loop_count.to_le_bytes());
+
+    let mut flags = 1 << 1;
+    if !xmp_metadata.is_empty() {
+        flags |= 1 << 2;
+    }
+    if !exif_metadata.is_empty() {
+        flags |= 1 << 3;
+    }
+    if decoder.has_alpha() {
+        flags |= 1 << 4;
+    }
+    if !icc_profile.is_empty() {
+        flags |= 1 << 5;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut chunks: Vec<(&[u8], &[u8])> = vec![(b"VP8X", &vp8x)];
+    if !icc_profile.is_empty() {
+        chunks.push((b"ICCP", &icc_profile));
+    }
+    chunks.push((b"ANIM", &anim));
+    chunks.extend(frames.iter().map(|frame| (&b"ANMF"[..], &frame[..])));
+    if !exif_metadata.is_empty() {
+        chunks.push((b"EXIF", &exif_metadata));
+    }
+    if !xmp_metadata.is_empty() {
+        chunks.push((b"XMP ", &xmp_metadata));
+    }
+
+    let total_bytes: u32 = 4 + chunks
+        .iter()
+        .map(|(_, data)| chunk_size(data.len()))
+        .sum::<u32>();
+    let mut output = Vec::new();
+    output.write_all(b"RIFF")?;
+    output.write_all(&total_bytes.to_le_bytes())?;
+    output.write_all(b"WEBP")?;
+    for (name, data) in chunks {
+        write_chunk(&mut output, name, data)?;
+    }
+    Ok(output)
+}
+
+/// Checks that `encoded` decodes to the same frames as `original`, with the same timing and
+/// metadata.
+fn verify(original: &[u8], encoded: &[u8]) -> Result<(), ReoptimizeError> {
+    let mut a = WebPDecoder::new(Cursor::new(original))?;
+    let mut b = WebPDecoder::new(Cursor::new(encoded))?;
+    if a.dimensions() != b.dimensions()
+        || a.has_alpha() != b.has_alpha()
+        || a.is_animated() != b.is_animated()
+        || a.num_frames() != b.num_frames()
+        || a.loop_count() != b.loop_count()
+        || a.background_color() != b.background_color()
+        || read_metadata(&mut a)? != read_metadata(&mut b)?
+    {
+        return Err(ReoptimizeError::Mismatch);
+    }
+
+    let mut frame_a = buffer(&a)?;
+    let mut frame_b = buffer(&b)?;
+    if a.is_animated() {
+        for _ in 0..a.num_frames() {
+            if a.read_frame(&mut frame_a)? != b.read_frame(&mut frame_b)? || frame_a != frame_b {
+                return Err(ReoptimizeError::Mismatch);
+            }
+        }
+    } else {
+        a.read_image(&mut frame_a)?;
+        b.read_image(&mut frame_b)?;
+        if frame_a != frame_b {
+            return Err(ReoptimizeError::Mismatch);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn still_image() {
+        let (width, height) = (64, 48);
+        let img: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = ((i % width) as u8, (i / width) as u8);
+                [x ^ y, x.wrapping_mul(3), y, 255 - x]
+            })
+            .collect();
+
+        let mut original = Vec::new();
+        let mut encoder = WebPEncoder::new(&mut original);
+        encoder.set_exif_metadata(vec![1, 2, 3]);
+        encoder.set_params(EncoderParams {
+            method: 0,
+            exact: true,
+            ..Default::default()
+        });
+        encoder
+            .encode(&img, width as u32, height as u32, ColorType::Rgba8)
+            .unwrap();
+
+        let params = EncoderParams {
+            method: 6,
+            ..Default::default()
+        };
+        let Reoptimized::Smaller(encoded) = reoptimize(&original, &params).unwrap() else {
+            panic!("not smaller");
+        };
+        assert!(encoded.len() < original.len());
+
+        let mut decoder = WebPDecoder::new(Cursor::new(&encoded)).unwrap();
+        assert_eq!(decoder.exif_metadata().unwrap(), Some(vec![1, 2, 3]));
+        let mut decoded = vec![0; img.len()];
+        decoder.read_image(&mut decoded).unwrap();
+        assert_eq!(decoded, img);
+
+        // Doing it again doesn't help.
+        assert!(matches!(
+            reoptimize(&encoded, &params).unwrap(),
+            Reoptimized::NotSmaller
+        ));
+    }
+
+    #[test]
+    fn animation() {
+        let original = std::fs::read("tests/images/animated/random_lossless.webp").unwrap();
+        let encoded = match reoptimize(&original, &EncoderParams::default()).unwrap() {
+            Reoptimized::Smaller(encoded) => encoded,
+            Reoptimized::NotSmaller => {
+                // Still check the re-encoded file.
+                let mut decoder = WebPDecoder::new(Cursor::new(&original[..])).unwrap();
+                encode_animation(&mut decoder, &EncoderParams::default()).unwrap()
+            }
+            Reoptimized::Lossy => unreachable!(),
+        };
+        verify(&original, &encoded).unwrap();
+
+        // Other decoders see the same frames.
+        let mut decoder = WebPDecoder::new(Cursor::new(&encoded)).unwrap();
+        let frames = webp::AnimDecoder::new(&encoded).decode().unwrap();
+        assert_eq!(frames.len(), decoder.num_frames() as usize);
+        let mut frame = buffer(&decoder).unwrap();
+        for i in 0..frames.len() {
+            decoder.read_frame(&mut frame).unwrap();
+            let expected = frames.get_frame(i).unwrap();
+            let expected: Vec<u8> = if decoder.has_alpha() {
+                expected.get_image().to_vec()
+            } else {
+                expected
+                    .get_image()
+                    .chunks_exact(4)
+                    .flat_map(|p| &p[..3])
+                    .copied()
+                    .collect()
+            };
+            assert_eq!(expected, frame);
+        }
+    }
+
+    #[test]
+    fn lossy_still_image_kept() {
+        let original = std::fs::read("tests/images/gallery1/1.webp").unwrap();
+        assert!(matches!(
+            reoptimize(&original, &EncoderParams::default()).unwrap(),
+            Reoptimized::Lossy
+        ));
+    }
+}
@@ -81,3 +81,101 @@ pub(crate) fn iwht4x4(block: &mut [i32]) {
         block[3] = (d2 + 3) >> 3;
     }
 }
+
+/// Forward transform of a block of residuals, the inverse of [`idct4x4`] up to rounding.
+///
+/// Based on `FTransform` in libwebp.
+pub(crate) fn fdct4x4(block: &mut [i32]) {
+    // Perform one length check up front to avoid subsequent bounds checks in this function
+    assert!(block.len() >= 16);
+
+    for row in block.chunks_exact_mut(4) {
+        let a0 = row[0] + row[3];
+        let a1 = row[1] + row[2];
+        let a2 = row[1] - row[2];
+        let a3 = row[0] - row[3];
+
+        row[0] = (a0 + a1) * 8;
+        row[1] = (a2 * 2217 + a3 * 5352 + 1812) >> 9;
+        row[2] = (a0 - a1) * 8;
+        row[3] = (a3 * 2217 - a2 * 5352 + 937) >> 9;
+    }
+
+    for i in 0usize..4 {
+        let a0 = block[i] + block[12 + i];
+        let a1 = block[4 + i] + block[8 + i];
+        let a2 = block[4 + i] - block[8 + i];
+        let a3 = block[i] - block[12 + i];
+
+        block[i] = (a0 + a1 + 7) >> 4;
+        block[4 + i] = ((a2 * 2217 + a3 * 5352 + 12000) >> 16) + i32::from(a3 != 0);
+        block[8 + i] = (a0 - a1 + 7) >> 4;
+        block[12 + i] = (a3 * 2217 - a2 * 5352 + 51000) >> 16;
+    }
+}
+
+/// Forward Walsh-Hadamard transform of the DC coefficients of the 16 luma blocks of a
+/// macroblock, the inverse of [`iwht4x4`] up to rounding.
+///
+/// Based on `FTransformWHT` in libwebp.
+pub(crate) fn fwht4x4(block: &mut [i32]) {
+    // Perform one length check up front to avoid subsequent bounds checks in this function
+    assert!(block.len() >= 16);
+
+    for row in block.chunks_exact_mut(4) {
+        let a0 = row[0] + row[2];
+        let a1 = row[1] + row[3];
+        let a2 = row[1] - row[3];
+        let a3 = row[0] - row[2];
+
+        row[0] = a0 + a1;
+        row[1] = a3 + a2;
+        row[2] = a3 - a2;
+        row[3] = a0 - a1;
+    }
+
+    for i in 0usize..4 {
+        let a0 = block[i] + block[8 + i];
+        let a1 = block[4 + i] + block[12 + i];
+        let a2 = block[4 + i] - block[12 + i];
+        let a3 = block[i] - block[8 + i];
+
+        block[i] = (a0 + a1) >> 1;
+        block[4 + i] = (a3 + a2) >> 1;
+        block[8 + i] = (a3 - a2) >> 1;
+        block[12 + i] = (a0 - a1) >> 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forward_transforms_invert() {
+        let mut state = 1u32;
+        let mut random = |range: i32| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as i32 % (2 * range + 1) - range
+        };
+
+        for _ in 0..1000 {
+            let residuals: Vec<i32> = (0..16).map(|_| random(255)).collect();
+            let mut block = residuals.clone();
+            fdct4x4(&mut block);
+            idct4x4(&mut block);
+            for (a, b) in block.iter().zip(&residuals) {
+                assert!((a - b).abs() <= 1, "{block:?} != {residuals:?}");
+            }
+
+            // The inputs are DC coefficients, which are up to eight times the largest residual.
+            let dc: Vec<i32> = (0..16).map(|_| random(255 * 8)).collect();
+            let mut block = dc.clone();
+            fwht4x4(&mut block);
+            iwht4x4(&mut block);
+            for (a, b) in block.iter().zip(&dc) {
+                assert!((a - b).abs() <= 1, "{block:?} != {dc:?}");
+            }
+        }
+    }
+}
@@ -44,7 +44,7 @@ const B_HU_PRED: i8 = 9;
 // Prediction mode enum
 #[repr(i8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
-enum LumaMode {
+pub(crate) enum LumaMode {
     /// Predict DC using row above and column to the left.
     #[default]
     DC = DC_PRED,
@@ -64,7 +64,7 @@ enum LumaMode {
 
 #[repr(i8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
-enum ChromaMode {
+pub(crate) enum ChromaMode {
     /// Predict DC using row above and column to the left.
     #[default]
     DC = DC_PRED,
@@ -81,7 +81,7 @@ enum ChromaMode {
 
 #[repr(i8)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
-enum IntraMode {
+pub(crate) enum IntraMode {
     #[default]
     DC = B_DC_PRED,
     TM = B_TM_PRED,
@@ -95,7 +95,7 @@ enum IntraMode {
     HU = B_HU_PRED,
 }
 
-type Prob = u8;
+pub(crate) type Prob = u8;
 
 #[derive(Clone, Copy)]
 pub(crate) struct TreeNode {
@@ -148,7 +148,8 @@ const fn tree_nodes_from<const N: usize, const M: usize>(
 
 const SEGMENT_ID_TREE: [i8; 6] = [2, 4, -0, -1, -2, -3];
 
-const SEGMENT_TREE_NODE_DEFAULTS: [TreeNode; 3] = tree_nodes_from(SEGMENT_ID_TREE, [255; 3]);
+pub(crate) const SEGMENT_TREE_NODE_DEFAULTS: [TreeNode; 3] =
+    tree_nodes_from(SEGMENT_ID_TREE, [255; 3]);
 
 // Section 11.2
 // Tree for determining the keyframe luma intra prediction modes:
@@ -157,7 +158,7 @@ const KEYFRAME_YMODE_TREE: [i8; 8] = [-B_PRED, 2, 4, 6, -DC_PRED, -V_PRED, -H_PR
 // Default probabilities for decoding the keyframe luma modes
 const KEYFRAME_YMODE_PROBS: [Prob; 4] = [145, 156, 163, 128];
 
-const KEYFRAME_YMODE_NODES: [TreeNode; 4] =
+pub(crate) const KEYFRAME_YMODE_NODES: [TreeNode; 4] =
     tree_nodes_from(KEYFRAME_YMODE_TREE, KEYFRAME_YMODE_PROBS);
 
 // Tree for determining the keyframe B_PRED mode:
@@ -290,7 +291,7 @@ const KEYFRAME_BPRED_MODE_PROBS: [[[Prob; 9]; 10]; 10] = [
     ],
 ];
 
-const KEYFRAME_BPRED_MODE_NODES: [[[TreeNode; 9]; 10]; 10] = {
+pub(crate) const KEYFRAME_BPRED_MODE_NODES: [[[TreeNode; 9]; 10]; 10] = {
     let mut output = [[[TreeNode::UNINIT; 9]; 10]; 10];
     let mut i = 0;
     while i < output.len() {
@@ -311,15 +312,15 @@ const KEYFRAME_UV_MODE_TREE: [i8; 6] = [-DC_PRED, 2, -V_PRED, 4, -H_PRED, -TM_PR
 // Probabilities for determining macroblock mode
 const KEYFRAME_UV_MODE_PROBS: [Prob; 3] = [142, 114, 183];
 
-const KEYFRAME_UV_MODE_NODES: [TreeNode; 3] =
+pub(crate) const KEYFRAME_UV_MODE_NODES: [TreeNode; 3] =
     tree_nodes_from(KEYFRAME_UV_MODE_TREE, KEYFRAME_UV_MODE_PROBS);
 
 // Section 13.4
 type TokenProbTables = [[[[Prob; NUM_DCT_TOKENS - 1]; 3]; 8]; 4];
-type TokenProbTreeNodes = [[[[TreeNode; NUM_DCT_TOKENS - 1]; 3]; 8]; 4];
+pub(crate) type TokenProbTreeNodes = [[[[TreeNode; NUM_DCT_TOKENS - 1]; 3]; 8]; 4];
 
 // Probabilities that a token's probability will be updated
-const COEFF_UPDATE_PROBS: TokenProbTables = [
+pub(crate) const COEFF_UPDATE_PROBS: TokenProbTables = [
     [
         [
             [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
@@ -663,7 +664,7 @@ const COEFF_PROBS: TokenProbTables = [
     ],
 ];
 
-const COEFF_PROB_NODES: TokenProbTreeNodes = {
+pub(crate) const COEFF_PROB_NODES: TokenProbTreeNodes = {
     let mut output = [[[[TreeNode::UNINIT; 11]; 3]; 8]; 4];
     let mut i = 0;
     while i < output.len() {
@@ -682,25 +683,25 @@ const COEFF_PROB_NODES: TokenProbTreeNodes = {
 };
 
 // DCT Tokens
-const DCT_0: i8 = 0;
-const DCT_1: i8 = 1;
-const DCT_2: i8 = 2;
-const DCT_3: i8 = 3;
-const DCT_4: i8 = 4;
-const DCT_CAT1: i8 = 5;
-const DCT_CAT2: i8 = 6;
-const DCT_CAT3: i8 = 7;
-const DCT_CAT4: i8 = 8;
-const DCT_CAT5: i8 = 9;
-const DCT_CAT6: i8 = 10;
-const DCT_EOB: i8 = 11;
+pub(crate) const DCT_0: i8 = 0;
+pub(crate) const DCT_1: i8 = 1;
+pub(crate) const DCT_2: i8 = 2;
+pub(crate) const DCT_3: i8 = 3;
+pub(crate) const DCT_4: i8 = 4;
+pub(crate) const DCT_CAT1: i8 = 5;
+pub(crate) const DCT_CAT2: i8 = 6;
+pub(crate) const DCT_CAT3: i8 = 7;
+pub(crate) const DCT_CAT4: i8 = 8;
+pub(crate) const DCT_CAT5: i8 = 9;
+pub(crate) const DCT_CAT6: i8 = 10;
+pub(crate) const DCT_EOB: i8 = 11;
 
 const DCT_TOKEN_TREE: [i8; 22] = [
     -DCT_EOB, 2, -DCT_0, 4, -DCT_1, 6, 8, 12, -DCT_2, 10, -DCT_3, -DCT_4, 14, 16, -DCT_CAT1,
     -DCT_CAT2, 18, 20, -DCT_CAT3, -DCT_CAT4, -DCT_CAT5, -DCT_CAT6,
 ];
 
-const PROB_DCT_CAT: [[Prob; 12]; 6] = [
+pub(crate) const PROB_DCT_CAT: [[Prob; 12]; 6] = [
     [159, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
     [165, 145, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
     [173, 148, 140, 0, 0, 0, 0, 0, 0, 0, 0, 0],
@@ -709,11 +710,11 @@ const PROB_DCT_CAT: [[Prob; 12]; 6] = [
     [254, 254, 243, 230, 196, 177, 153, 140, 133, 130, 129, 0],
 ];
 
-const DCT_CAT_BASE: [u8; 6] = [5, 7, 11, 19, 35, 67];
-const COEFF_BANDS: [u8; 16] = [0, 1, 2, 3, 6, 4, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7];
+pub(crate) const DCT_CAT_BASE: [u8; 6] = [5, 7, 11, 19, 35, 67];
+pub(crate) const COEFF_BANDS: [u8; 16] = [0, 1, 2, 3, 6, 4, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7];
 
 #[rustfmt::skip]
-const DC_QUANT: [i16; 128] = [
+pub(crate) const DC_QUANT: [i16; 128] = [
       4,   5,   6,   7,   8,   9,  10,  10,
      11,  12,  13,  14,  15,  16,  17,  17,
      18,  19,  20,  20,  21,  21,  22,  22,
@@ -733,7 +734,7 @@ const DC_QUANT: [i16; 128] = [
 ];
 
 #[rustfmt::skip]
-const AC_QUANT: [i16; 128] = [
+pub(crate) const AC_QUANT: [i16; 128] = [
       4,   5,   6,   7,   8,    9,  10,  11,
       12,  13,  14,  15,  16,  17,  18,  19,
       20,  21,  22,  23,  24,  25,  26,  27,
@@ -752,7 +753,7 @@ const AC_QUANT: [i16; 128] = [
      249, 254, 259, 264, 269, 274, 279, 284,
 ];
 
-const ZIGZAG: [u8; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
+pub(crate) const ZIGZAG: [u8; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
 
 #[derive(Default, Clone, Copy)]
 struct MacroBlock {
@@ -796,19 +797,19 @@ pub struct Frame {
     pub pixel_type: u8,
 
     // Section 9.4 and 15
-    filter_type: bool, //if true uses simple filter // if false uses normal filter
-    filter_level: u8,
-    sharpness_level: u8,
+    pub(crate) filter_type: bool, //if true uses simple filter // if false uses normal filter
+    pub(crate) filter_level: u8,
+    pub(crate) sharpness_level: u8,
 }
 
 impl Frame {
     /// Chroma plane is half the size of the Luma plane
-    const fn chroma_width(&self) -> u16 {
-        (self.width + 1) / 2
+    pub(crate) const fn chroma_width(&self) -> u16 {
+        self.width.div_ceil(2)
     }
 
-    const fn chroma_height(&self) -> u16 {
-        (self.height + 1) / 2
+    pub(crate) const fn chroma_height(&self) -> u16 {
+        self.height.div_ceil(2)
     }
 
     /// Fills an rgb buffer with the image
@@ -966,6 +967,318 @@ impl Frame {
     pub fn get_buf_size(&self) -> usize {
         self.ybuf.len() * 3
     }
+
+    /// Does loop filtering on a macroblock of the reconstructed frame, at a level from 0 to 63.
+    /// The edges between its subblocks are only filtered if `filter_inner_edges` is set.
+    pub(crate) fn filter_macroblock(
+        &mut self,
+        mbx: usize,
+        mby: usize,
+        filter_level: u8,
+        filter_inner_edges: bool,
+    ) {
+        let luma_w = self.width as usize;
+        let luma_h = self.height as usize;
+        let chroma_w = self.chroma_width() as usize;
+        let chroma_h = self.chroma_height() as usize;
+
+        let (interior_limit, hev_threshold) = self.calculate_filter_parameters(filter_level);
+
+        if filter_level > 0 {
+            let mbedge_limit = (filter_level + 2) * 2 + interior_limit;
+            let sub_bedge_limit = (filter_level * 2) + interior_limit;
+
+            let luma_ylength = cmp::min(luma_h - 16 * mby, 16);
+            let luma_xlength = cmp::min(luma_w - 16 * mbx, 16);
+
+            let chroma_ylength = cmp::min(chroma_h - 8 * mby, 8);
+            let chroma_xlength = cmp::min(chroma_w - 8 * mbx, 8);
+
+            //filter across left of macroblock
+            if mbx > 0 {
+                //simple loop filtering
+                if self.filter_type {
+                    if luma_xlength >= 2 {
+                        for y in 0usize..luma_ylength {
+                            let y0 = mby * 16 + y;
+                            let x0 = mbx * 16;
+
+                            loop_filter::simple_segment(
+                                mbedge_limit,
+                                &mut self.ybuf[..],
+                                y0 * luma_w + x0,
+                                1,
+                            );
+                        }
+                    }
+                } else {
+                    if luma_xlength >= 4 {
+                        for y in 0usize..luma_ylength {
+                            let y0 = mby * 16 + y;
+                            let x0 = mbx * 16;
+
+                            loop_filter::macroblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                mbedge_limit,
+                                &mut self.ybuf[..],
+                                y0 * luma_w + x0,
+                                1,
+                            );
+                        }
+                    }
+
+                    if chroma_xlength >= 4 {
+                        for y in 0usize..chroma_ylength {
+                            let y0 = mby * 8 + y;
+                            let x0 = mbx * 8;
+
+                            loop_filter::macroblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                mbedge_limit,
+                                &mut self.ubuf[..],
+                                y0 * chroma_w + x0,
+                                1,
+                            );
+                            loop_filter::macroblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                mbedge_limit,
+                                &mut self.vbuf[..],
+                                y0 * chroma_w + x0,
+                                1,
+                            );
+                        }
+                    }
+                }
+            }
+
+            //filter across vertical subblocks in macroblock
+            if filter_inner_edges {
+                if self.filter_type {
+                    for x in (4usize..luma_xlength - 1).step_by(4) {
+                        for y in 0..luma_ylength {
+                            let y0 = mby * 16 + y;
+                            let x0 = mbx * 16 + x;
+
+                            loop_filter::simple_segment(
+                                sub_bedge_limit,
+                                &mut self.ybuf[..],
+                                y0 * luma_w + x0,
+                                1,
+                            );
+                        }
+                    }
+                } else {
+                    if luma_xlength > 3 {
+                        for x in (4usize..luma_xlength - 3).step_by(4) {
+                            for y in 0..luma_ylength {
+                                let y0 = mby * 16 + y;
+                                let x0 = mbx * 16 + x;
+
+                                loop_filter::subblock_filter(
+                                    hev_threshold,
+                                    interior_limit,
+                                    sub_bedge_limit,
+                                    &mut self.ybuf[..],
+                                    y0 * luma_w + x0,
+                                    1,
+                                );
+                            }
+                        }
+                    }
+
+                    if chroma_xlength == 8 {
+                        for y in 0usize..chroma_ylength {
+                            let y0 = mby * 8 + y;
+                            let x0 = mbx * 8 + 4;
+
+                            loop_filter::subblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                sub_bedge_limit,
+                                &mut self.ubuf[..],
+                                y0 * chroma_w + x0,
+                                1,
+                            );
+
+                            loop_filter::subblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                sub_bedge_limit,
+                                &mut self.vbuf[..],
+                                y0 * chroma_w + x0,
+                                1,
+                            );
+                        }
+                    }
+                }
+            }
+
+            //filter across top of macroblock
+            if mby > 0 {
+                if self.filter_type {
+                    if luma_ylength >= 2 {
+                        for x in 0usize..luma_xlength {
+                            let y0 = mby * 16;
+                            let x0 = mbx * 16 + x;
+
+                            loop_filter::simple_segment(
+                                mbedge_limit,
+                                &mut self.ybuf[..],
+                                y0 * luma_w + x0,
+                                luma_w,
+                            );
+                        }
+                    }
+                } else {
+                    //if bottom macroblock, can only filter if there is 3 pixels below
+                    if luma_ylength >= 4 {
+                        for x in 0usize..luma_xlength {
+                            let y0 = mby * 16;
+                            let x0 = mbx * 16 + x;
+
+                            loop_filter::macroblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                mbedge_limit,
+                                &mut self.ybuf[..],
+                                y0 * luma_w + x0,
+                                luma_w,
+                            );
+                        }
+                    }
+
+                    if chroma_ylength >= 4 {
+                        for x in 0usize..chroma_xlength {
+                            let y0 = mby * 8;
+                            let x0 = mbx * 8 + x;
+
+                            loop_filter::macroblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                mbedge_limit,
+                                &mut self.ubuf[..],
+                                y0 * chroma_w + x0,
+                                chroma_w,
+                            );
+                            loop_filter::macroblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                mbedge_limit,
+                                &mut self.vbuf[..],
+                                y0 * chroma_w + x0,
+                                chroma_w,
+                            );
+                        }
+                    }
+                }
+            }
+
+            //filter across horizontal subblock edges within the macroblock
+            if filter_inner_edges {
+                if self.filter_type {
+                    for y in (4usize..luma_ylength - 1).step_by(4) {
+                        for x in 0..luma_xlength {
+                            let y0 = mby * 16 + y;
+                            let x0 = mbx * 16 + x;
+
+                            loop_filter::simple_segment(
+                                sub_bedge_limit,
+                                &mut self.ybuf[..],
+                                y0 * luma_w + x0,
+                                luma_w,
+                            );
+                        }
+                    }
+                } else {
+                    if luma_ylength > 3 {
+                        for y in (4usize..luma_ylength - 3).step_by(4) {
+                            for x in 0..luma_xlength {
+                                let y0 = mby * 16 + y;
+                                let x0 = mbx * 16 + x;
+
+                                loop_filter::subblock_filter(
+                                    hev_threshold,
+                                    interior_limit,
+                                    sub_bedge_limit,
+                                    &mut self.ybuf[..],
+                                    y0 * luma_w + x0,
+                                    luma_w,
+                                );
+                            }
+                        }
+                    }
+
+                    if chroma_ylength == 8 {
+                        for x in 0..chroma_xlength {
+                            let y0 = mby * 8 + 4;
+                            let x0 = mbx * 8 + x;
+
+                            loop_filter::subblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                sub_bedge_limit,
+                                &mut self.ubuf[..],
+                                y0 * chroma_w + x0,
+                                chroma_w,
+                            );
+
+                            loop_filter::subblock_filter(
+                                hev_threshold,
+                                interior_limit,
+                                sub_bedge_limit,
+                                &mut self.vbuf[..],
+                                y0 * chroma_w + x0,
+                                chroma_w,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //return values are the interior limit and hev threshold
+    fn calculate_filter_parameters(&self, filter_level: u8) -> (u8, u8) {
+        //interior limit
+        let mut interior_limit = filter_level;
+
+        if self.sharpness_level > 0 {
+            interior_limit >>= if self.sharpness_level > 4 { 2 } else { 1 };
+
+            if interior_limit > 9 - self.sharpness_level {
+                interior_limit = 9 - self.sharpness_level;
+            }
+        }
+
+        if interior_limit == 0 {
+            interior_limit = 1;
+        }
+
+        //high edge variance threshold
+        let mut hev_threshold = 0;
+
+        #[allow(clippy::collapsible_else_if)]
+        if self.keyframe {
+            if filter_level >= 40 {
+                hev_threshold = 2;
+            } else if filter_level >= 15 {
+                hev_threshold = 1;
+            }
+        } else {
+            if filter_level >= 40 {
+                hev_threshold = 3;
+            } else if filter_level >= 20 {
+                hev_threshold = 2;
+            } else if filter_level >= 15 {
+                hev_threshold = 1;
+            }
+        }
+
+        (interior_limit, hev_threshold)
+    }
 }
 
 /// `_mm_mulhi_epu16` emulation used in `Frame::fill_rgb` and `Frame::fill_rgba`.
@@ -1136,7 +1449,7 @@ impl<R: Read> Vp8Decoder<R> {
                     .expect("Reading from &[u8] can't fail and the chunk is complete");
 
                 let size = size as usize;
-                let mut buf = vec![[0; 4]; (size + 3) / 4];
+                let mut buf = vec![[0; 4]; size.div_ceil(4)];
                 let bytes: &mut [u8] = buf.as_mut_slice().as_flattened_mut();
                 self.r.read_exact(&mut bytes[..size])?;
                 self.partitions[i].init(buf, size)?;
@@ -1146,7 +1459,7 @@ impl<R: Read> Vp8Decoder<R> {
         let mut buf = Vec::new();
         self.r.read_to_end(&mut buf)?;
         let size = buf.len();
-        let mut chunks = vec![[0; 4]; (size + 3) / 4];
+        let mut chunks = vec![[0; 4]; size.div_ceil(4)];
         chunks.as_mut_slice().as_flattened_mut()[..size].copy_from_slice(&buf);
         self.partitions[n - 1].init(chunks, size)?;
 
@@ -1293,8 +1606,8 @@ impl<R: Read> Vp8Decoder<R> {
             // Almost always the first macro block, except when non exists (i.e. `width == 0`)
             self.left = self.top.first().copied().unwrap_or_default();
 
-            self.mbwidth = (self.frame.width + 15) / 16;
-            self.mbheight = (self.frame.height + 15) / 16;
+            self.mbwidth = self.frame.width.div_ceil(16);
+            self.mbheight = self.frame.height.div_ceil(16);
 
             self.frame.ybuf = vec![0u8; self.frame.width as usize * self.frame.height as usize];
             self.frame.ubuf =
@@ -1307,7 +1620,7 @@ impl<R: Read> Vp8Decoder<R> {
         }
 
         let size = first_partition_size as usize;
-        let mut buf = vec![[0; 4]; (size + 3) / 4];
+        let mut buf = vec![[0; 4]; size.div_ceil(4)];
         let bytes: &mut [u8] = buf.as_mut_slice().as_flattened_mut();
         self.r.read_exact(&mut bytes[..size])?;
 
@@ -1516,54 +1829,10 @@ impl<R: Read> Vp8Decoder<R> {
 
         let w = self.frame.chroma_width() as usize;
 
-        //8x8 with left top border of 1
-        let mut uws = [0u8; (8 + 1) * (8 + 1)];
-        let mut vws = [0u8; (8 + 1) * (8 + 1)];
-
         let ylength = cmp::min(self.frame.chroma_height() as usize - mby * 8, 8);
         let xlength = cmp::min(self.frame.chroma_width() as usize - mbx * 8, 8);
 
-        //left border
-        for y in 0usize..8 {
-            let (uy, vy) = if mbx == 0 || y >= ylength {
-                (129, 129)
-            } else {
-                let index = (mby * 8 + y) * w + ((mbx - 1) * 8 + 7);
-                (self.frame.ubuf[index], self.frame.vbuf[index])
-            };
-
-            uws[(y + 1) * stride] = uy;
-            vws[(y + 1) * stride] = vy;
-        }
-        //top border
-        for x in 0usize..8 {
-            let (ux, vx) = if mby == 0 || x >= xlength {
-                (127, 127)
-            } else {
-                let index = ((mby - 1) * 8 + 7) * w + (mbx * 8 + x);
-                (self.frame.ubuf[index], self.frame.vbuf[index])
-            };
-
-            uws[x + 1] = ux;
-            vws[x + 1] = vx;
-        }
-
-        //top left point
-        let (u1, v1) = if mby == 0 {
-            (127, 127)
-        } else if mbx == 0 {
-            (129, 129)
-        } else {
-            let index = ((mby - 1) * 8 + 7) * w + (mbx - 1) * 8 + 7;
-            if index >= self.frame.ubuf.len() {
-                (127, 127)
-            } else {
-                (self.frame.ubuf[index], self.frame.vbuf[index])
-            }
-        };
-
-        uws[0] = u1;
-        vws[0] = v1;
+        let (mut uws, mut vws) = create_border_chroma(mbx, mby, &self.frame);
 
         match mb.chroma_mode {
             ChromaMode::DC => {
@@ -1702,356 +1971,98 @@ impl<R: Read> Vp8Decoder<R> {
 
     fn read_residual_data(
         &mut self,
-        mb: &MacroBlock,
-        mbx: usize,
-        p: usize,
-    ) -> Result<[i32; 384], DecodingError> {
-        let sindex = mb.segmentid as usize;
-        let mut blocks = [0i32; 384];
-        let mut plane = if mb.luma_mode == LumaMode::B { 3 } else { 1 };
-
-        if plane == 1 {
-            let complexity = self.top[mbx].complexity[0] + self.left.complexity[0];
-            let mut block = [0i32; 16];
-            let dcq = self.segment[sindex].y2dc;
-            let acq = self.segment[sindex].y2ac;
-            let n = self.read_coefficients(&mut block, p, plane, complexity as usize, dcq, acq)?;
-
-            self.left.complexity[0] = if n { 1 } else { 0 };
-            self.top[mbx].complexity[0] = if n { 1 } else { 0 };
-
-            transform::iwht4x4(&mut block);
-
-            for k in 0usize..16 {
-                blocks[16 * k] = block[k];
-            }
-
-            plane = 0;
-        }
-
-        for y in 0usize..4 {
-            let mut left = self.left.complexity[y + 1];
-            for x in 0usize..4 {
-                let i = x + y * 4;
-                let block = &mut blocks[i * 16..][..16];
-                let block: &mut [i32; 16] = block.try_into().unwrap();
-
-                let complexity = self.top[mbx].complexity[x + 1] + left;
-                let dcq = self.segment[sindex].ydc;
-                let acq = self.segment[sindex].yac;
-
-                let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq)?;
-
-                if block[0] != 0 || n {
-                    transform::idct4x4(block);
-                }
-
-                left = if n { 1 } else { 0 };
-                self.top[mbx].complexity[x + 1] = if n { 1 } else { 0 };
-            }
-
-            self.left.complexity[y + 1] = left;
-        }
-
-        plane = 2;
-
-        for &j in &[5usize, 7usize] {
-            for y in 0usize..2 {
-                let mut left = self.left.complexity[y + j];
-
-                for x in 0usize..2 {
-                    let i = x + y * 2 + if j == 5 { 16 } else { 20 };
-                    let block = &mut blocks[i * 16..][..16];
-                    let block: &mut [i32; 16] = block.try_into().unwrap();
-
-                    let complexity = self.top[mbx].complexity[x + j] + left;
-                    let dcq = self.segment[sindex].uvdc;
-                    let acq = self.segment[sindex].uvac;
-
-                    let n =
-                        self.read_coefficients(block, p, plane, complexity as usize, dcq, acq)?;
-                    if block[0] != 0 || n {
-                        transform::idct4x4(block);
-                    }
-
-                    left = if n { 1 } else { 0 };
-                    self.top[mbx].complexity[x + j] = if n { 1 } else { 0 };
-                }
-
-                self.left.complexity[y + j] = left;
-            }
-        }
-
-        Ok(blocks)
-    }
-
-    /// Does loop filtering on the macroblock
-    fn loop_filter(&mut self, mbx: usize, mby: usize, mb: &MacroBlock) {
-        let luma_w = self.frame.width as usize;
-        let luma_h = self.frame.height as usize;
-        let chroma_w = self.frame.chroma_width() as usize;
-        let chroma_h = self.frame.chroma_height() as usize;
-
-        let (filter_level, interior_limit, hev_threshold) = self.calculate_filter_parameters(mb);
-
-        if filter_level > 0 {
-            let mbedge_limit = (filter_level + 2) * 2 + interior_limit;
-            let sub_bedge_limit = (filter_level * 2) + interior_limit;
-
-            let luma_ylength = cmp::min(luma_h - 16 * mby, 16);
-            let luma_xlength = cmp::min(luma_w - 16 * mbx, 16);
-
-            let chroma_ylength = cmp::min(chroma_h - 8 * mby, 8);
-            let chroma_xlength = cmp::min(chroma_w - 8 * mbx, 8);
-
-            //filter across left of macroblock
-            if mbx > 0 {
-                //simple loop filtering
-                if self.frame.filter_type {
-                    if luma_xlength >= 2 {
-                        for y in 0usize..luma_ylength {
-                            let y0 = mby * 16 + y;
-                            let x0 = mbx * 16;
-
-                            loop_filter::simple_segment(
-                                mbedge_limit,
-                                &mut self.frame.ybuf[..],
-                                y0 * luma_w + x0,
-                                1,
-                            );
-                        }
-                    }
-                } else {
-                    if luma_xlength >= 4 {
-                        for y in 0usize..luma_ylength {
-                            let y0 = mby * 16 + y;
-                            let x0 = mbx * 16;
-
-                            loop_filter::macroblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                mbedge_limit,
-                                &mut self.frame.ybuf[..],
-                                y0 * luma_w + x0,
-                                1,
-                            );
-                        }
-                    }
-
-                    if chroma_xlength >= 4 {
-                        for y in 0usize..chroma_ylength {
-                            let y0 = mby * 8 + y;
-                            let x0 = mbx * 8;
-
-                            loop_filter::macroblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                mbedge_limit,
-                                &mut self.frame.ubuf[..],
-                                y0 * chroma_w + x0,
-                                1,
-                            );
-                            loop_filter::macroblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                mbedge_limit,
-                                &mut self.frame.vbuf[..],
-                                y0 * chroma_w + x0,
-                                1,
-                            );
-                        }
-                    }
-                }
-            }
-
-            //filter across vertical subblocks in macroblock
-            if mb.luma_mode == LumaMode::B || !mb.coeffs_skipped {
-                if self.frame.filter_type {
-                    for x in (4usize..luma_xlength - 1).step_by(4) {
-                        for y in 0..luma_ylength {
-                            let y0 = mby * 16 + y;
-                            let x0 = mbx * 16 + x;
-
-                            loop_filter::simple_segment(
-                                sub_bedge_limit,
-                                &mut self.frame.ybuf[..],
-                                y0 * luma_w + x0,
-                                1,
-                            );
-                        }
-                    }
-                } else {
-                    if luma_xlength > 3 {
-                        for x in (4usize..luma_xlength - 3).step_by(4) {
-                            for y in 0..luma_ylength {
-                                let y0 = mby * 16 + y;
-                                let x0 = mbx * 16 + x;
+        mb: &MacroBlock,
+        mbx: usize,
+        p: usize,
+    ) -> Result<[i32; 384], DecodingError> {
+        let sindex = mb.segmentid as usize;
+        let mut blocks = [0i32; 384];
+        let mut plane = if mb.luma_mode == LumaMode::B { 3 } else { 1 };
 
-                                loop_filter::subblock_filter(
-                                    hev_threshold,
-                                    interior_limit,
-                                    sub_bedge_limit,
-                                    &mut self.frame.ybuf[..],
-                                    y0 * luma_w + x0,
-                                    1,
-                                );
-                            }
-                        }
-                    }
+        if plane == 1 {
+            let complexity = self.top[mbx].complexity[0] + self.left.complexity[0];
+            let mut block = [0i32; 16];
+            let dcq = self.segment[sindex].y2dc;
+            let acq = self.segment[sindex].y2ac;
+            let n = self.read_coefficients(&mut block, p, plane, complexity as usize, dcq, acq)?;
 
-                    if chroma_xlength == 8 {
-                        for y in 0usize..chroma_ylength {
-                            let y0 = mby * 8 + y;
-                            let x0 = mbx * 8 + 4;
+            self.left.complexity[0] = if n { 1 } else { 0 };
+            self.top[mbx].complexity[0] = if n { 1 } else { 0 };
 
-                            loop_filter::subblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                sub_bedge_limit,
-                                &mut self.frame.ubuf[..],
-                                y0 * chroma_w + x0,
-                                1,
-                            );
+            transform::iwht4x4(&mut block);
 
-                            loop_filter::subblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                sub_bedge_limit,
-                                &mut self.frame.vbuf[..],
-                                y0 * chroma_w + x0,
-                                1,
-                            );
-                        }
-                    }
-                }
+            for k in 0usize..16 {
+                blocks[16 * k] = block[k];
             }
 
-            //filter across top of macroblock
-            if mby > 0 {
-                if self.frame.filter_type {
-                    if luma_ylength >= 2 {
-                        for x in 0usize..luma_xlength {
-                            let y0 = mby * 16;
-                            let x0 = mbx * 16 + x;
+            plane = 0;
+        }
 
-                            loop_filter::simple_segment(
-                                mbedge_limit,
-                                &mut self.frame.ybuf[..],
-                                y0 * luma_w + x0,
-                                luma_w,
-                            );
-                        }
-                    }
-                } else {
-                    //if bottom macroblock, can only filter if there is 3 pixels below
-                    if luma_ylength >= 4 {
-                        for x in 0usize..luma_xlength {
-                            let y0 = mby * 16;
-                            let x0 = mbx * 16 + x;
+        for y in 0usize..4 {
+            let mut left = self.left.complexity[y + 1];
+            for x in 0usize..4 {
+                let i = x + y * 4;
+                let block = &mut blocks[i * 16..][..16];
+                let block: &mut [i32; 16] = block.try_into().unwrap();
 
-                            loop_filter::macroblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                mbedge_limit,
-                                &mut self.frame.ybuf[..],
-                                y0 * luma_w + x0,
-                                luma_w,
-                            );
-                        }
-                    }
+                let complexity = self.top[mbx].complexity[x + 1] + left;
+                let dcq = self.segment[sindex].ydc;
+                let acq = self.segment[sindex].yac;
 
-                    if chroma_ylength >= 4 {
-                        for x in 0usize..chroma_xlength {
-                            let y0 = mby * 8;
-                            let x0 = mbx * 8 + x;
+                let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq)?;
 
-                            loop_filter::macroblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                mbedge_limit,
-                                &mut self.frame.ubuf[..],
-                                y0 * chroma_w + x0,
-                                chroma_w,
-                            );
-                            loop_filter::macroblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                mbedge_limit,
-                                &mut self.frame.vbuf[..],
-                                y0 * chroma_w + x0,
-                                chroma_w,
-                            );
-                        }
-                    }
+                if block[0] != 0 || n {
+                    transform::idct4x4(block);
                 }
+
+                left = if n { 1 } else { 0 };
+                self.top[mbx].complexity[x + 1] = if n { 1 } else { 0 };
             }
 
-            //filter across horizontal subblock edges within the macroblock
-            if mb.luma_mode == LumaMode::B || !mb.coeffs_skipped {
-                if self.frame.filter_type {
-                    for y in (4usize..luma_ylength - 1).step_by(4) {
-                        for x in 0..luma_xlength {
-                            let y0 = mby * 16 + y;
-                            let x0 = mbx * 16 + x;
+            self.left.complexity[y + 1] = left;
+        }
 
-                            loop_filter::simple_segment(
-                                sub_bedge_limit,
-                                &mut self.frame.ybuf[..],
-                                y0 * luma_w + x0,
-                                luma_w,
-                            );
-                        }
-                    }
-                } else {
-                    if luma_ylength > 3 {
-                        for y in (4usize..luma_ylength - 3).step_by(4) {
-                            for x in 0..luma_xlength {
-                                let y0 = mby * 16 + y;
-                                let x0 = mbx * 16 + x;
+        plane = 2;
 
-                                loop_filter::subblock_filter(
-                                    hev_threshold,
-                                    interior_limit,
-                                    sub_bedge_limit,
-                                    &mut self.frame.ybuf[..],
-                                    y0 * luma_w + x0,
-                                    luma_w,
-                                );
-                            }
-                        }
-                    }
+        for &j in &[5usize, 7usize] {
+            for y in 0usize..2 {
+                let mut left = self.left.complexity[y + j];
 
-                    if chroma_ylength == 8 {
-                        for x in 0..chroma_xlength {
-                            let y0 = mby * 8 + 4;
-                            let x0 = mbx * 8 + x;
+                for x in 0usize..2 {
+                    let i = x + y * 2 + if j == 5 { 16 } else { 20 };
+                    let block = &mut blocks[i * 16..][..16];
+                    let block: &mut [i32; 16] = block.try_into().unwrap();
 
-                            loop_filter::subblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                sub_bedge_limit,
-                                &mut self.frame.ubuf[..],
-                                y0 * chroma_w + x0,
-                                chroma_w,
-                            );
+                    let complexity = self.top[mbx].complexity[x + j] + left;
+                    let dcq = self.segment[sindex].uvdc;
+                    let acq = self.segment[sindex].uvac;
 
-                            loop_filter::subblock_filter(
-                                hev_threshold,
-                                interior_limit,
-                                sub_bedge_limit,
-                                &mut self.frame.vbuf[..],
-                                y0 * chroma_w + x0,
-                                chroma_w,
-                            );
-                        }
+                    let n =
+                        self.read_coefficients(block, p, plane, complexity as usize, dcq, acq)?;
+                    if block[0] != 0 || n {
+                        transform::idct4x4(block);
                     }
+
+                    left = if n { 1 } else { 0 };
+                    self.top[mbx].complexity[x + j] = if n { 1 } else { 0 };
                 }
+
+                self.left.complexity[y + j] = left;
             }
         }
+
+        Ok(blocks)
+    }
+
+    /// Does loop filtering on the macroblock
+    fn loop_filter(&mut self, mbx: usize, mby: usize, mb: &MacroBlock) {
+        let filter_level = self.calculate_filter_level(mb);
+        let filter_inner_edges = mb.luma_mode == LumaMode::B || !mb.coeffs_skipped;
+        self.frame
+            .filter_macroblock(mbx, mby, filter_level, filter_inner_edges);
     }
 
-    //return values are the filter level, interior limit and hev threshold
-    fn calculate_filter_parameters(&self, macroblock: &MacroBlock) -> (u8, u8, u8) {
+    fn calculate_filter_level(&self, macroblock: &MacroBlock) -> u8 {
         let segment = self.segment[macroblock.segmentid as usize];
         let mut filter_level = i32::from(self.frame.filter_level);
 
@@ -2069,44 +2080,7 @@ impl<R: Read> Vp8Decoder<R> {
             filter_level += self.mode_delta[0];
         }
 
-        let filter_level = filter_level.clamp(0, 63) as u8;
-
-        //interior limit
-        let mut interior_limit = filter_level;
-
-        if self.frame.sharpness_level > 0 {
-            interior_limit >>= if self.frame.sharpness_level > 4 { 2 } else { 1 };
-
-            if interior_limit > 9 - self.frame.sharpness_level {
-                interior_limit = 9 - self.frame.sharpness_level;
-            }
-        }
-
-        if interior_limit == 0 {
-            interior_limit = 1;
-        }
-
-        //high edge variance threshold
-        let mut hev_threshold = 0;
-
-        #[allow(clippy::collapsible_else_if)]
-        if self.frame.keyframe {
-            if filter_level >= 40 {
-                hev_threshold = 2;
-            } else {
-                hev_threshold = 1;
-            }
-        } else {
-            if filter_level >= 40 {
-                hev_threshold = 3;
-            } else if filter_level >= 20 {
-                hev_threshold = 2;
-            } else if filter_level >= 15 {
-                hev_threshold = 1;
-            }
-        }
-
-        (filter_level, interior_limit, hev_threshold)
+        filter_level.clamp(0, 63) as u8
     }
 
     /// Decodes the current frame
@@ -2173,7 +2147,7 @@ impl LumaMode {
         })
     }
 
-    const fn into_intra(self) -> Option<IntraMode> {
+    pub(crate) const fn into_intra(self) -> Option<IntraMode> {
         Some(match self {
             Self::DC => IntraMode::DC,
             Self::V => IntraMode::VE,
@@ -2215,7 +2189,7 @@ impl IntraMode {
 }
 
 fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
-    let mb_width = (width + 15) / 16;
+    let mb_width = width.div_ceil(16);
 
     let mb = MacroBlock {
         // Section 11.3 #3
@@ -2227,7 +2201,13 @@ fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
     vec![mb; mb_width]
 }
 
-fn create_border_luma(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
+pub(crate) fn create_border_luma(
+    mbx: usize,
+    mby: usize,
+    mbw: usize,
+    top: &[u8],
+    left: &[u8],
+) -> [u8; 357] {
     let stride = 1usize + 16 + 4;
     let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
 
@@ -2284,6 +2264,65 @@ fn create_border_luma(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8
     ws
 }
 
+/// Creates the workspaces for predicting the chroma planes of a macroblock, each an 8x8 block
+/// with a border of one pixel above and to the left taken from `frame`.
+pub(crate) fn create_border_chroma(mbx: usize, mby: usize, frame: &Frame) -> ([u8; 81], [u8; 81]) {
+    let stride = 1usize + 8;
+
+    let w = frame.chroma_width() as usize;
+
+    //8x8 with left top border of 1
+    let mut uws = [0u8; (8 + 1) * (8 + 1)];
+    let mut vws = [0u8; (8 + 1) * (8 + 1)];
+
+    let ylength = cmp::min(frame.chroma_height() as usize - mby * 8, 8);
+    let xlength = cmp::min(frame.chroma_width() as usize - mbx * 8, 8);
+
+    //left border
+    for y in 0usize..8 {
+        let (uy, vy) = if mbx == 0 || y >= ylength {
+            (129, 129)
+        } else {
+            let index = (mby * 8 + y) * w + ((mbx - 1) * 8 + 7);
+            (frame.ubuf[index], frame.vbuf[index])
+        };
+
+        uws[(y + 1) * stride] = uy;
+        vws[(y + 1) * stride] = vy;
+    }
+    //top border
+    for x in 0usize..8 {
+        let (ux, vx) = if mby == 0 || x >= xlength {
+            (127, 127)
+        } else {
+            let index = ((mby - 1) * 8 + 7) * w + (mbx * 8 + x);
+            (frame.ubuf[index], frame.vbuf[index])
+        };
+
+        uws[x + 1] = ux;
+        vws[x + 1] = vx;
+    }
+
+    //top left point
+    let (u1, v1) = if mby == 0 {
+        (127, 127)
+    } else if mbx == 0 {
+        (129, 129)
+    } else {
+        let index = ((mby - 1) * 8 + 7) * w + (mbx - 1) * 8 + 7;
+        if index >= frame.ubuf.len() {
+            (127, 127)
+        } else {
+            (frame.ubuf[index], frame.vbuf[index])
+        }
+    };
+
+    uws[0] = u1;
+    vws[0] = v1;
+
+    (uws, vws)
+}
+
 fn avg3(left: u8, this: u8, right: u8) -> u8 {
     let avg = (u16::from(left) + 2 * u16::from(this) + u16::from(right) + 2) >> 2;
     avg as u8
@@ -2299,7 +2338,13 @@ fn avg2(this: u8, right: u8) -> u8 {
 //
 // Clippy suggests the clamp method, but it seems to optimize worse as of rustc 1.82.0 nightly.
 #[allow(clippy::manual_clamp)]
-fn add_residue(pblock: &mut [u8], rblock: &[i32; 16], y0: usize, x0: usize, stride: usize) {
+pub(crate) fn add_residue(
+    pblock: &mut [u8],
+    rblock: &[i32; 16],
+    y0: usize,
+    x0: usize,
+    stride: usize,
+) {
     let mut pos = y0 * stride + x0;
     for row in rblock.chunks(4) {
         for (p, &a) in pblock[pos..][..4].iter_mut().zip(row.iter()) {
@@ -2316,18 +2361,7 @@ fn predict_4x4(ws: &mut [u8], stride: usize, modes: &[IntraMode], resdata: &[i32
             let y0 = sby * 4 + 1;
             let x0 = sbx * 4 + 1;
 
-            match modes[i] {
-                IntraMode::TM => predict_tmpred(ws, 4, x0, y0, stride),
-                IntraMode::VE => predict_bvepred(ws, x0, y0, stride),
-                IntraMode::HE => predict_bhepred(ws, x0, y0, stride),
-                IntraMode::DC => predict_bdcpred(ws, x0, y0, stride),
-                IntraMode::LD => predict_bldpred(ws, x0, y0, stride),
-                IntraMode::RD => predict_brdpred(ws, x0, y0, stride),
-                IntraMode::VR => predict_bvrpred(ws, x0, y0, stride),
-                IntraMode::VL => predict_bvlpred(ws, x0, y0, stride),
-                IntraMode::HD => predict_bhdpred(ws, x0, y0, stride),
-                IntraMode::HU => predict_bhupred(ws, x0, y0, stride),
-            }
+            predict_subblock(ws, modes[i], x0, y0, stride);
 
             let rb: &[i32; 16] = resdata[i * 16..][..16].try_into().unwrap();
             add_residue(ws, rb, y0, x0, stride);
@@ -2335,7 +2369,29 @@ fn predict_4x4(ws: &mut [u8], stride: usize, modes: &[IntraMode], resdata: &[i32
     }
 }
 
-fn predict_vpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
+/// Predicts the 4x4 subblock at (`x0`, `y0`) with a subblock mode.
+pub(crate) fn predict_subblock(
+    ws: &mut [u8],
+    mode: IntraMode,
+    x0: usize,
+    y0: usize,
+    stride: usize,
+) {
+    match mode {
+        IntraMode::TM => predict_tmpred(ws, 4, x0, y0, stride),
+        IntraMode::VE => predict_bvepred(ws, x0, y0, stride),
+        IntraMode::HE => predict_bhepred(ws, x0, y0, stride),
+        IntraMode::DC => predict_bdcpred(ws, x0, y0, stride),
+        IntraMode::LD => predict_bldpred(ws, x0, y0, stride),
+        IntraMode::RD => predict_brdpred(ws, x0, y0, stride),
+        IntraMode::VR => predict_bvrpred(ws, x0, y0, stride),
+        IntraMode::VL => predict_bvlpred(ws, x0, y0, stride),
+        IntraMode::HD => predict_bhdpred(ws, x0, y0, stride),
+        IntraMode::HU => predict_bhupred(ws, x0, y0, stride),
+    }
+}
+
+pub(crate) fn predict_vpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
     // This pass copies the top row to the rows below it.
     let (above, curr) = a.split_at_mut(stride * y0);
     let above_slice = &above[x0..];
@@ -2347,7 +2403,7 @@ fn predict_vpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize)
     }
 }
 
-fn predict_hpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
+pub(crate) fn predict_hpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
     // This pass copies the first value of a row to the values right of it.
     for chunk in a.chunks_exact_mut(stride).skip(y0).take(size) {
         let left = chunk[x0 - 1];
@@ -2355,7 +2411,7 @@ fn predict_hpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize)
     }
 }
 
-fn predict_dcpred(a: &mut [u8], size: usize, stride: usize, above: bool, left: bool) {
+pub(crate) fn predict_dcpred(a: &mut [u8], size: usize, stride: usize, above: bool, left: bool) {
     let mut sum = 0;
     let mut shf = if size == 8 { 2 } else { 3 };
 
@@ -2388,7 +2444,7 @@ fn predict_dcpred(a: &mut [u8], size: usize, stride: usize, above: bool, left: b
 
 // Clippy suggests the clamp method, but it seems to optimize worse as of rustc 1.82.0 nightly.
 #[allow(clippy::manual_clamp)]
-fn predict_tmpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
+pub(crate) fn predict_tmpred(a: &mut [u8], size: usize, x0: usize, y0: usize, stride: usize) {
     // The formula for tmpred is:
     // X_ij = L_i + A_j - P (i, j=0, 1, 2, 3)
     //
@@ -572,9 +572,9 @@ mod tests {
         buf.as_mut_slice().as_flattened_mut()[..size].copy_from_slice(&data[..]);
         decoder.init(buf, size).unwrap();
         let mut res = decoder.start_accumulated_result();
-        assert_eq!(false, decoder.read_flag().or_accumulate(&mut res));
-        assert_eq!(true, decoder.read_bool(10).or_accumulate(&mut res));
-        assert_eq!(false, decoder.read_bool(250).or_accumulate(&mut res));
+        assert!(!decoder.read_flag().or_accumulate(&mut res));
+        assert!(decoder.read_bool(10).or_accumulate(&mut res));
+        assert!(!decoder.read_bool(250).or_accumulate(&mut res));
         assert_eq!(1, decoder.read_literal(1).or_accumulate(&mut res));
         assert_eq!(5, decoder.read_literal(3).or_accumulate(&mut res));
         assert_eq!(64, decoder.read_literal(8).or_accumulate(&mut res));
@@ -587,13 +587,13 @@ mod tests {
         let mut decoder = ArithmeticDecoder::new();
         let data = b"hello world";
         let size = data.len();
-        let mut buf = vec![[0u8; 4]; (size + 3) / 4];
+        let mut buf = vec![[0u8; 4]; size.div_ceil(4)];
         buf.as_mut_slice().as_flattened_mut()[..size].copy_from_slice(&data[..]);
         decoder.init(buf, size).unwrap();
         let mut res = decoder.start_accumulated_result();
-        assert_eq!(false, decoder.read_flag().or_accumulate(&mut res));
-        assert_eq!(true, decoder.read_bool(10).or_accumulate(&mut res));
-        assert_eq!(false, decoder.read_bool(250).or_accumulate(&mut res));
+        assert!(!decoder.read_flag().or_accumulate(&mut res));
+        assert!(decoder.read_bool(10).or_accumulate(&mut res));
+        assert!(!decoder.read_bool(250).or_accumulate(&mut res));
         assert_eq!(1, decoder.read_literal(1).or_accumulate(&mut res));
         assert_eq!(5, decoder.read_literal(3).or_accumulate(&mut res));
         assert_eq!(64, decoder.read_literal(8).or_accumulate(&mut res));
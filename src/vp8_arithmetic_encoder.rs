@@ -0,0 +1,307 @@
+use super::vp8::TreeNode;
+
+/// Boolean entropy encoder, producing the bitstreams that [`ArithmeticDecoder`] reads.
+///
+/// Based on the encoder in section 7.3 of RFC 6386.
+///
+/// [`ArithmeticDecoder`]: super::vp8_arithmetic_decoder::ArithmeticDecoder
+pub(crate) struct ArithmeticEncoder {
+    output: Vec<u8>,
+    range: u32,
+    bottom: u32,
+    bit_count: i32,
+}
+
+impl ArithmeticEncoder {
+    pub(crate) fn new() -> ArithmeticEncoder {
+        ArithmeticEncoder {
+            output: Vec::new(),
+            range: 255,
+            bottom: 0,
+            bit_count: 24,
+        }
+    }
+
+    /// Propagate a carry into the bytes written so far.
+    fn add_one_to_output(&mut self) {
+        for byte in self.output.iter_mut().rev() {
+            if *byte == 255 {
+                *byte = 0;
+            } else {
+                *byte += 1;
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn write_bool(&mut self, value: bool, probability: u8) {
+        let split = 1 + (((self.range - 1) * u32::from(probability)) >> 8);
+        if value {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        while self.range < 128 {
+            self.range <<= 1;
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one_to_output();
+            }
+            self.bottom <<= 1;
+
+            self.bit_count -= 1;
+            if self.bit_count == 0 {
+                self.output.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    pub(crate) fn write_flag(&mut self, value: bool) {
+        self.write_bool(value, 128);
+    }
+
+    /// Writes the lowest `n` bits of `value`, most significant bit first.
+    pub(crate) fn write_literal(&mut self, n: u8, value: u8) {
+        for bit in (0..n).rev() {
+            self.write_flag((value >> bit) & 1 != 0);
+        }
+    }
+
+    /// Writes a flag telling whether `value` is nonzero, followed by its magnitude in `n` bits and
+    /// its sign if it is.
+    pub(crate) fn write_optional_signed_value(&mut self, n: u8, value: i32) {
+        self.write_flag(value != 0);
+        if value != 0 {
+            debug_assert!(value.unsigned_abs() < 1 << n);
+            self.write_literal(n, value.unsigned_abs() as u8);
+            self.write_flag(value < 0);
+        }
+    }
+
+    pub(crate) fn write_with_tree(&mut self, tree: &[TreeNode], value: i8) {
+        self.write_with_tree_with_first_node(tree, tree[0], value);
+    }
+
+    /// Writes `value` with a tree, starting at `first_node` instead of the root.
+    pub(crate) fn write_with_tree_with_first_node(
+        &mut self,
+        tree: &[TreeNode],
+        first_node: TreeNode,
+        value: i8,
+    ) {
+        let (mut branches, len) =
+            tree_branches(tree, first_node, value).expect("value is not in the tree");
+
+        let mut node = first_node;
+        for _ in 0..len {
+            let b = branches & 1 != 0;
+            branches >>= 1;
+            self.write_bool(b, node.prob);
+            let t = if b { node.right } else { node.left };
+            if let Some(&next_node) = tree.get(usize::from(t)) {
+                node = next_node;
+            }
+        }
+    }
+
+    /// Finishes the bitstream and returns the bytes written.
+    pub(crate) fn flush(mut self) -> Vec<u8> {
+        if self.bottom & (1 << (32 - self.bit_count)) != 0 {
+            self.add_one_to_output();
+        }
+        let value = self.bottom << self.bit_count;
+        self.output.extend_from_slice(&value.to_be_bytes());
+        self.output
+    }
+}
+
+/// Estimated cost of writing a bool with a probability, in 1/256 bits.
+pub(crate) fn bool_cost(value: bool, probability: u8) -> u32 {
+    let probability = if value {
+        256 - u32::from(probability)
+    } else {
+        u32::from(probability)
+    };
+    (-(f64::from(probability.max(1)) / 256.0).log2() * 256.0).round() as u32
+}
+
+/// Estimated cost of writing `value` with a tree starting at `first_node`, in 1/256 bits.
+pub(crate) fn tree_cost(tree: &[TreeNode], first_node: TreeNode, value: i8) -> u32 {
+    let (mut branches, len) =
+        tree_branches(tree, first_node, value).expect("value is not in the tree");
+
+    let mut cost = 0;
+    let mut node = first_node;
+    for _ in 0..len {
+        let b = branches & 1 != 0;
+        branches >>= 1;
+        cost += bool_cost(b, node.prob);
+        let t = if b { node.right } else { node.left };
+        if let Some(&next_node) = tree.get(usize::from(t)) {
+            node = next_node;
+        }
+    }
+    cost
+}
+
+/// Finds the branches leading from `node` to the leaf with `value`. Returns them as bits, the
+/// first branch in the lowest bit, along with how many there are.
+fn tree_branches(tree: &[TreeNode], node: TreeNode, value: i8) -> Option<(u32, u32)> {
+    for (b, t) in [(0, node.left), (1, node.right)] {
+        match tree.get(usize::from(t)) {
+            Some(&next_node) => {
+                if let Some((branches, len)) = tree_branches(tree, next_node, value) {
+                    return Some((branches << 1 | b, len + 1));
+                }
+            }
+            None => {
+                if TreeNode::value_from_branch(t) == value {
+                    return Some((b, 1));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::vp8::{
+        COEFF_PROB_NODES, KEYFRAME_BPRED_MODE_NODES, KEYFRAME_UV_MODE_NODES, KEYFRAME_YMODE_NODES,
+    };
+    use crate::vp8_arithmetic_decoder::ArithmeticDecoder;
+
+    enum Symbol {
+        Bool(bool, u8),
+        Literal(u8, u8),
+        Signed(u8, i32),
+        Tree(Vec<TreeNode>, usize, i8),
+    }
+
+    /// Values of the leaves reachable from `node`.
+    fn leaves(tree: &[TreeNode], node: TreeNode) -> Vec<i8> {
+        [node.left, node.right]
+            .into_iter()
+            .flat_map(|t| match tree.get(usize::from(t)) {
+                Some(&next_node) => leaves(tree, next_node),
+                None => vec![TreeNode::value_from_branch(t)],
+            })
+            .collect()
+    }
+
+    fn random_symbol(rng: &mut impl Rng) -> Symbol {
+        match rng.gen_range(0..5) {
+            0 => {
+                // Mostly likely values, to exercise long runs without output and carries.
+                let prob = rng.gen();
+                let value = rng.gen_ratio(1, 10) ^ (prob < 128);
+                Symbol::Bool(value, prob)
+            }
+            1 => {
+                let n = rng.gen_range(0..=8);
+                Symbol::Literal(n, (rng.gen::<u16>() & ((1 << n) - 1)) as u8)
+            }
+            2 => {
+                let n = rng.gen_range(1..=7);
+                let magnitude = rng.gen_range(0..1 << n);
+                Symbol::Signed(n, if rng.gen() { -magnitude } else { magnitude })
+            }
+            _ => {
+                let (mut tree, first) = match rng.gen_range(0..4) {
+                    0 => (KEYFRAME_YMODE_NODES.to_vec(), 0),
+                    1 => (KEYFRAME_UV_MODE_NODES.to_vec(), 0),
+                    2 => (
+                        KEYFRAME_BPRED_MODE_NODES[rng.gen_range(0..10)][rng.gen_range(0..10)]
+                            .to_vec(),
+                        0,
+                    ),
+                    _ => (
+                        COEFF_PROB_NODES[rng.gen_range(0..4)][rng.gen_range(0..8)]
+                            [rng.gen_range(0..3)]
+                        .to_vec(),
+                        rng.gen_range(0..2),
+                    ),
+                };
+                if rng.gen() {
+                    for node in &mut tree {
+                        node.prob = rng.gen_range(1..=255);
+                    }
+                }
+                let values = leaves(&tree, tree[first]);
+                let value = values[rng.gen_range(0..values.len())];
+                Symbol::Tree(tree, first, value)
+            }
+        }
+    }
+
+    fn roundtrip(symbols: &[Symbol]) {
+        let mut encoder = ArithmeticEncoder::new();
+        for symbol in symbols {
+            match *symbol {
+                Symbol::Bool(value, prob) => encoder.write_bool(value, prob),
+                Symbol::Literal(n, value) => encoder.write_literal(n, value),
+                Symbol::Signed(n, value) => encoder.write_optional_signed_value(n, value),
+                Symbol::Tree(ref tree, first, value) => {
+                    encoder.write_with_tree_with_first_node(tree, tree[first], value)
+                }
+            }
+        }
+        let data = encoder.flush();
+
+        let mut buf = vec![[0u8; 4]; data.len().div_ceil(4)];
+        buf.as_mut_slice().as_flattened_mut()[..data.len()].copy_from_slice(&data);
+        let mut decoder = ArithmeticDecoder::new();
+        decoder.init(buf, data.len()).unwrap();
+        let mut res = decoder.start_accumulated_result();
+        for symbol in symbols {
+            match *symbol {
+                Symbol::Bool(value, prob) => {
+                    assert_eq!(decoder.read_bool(prob).or_accumulate(&mut res), value)
+                }
+                Symbol::Literal(n, value) => {
+                    assert_eq!(decoder.read_literal(n).or_accumulate(&mut res), value)
+                }
+                Symbol::Signed(n, value) => assert_eq!(
+                    decoder
+                        .read_optional_signed_value(n)
+                        .or_accumulate(&mut res),
+                    value
+                ),
+                Symbol::Tree(ref tree, first, value) => assert_eq!(
+                    decoder
+                        .read_with_tree_with_first_node(tree, tree[first])
+                        .or_accumulate(&mut res),
+                    value
+                ),
+            }
+        }
+        decoder.check(res, ()).unwrap();
+    }
+
+    #[test]
+    fn roundtrip_random_symbols() {
+        let mut rng = rand::thread_rng();
+        roundtrip(&[]);
+        for _ in 0..500 {
+            let len = rng.gen_range(1..2000);
+            let symbols: Vec<_> = (0..len).map(|_| random_symbol(&mut rng)).collect();
+            roundtrip(&symbols);
+        }
+    }
+
+    #[test]
+    fn roundtrip_carries() {
+        // Unlikely values in a row push the bottom of the range upwards, forcing carries through
+        // runs of 0xff bytes.
+        for prob in [1, 2, 128, 254, 255] {
+            let symbols: Vec<_> = (0..5000).map(|i| Symbol::Bool(i % 97 != 0, prob)).collect();
+            roundtrip(&symbols);
+        }
+    }
+}
@@ -0,0 +1,1963 @@
+//! Lossy encoding of VP8 keyframes.
+//!
+//! Macroblocks are predicted, transformed and quantized in the order the decoder in
+//! [`crate::vp8`] reads them. Each macroblock is reconstructed the same way the decoder does it,
+//! so that later macroblocks are predicted from exactly the pixels the decoder will see.
+//!
+//! Prediction modes are chosen by trying each of them and comparing the distortion of the
+//! reconstruction with the estimated number of bits its coefficients and modes take. Once the
+//! frame is reconstructed, the loop filter settings are chosen by running the filters of the
+//! decoder over it and keeping the ones that bring it closest to the source.
+
+use std::cmp;
+use std::io::{self, Write};
+
+use crate::encoder::{EncoderParams, EncodingError};
+use crate::transform;
+use crate::vp8::{
+    add_residue, create_border_chroma, create_border_luma, predict_dcpred, predict_hpred,
+    predict_subblock, predict_tmpred, predict_vpred, ChromaMode, Frame, IntraMode, LumaMode,
+    TokenProbTreeNodes, TreeNode, AC_QUANT, COEFF_BANDS, COEFF_PROB_NODES, COEFF_UPDATE_PROBS,
+    DCT_0, DCT_CAT1, DCT_CAT2, DCT_CAT3, DCT_CAT4, DCT_CAT5, DCT_CAT6, DCT_CAT_BASE, DCT_EOB,
+    DC_QUANT, KEYFRAME_BPRED_MODE_NODES, KEYFRAME_UV_MODE_NODES, KEYFRAME_YMODE_NODES,
+    PROB_DCT_CAT, SEGMENT_TREE_NODE_DEFAULTS, ZIGZAG,
+};
+use crate::vp8_arithmetic_encoder::{bool_cost, tree_cost, ArithmeticEncoder};
+
+/// Largest magnitude of a quantized coefficient.
+const MAX_LEVEL: i32 = 2047;
+
+/// Largest size of the first partition, which holds the frame header and the macroblock headers.
+const MAX_FIRST_PARTITION_SIZE: usize = (1 << 19) - 1;
+
+const LUMA_MODES: [LumaMode; 4] = [LumaMode::DC, LumaMode::V, LumaMode::H, LumaMode::TM];
+const CHROMA_MODES: [ChromaMode; 4] =
+    [ChromaMode::DC, ChromaMode::V, ChromaMode::H, ChromaMode::TM];
+const INTRA_MODES: [IntraMode; 10] = [
+    IntraMode::DC,
+    IntraMode::TM,
+    IntraMode::VE,
+    IntraMode::HE,
+    IntraMode::LD,
+    IntraMode::RD,
+    IntraMode::VR,
+    IntraMode::VL,
+    IntraMode::HD,
+    IntraMode::HU,
+];
+
+/// Largest number of segments in a frame.
+const MAX_SEGMENTS: usize = 4;
+
+/// How much the quantizers of segments are spread around the quantizer of the frame, moving bits
+/// from busy areas, where artifacts are hard to see, to flat areas, where they stand out. The
+/// same as libwebp with its default spatial noise shaping strength of 50.
+const SEGMENT_SPREAD: f64 = 0.9 * 50.0 / 100.0 / 128.0;
+
+/// Largest loop filter level.
+const MAX_FILTER_LEVEL: u8 = 63;
+
+/// Largest loop filter sharpness.
+const MAX_SHARPNESS: u8 = 7;
+
+/// Distance between the loop filter levels tried at first, before trying the ones next to the
+/// best of them.
+const FILTER_LEVEL_STEP: u8 = 4;
+
+/// Quantizer index, from 0 to 127, for a compression from 0 to 1.
+fn compressed_quantizer_index(compression: f64) -> u8 {
+    (127.0 * (1.0 - compression.clamp(0.0, 1.0))) as u8
+}
+
+/// How much of the image is kept at a quality from 0 to 100, from 0 (least) to 1 (most).
+///
+/// Follows the curve libwebp uses, so that qualities give similar results in both.
+fn compression(quality: u8) -> f64 {
+    let quality = f64::from(quality.min(100)) / 100.0;
+    let linear = if quality < 0.75 {
+        quality * (2.0 / 3.0)
+    } else {
+        2.0 * quality - 1.0
+    };
+    linear.cbrt()
+}
+
+#[derive(Clone, Copy)]
+struct Quantizer {
+    step: i32,
+    /// Rounding offset in 1/256 of a step. Below half a step, small values round to zero more
+    /// often, which saves more bits than it costs in quality.
+    bias: i32,
+}
+
+impl Quantizer {
+    fn quantize(self, value: i32) -> i32 {
+        let level = ((value.abs() << 8) + self.bias * self.step) / (self.step << 8);
+        level.min(MAX_LEVEL) * value.signum()
+    }
+}
+
+/// Quantizers for the DC and AC coefficients of each kind of block, along with the weights of
+/// the bit costs against the distortion when choosing how to encode a macroblock.
+struct Quantizers {
+    y1: [Quantizer; 2],
+    y2: [Quantizer; 2],
+    uv: [Quantizer; 2],
+    lambda_i16: u64,
+    lambda_i4: u64,
+    lambda_uv: u64,
+    /// Weight for the choice between 16x16 prediction and subblock prediction.
+    lambda_mode: u64,
+    /// Weights for the bits of the levels in trellis quantization, against the squared errors of
+    /// the coefficients.
+    lambda_trellis_i16: u64,
+    lambda_trellis_i4: u64,
+    lambda_trellis_uv: u64,
+}
+
+impl Quantizers {
+    /// The quantizers for a quantizer index, with the step sizes that
+    /// `Vp8Decoder::read_quantization_indices` derives from it.
+    fn new(index: u8) -> Self {
+        let dc = i32::from(DC_QUANT[usize::from(index)]);
+        let ac = i32::from(AC_QUANT[usize::from(index)]);
+        let quantizer = |step, bias| Quantizer { step, bias };
+        let y1 = [quantizer(dc, 96), quantizer(ac, 110)];
+        let y2 = [
+            quantizer(dc * 2, 96),
+            quantizer((ac * 155 / 100).max(8), 108),
+        ];
+        let uv = [quantizer(dc.min(132), 110), quantizer(ac, 115)];
+
+        // The same weights as libwebp, from the average step of each kind of block. Unlike libwebp,
+        // trellis quantization counts the errors of all frequencies the same, which is what PSNR
+        // measures, so its weights are lower.
+        let average = |q: [Quantizer; 2]| u64::from((q[0].step + 15 * q[1].step + 8) as u32 >> 4);
+        let (q_i4, q_i16, q_uv) = (average(y1), average(y2), average(uv));
+        Self {
+            y1,
+            y2,
+            uv,
+            lambda_i16: (3 * q_i16 * q_i16).max(1),
+            lambda_i4: ((3 * q_i4 * q_i4) >> 7).max(1),
+            lambda_uv: ((3 * q_uv * q_uv) >> 6).max(1),
+            lambda_mode: ((q_i4 * q_i4) >> 7).max(1),
+            lambda_trellis_i16: ((q_i16 * q_i16) >> 6).max(1),
+            lambda_trellis_i4: ((5 * q_i4 * q_i4) >> 7).max(1),
+            lambda_trellis_uv: ((q_uv * q_uv) >> 7).max(1),
+        }
+    }
+}
+
+/// Quantizes the coefficients of a block from `first` on, and returns the levels in zigzag order.
+/// The coefficients are replaced by the values the decoder dequantizes the levels to.
+fn quantize_block(coeffs: &mut [i32; 16], first: usize, quantizers: &[Quantizer; 2]) -> [i32; 16] {
+    let mut levels = [0; 16];
+    for (i, level) in levels.iter_mut().enumerate().skip(first) {
+        let zigzag = usize::from(ZIGZAG[i]);
+        let quantizer = quantizers[usize::from(zigzag > 0)];
+        *level = quantizer.quantize(coeffs[zigzag]);
+        coeffs[zigzag] = *level * quantizer.step;
+    }
+    levels
+}
+
+/// Settings shared by the macroblocks of a segment.
+struct Segment {
+    quantizer_index: u8,
+    quantizers: Quantizers,
+    /// The loop filter level, which is chosen once the frame is reconstructed.
+    filter_level: u8,
+}
+
+impl Segment {
+    fn new(quantizer_index: u8) -> Self {
+        Self {
+            quantizer_index,
+            quantizers: Quantizers::new(quantizer_index),
+            filter_level: 0,
+        }
+    }
+}
+
+/// Rate-distortion score of a choice: its distortion as a sum of squared errors, plus its cost in
+/// 1/256 bits weighted by `lambda`. Lower is better.
+fn rd_score(distortion: u32, rate: u32, lambda: u64) -> u64 {
+    256 * u64::from(distortion) + lambda * u64::from(rate)
+}
+
+/// Quantizes a block like `quantize_block`, but chooses the levels with the best rate-distortion
+/// score, weighing the bits they take with `lambda`.
+///
+/// Each level is either rounded down or up, and the block may end early. The levels are found
+/// with a trellis over the coefficients in zigzag order, since the cost of a token depends on the
+/// level before it, and are coded with the `costs` of the tokens of `plane`.
+fn trellis_quantize_block(
+    coeffs: &mut [i32; 16],
+    first: usize,
+    quantizers: &[Quantizer; 2],
+    costs: &Costs,
+    plane: usize,
+    complexity: usize,
+    lambda: u64,
+) -> [i32; 16] {
+    /// A choice of level for a coefficient, with the best score for the coefficients up to it.
+    #[derive(Clone, Copy)]
+    struct Node {
+        score: u64,
+        level: i32,
+        /// The choice for the previous coefficient.
+        previous: usize,
+    }
+
+    let token_costs = &costs.tokens[plane];
+    let eob = DCT_EOB as usize;
+    let complexity_after = |level: i32| level.min(2) as usize;
+    let band = |n: usize| usize::from(COEFF_BANDS[n]);
+
+    // The distortion of zeroing the coefficients from each position on.
+    let mut zeroed = [0u64; 17];
+    for n in (first..16).rev() {
+        let j = usize::from(ZIGZAG[n]);
+        let error = u64::from(coeffs[j].unsigned_abs());
+        zeroed[n] = zeroed[n + 1] + error * error;
+    }
+
+    let mut best_score =
+        256 * zeroed[first] + lambda * u64::from(token_costs[band(first)][complexity][0][eob]);
+    let mut best_last = None;
+    let unreachable = Node {
+        score: u64::MAX,
+        level: 0,
+        previous: 0,
+    };
+    let mut nodes = [[unreachable; 2]; 16];
+    for n in first..16 {
+        let j = usize::from(ZIGZAG[n]);
+        let step = quantizers[usize::from(j > 0)].step;
+        let abs = coeffs[j].abs();
+        let rounded_down = (abs / step).min(MAX_LEVEL);
+
+        for (choice, level) in [rounded_down, rounded_down + 1].into_iter().enumerate() {
+            if choice == 1 && (abs == 0 || level > MAX_LEVEL) {
+                continue;
+            }
+            let (token, _) = coefficient_token(level);
+            let rate = |complexity: usize, skip: usize| {
+                let cost = token_costs[band(n)][complexity][skip][token as usize]
+                    + costs.levels[level as usize];
+                lambda * u64::from(cost)
+            };
+
+            let (score, previous) = if n == first {
+                (rate(complexity, 0), 0)
+            } else {
+                nodes[n - 1]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, node)| node.score != u64::MAX)
+                    .map(|(i, node)| {
+                        let skip = usize::from(node.level == 0);
+                        (node.score + rate(complexity_after(node.level), skip), i)
+                    })
+                    .min()
+                    .unwrap()
+            };
+            let error = u64::from((abs - level * step).unsigned_abs());
+            let score = score + 256 * error * error;
+            nodes[n][choice] = Node {
+                score,
+                level,
+                previous,
+            };
+
+            if level != 0 {
+                let mut end_score = score + 256 * zeroed[n + 1];
+                if n < 15 {
+                    let cost = token_costs[band(n + 1)][complexity_after(level)][0][eob];
+                    end_score += lambda * u64::from(cost);
+                }
+                if end_score < best_score {
+                    best_score = end_score;
+                    best_last = Some((n, choice));
+                }
+            }
+        }
+    }
+
+    let mut levels = [0; 16];
+    if let Some((last, mut choice)) = best_last {
+        for n in (first..=last).rev() {
+            let node = nodes[n][choice];
+            let j = usize::from(ZIGZAG[n]);
+            levels[n] = node.level * coeffs[j].signum();
+            choice = node.previous;
+        }
+    }
+    for (n, &level) in levels.iter().enumerate().skip(first) {
+        let j = usize::from(ZIGZAG[n]);
+        coeffs[j] = level * quantizers[usize::from(j > 0)].step;
+    }
+    levels
+}
+
+fn rgb_to_y(r: i32, g: i32, b: i32) -> u8 {
+    ((16839 * r + 33059 * g + 6420 * b + (1 << 15) + (16 << 16)) >> 16) as u8
+}
+
+/// Converts the sums of four pixels to chroma.
+fn rgb_to_uv(r: i32, g: i32, b: i32) -> (u8, u8) {
+    let clip = |v: i32| ((v + (1 << 17) + (128 << 18)) >> 18).clamp(0, 255) as u8;
+    (
+        clip(-9719 * r - 19081 * g + 28800 * b),
+        clip(28800 * r - 24116 * g - 4684 * b),
+    )
+}
+
+/// An image converted to YUV 4:2:0, padded to whole macroblocks by repeating the last row and
+/// column.
+struct SourcePlanes {
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+    luma_stride: usize,
+    chroma_stride: usize,
+}
+
+impl SourcePlanes {
+    fn from_rgba(pixels: &[u8], width: usize, height: usize) -> Self {
+        let luma_stride = width.div_ceil(16) * 16;
+        let luma_rows = height.div_ceil(16) * 16;
+        let chroma_stride = luma_stride / 2;
+        let chroma_rows = luma_rows / 2;
+        let pixel = |x: usize, y: usize| {
+            let i = (cmp::min(y, height - 1) * width + cmp::min(x, width - 1)) * 4;
+            [
+                i32::from(pixels[i]),
+                i32::from(pixels[i + 1]),
+                i32::from(pixels[i + 2]),
+            ]
+        };
+
+        let mut y = Vec::with_capacity(luma_stride * luma_rows);
+        for py in 0..luma_rows {
+            for px in 0..luma_stride {
+                let [r, g, b] = pixel(px, py);
+                y.push(rgb_to_y(r, g, b));
+            }
+        }
+
+        let mut u = Vec::with_capacity(chroma_stride * chroma_rows);
+        let mut v = Vec::with_capacity(chroma_stride * chroma_rows);
+        for py in 0..chroma_rows {
+            for px in 0..chroma_stride {
+                let mut sum = [0; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let rgb = pixel(px * 2 + dx, py * 2 + dy);
+                    for (sum, value) in sum.iter_mut().zip(rgb) {
+                        *sum += value;
+                    }
+                }
+                let (pu, pv) = rgb_to_uv(sum[0], sum[1], sum[2]);
+                u.push(pu);
+                v.push(pv);
+            }
+        }
+
+        Self {
+            y,
+            u,
+            v,
+            luma_stride,
+            chroma_stride,
+        }
+    }
+}
+
+/// How easy a macroblock of the source is to compress, from 0 for busy areas to 255 for flat ones.
+///
+/// Follows the analysis of libwebp: the farther the largest transformed coefficients are from the
+/// most common ones, the busier the macroblock.
+fn macroblock_alpha(source: &SourcePlanes, mbx: usize, mby: usize) -> u8 {
+    let add_to_histogram = |histogram: &mut [u32; 32], plane: &[u8], stride: usize, size: usize| {
+        let pixels = &plane[mby * size * stride + mbx * size..];
+        let sum: u32 = (0..size)
+            .flat_map(|y| &pixels[y * stride..][..size])
+            .map(|&p| u32::from(p))
+            .sum();
+        let mean = (sum / (size * size) as u32) as i32;
+        for y0 in (0..size).step_by(4) {
+            for x0 in (0..size).step_by(4) {
+                let mut block = [0; 16];
+                for (i, residual) in block.iter_mut().enumerate() {
+                    let pixel = pixels[(y0 + i / 4) * stride + x0 + i % 4];
+                    *residual = i32::from(pixel) - mean;
+                }
+                transform::fdct4x4(&mut block);
+                for coeff in block {
+                    histogram[(coeff.unsigned_abs() as usize >> 3).min(31)] += 1;
+                }
+            }
+        }
+    };
+    let alpha = |histogram: &[u32; 32]| {
+        let max_count = *histogram.iter().max().unwrap();
+        let last = histogram.iter().rposition(|&count| count > 0).unwrap() as u32;
+        if max_count > 1 {
+            2 * 255 * last / max_count
+        } else {
+            0
+        }
+    };
+
+    let mut luma = [0; 32];
+    add_to_histogram(&mut luma, &source.y, source.luma_stride, 16);
+    let mut chroma = [0; 32];
+    add_to_histogram(&mut chroma, &source.u, source.chroma_stride, 8);
+    add_to_histogram(&mut chroma, &source.v, source.chroma_stride, 8);
+
+    let busyness = (3 * alpha(&luma) + alpha(&chroma) + 2) >> 2;
+    255 - busyness.min(255) as u8
+}
+
+/// Groups values into at most `count` clusters with k-means. Returns the cluster of each value
+/// and the centers of the clusters.
+fn cluster(values: &[u8], count: usize) -> (Vec<u8>, Vec<u32>) {
+    let mut histogram = [0u32; 256];
+    for &value in values {
+        histogram[usize::from(value)] += 1;
+    }
+    let min = u32::from(*values.iter().min().unwrap());
+    let max = u32::from(*values.iter().max().unwrap());
+    let count = count.min((max - min + 1) as usize) as u32;
+
+    let mut centers: Vec<u32> = (0..count)
+        .map(|k| min + (2 * k + 1) * (max - min) / (2 * count))
+        .collect();
+    let mut clusters = [0u8; 256];
+    for _ in 0..6 {
+        let mut sums = vec![0; centers.len()];
+        let mut counts = vec![0; centers.len()];
+        for value in min..=max {
+            let nearest = (0..centers.len())
+                .min_by_key(|&n| centers[n].abs_diff(value))
+                .unwrap();
+            clusters[value as usize] = nearest as u8;
+            sums[nearest] += value * histogram[value as usize];
+            counts[nearest] += histogram[value as usize];
+        }
+
+        let mut displacement = 0;
+        for ((center, sum), count) in centers.iter_mut().zip(sums).zip(counts) {
+            if let Some(mean) = (sum + count / 2).checked_div(count) {
+                displacement += center.abs_diff(mean);
+                *center = mean;
+            }
+        }
+        if displacement < 5 {
+            break;
+        }
+    }
+
+    let assignment = values
+        .iter()
+        .map(|&value| clusters[usize::from(value)])
+        .collect();
+    (assignment, centers)
+}
+
+/// Sum of squared differences between a predicted or reconstructed block in a workspace, which
+/// starts at (1, 1), and the source pixels.
+fn block_sse(ws: &[u8], ws_stride: usize, source: &[u8], stride: usize, size: usize) -> u32 {
+    let mut sse = 0;
+    for y in 0..size {
+        for (&a, &b) in ws[(y + 1) * ws_stride + 1..][..size]
+            .iter()
+            .zip(&source[y * stride..][..size])
+        {
+            let d = i32::from(a) - i32::from(b);
+            sse += (d * d) as u32;
+        }
+    }
+    sse
+}
+
+/// Subtracts the prediction of a 4x4 block in a workspace from the source pixels, and transforms
+/// the result.
+fn transform_residuals(
+    ws: &[u8],
+    ws_stride: usize,
+    y0: usize,
+    x0: usize,
+    source: &[u8],
+    stride: usize,
+) -> [i32; 16] {
+    let mut block = [0; 16];
+    for (y, row) in block.chunks_exact_mut(4).enumerate() {
+        for (x, residual) in row.iter_mut().enumerate() {
+            *residual = i32::from(source[(y0 - 1 + y) * stride + x0 - 1 + x])
+                - i32::from(ws[(y0 + y) * ws_stride + x0 + x]);
+        }
+    }
+    transform::fdct4x4(&mut block);
+    block
+}
+
+fn predict_luma(ws: &mut [u8], mode: LumaMode, mbx: usize, mby: usize) {
+    let stride = 1usize + 16 + 4;
+    match mode {
+        LumaMode::V => predict_vpred(ws, 16, 1, 1, stride),
+        LumaMode::H => predict_hpred(ws, 16, 1, 1, stride),
+        LumaMode::TM => predict_tmpred(ws, 16, 1, 1, stride),
+        LumaMode::DC => predict_dcpred(ws, 16, stride, mby != 0, mbx != 0),
+        LumaMode::B => unreachable!(),
+    }
+}
+
+fn predict_chroma(ws: &mut [u8], mode: ChromaMode, mbx: usize, mby: usize) {
+    let stride = 1usize + 8;
+    match mode {
+        ChromaMode::DC => predict_dcpred(ws, 8, stride, mby != 0, mbx != 0),
+        ChromaMode::V => predict_vpred(ws, 8, 1, 1, stride),
+        ChromaMode::H => predict_hpred(ws, 8, 1, 1, stride),
+        ChromaMode::TM => predict_tmpred(ws, 8, 1, 1, stride),
+    }
+}
+
+/// The token for a quantized coefficient, along with the extra bits that follow it.
+fn coefficient_token(abs_value: i32) -> (i8, i32) {
+    match abs_value {
+        0..=4 => (abs_value as i8, 0),
+        5..=6 => (DCT_CAT1, abs_value - i32::from(DCT_CAT_BASE[0])),
+        7..=10 => (DCT_CAT2, abs_value - i32::from(DCT_CAT_BASE[1])),
+        11..=18 => (DCT_CAT3, abs_value - i32::from(DCT_CAT_BASE[2])),
+        19..=34 => (DCT_CAT4, abs_value - i32::from(DCT_CAT_BASE[3])),
+        35..=66 => (DCT_CAT5, abs_value - i32::from(DCT_CAT_BASE[4])),
+        _ => (DCT_CAT6, abs_value - i32::from(DCT_CAT_BASE[5])),
+    }
+}
+
+/// Writes the quantized coefficients of a block from `first` on, the counterpart of
+/// `Vp8Decoder::read_coefficients`. Returns whether any coefficient is nonzero.
+fn write_coefficients(
+    encoder: &mut ArithmeticEncoder,
+    probs: &[[[TreeNode; 11]; 3]; 8],
+    levels: &[i32; 16],
+    first: usize,
+    complexity: usize,
+) -> bool {
+    let Some(last) = (first..16).rev().find(|&i| levels[i] != 0) else {
+        let tree = &probs[usize::from(COEFF_BANDS[first])][complexity];
+        encoder.write_with_tree(tree, DCT_EOB);
+        return false;
+    };
+
+    let mut complexity = complexity;
+    let mut skip = false;
+    for (i, &level) in levels.iter().enumerate().take(last + 1).skip(first) {
+        let tree = &probs[usize::from(COEFF_BANDS[i])][complexity];
+        let (token, extra) = coefficient_token(level.abs());
+        encoder.write_with_tree_with_first_node(tree, tree[usize::from(skip)], token);
+
+        if token == DCT_0 {
+            skip = true;
+            complexity = 0;
+            continue;
+        }
+
+        if token >= DCT_CAT1 {
+            let probs = PROB_DCT_CAT[(token - DCT_CAT1) as usize];
+            let bits = probs.iter().take_while(|&&p| p != 0).count();
+            for (j, &prob) in probs[..bits].iter().enumerate() {
+                encoder.write_bool((extra >> (bits - 1 - j)) & 1 != 0, prob);
+            }
+        }
+        encoder.write_flag(level < 0);
+
+        skip = false;
+        complexity = if level.abs() == 1 { 1 } else { 2 };
+    }
+
+    if last < 15 {
+        let tree = &probs[usize::from(COEFF_BANDS[last + 1])][complexity];
+        encoder.write_with_tree(tree, DCT_EOB);
+    }
+    true
+}
+
+/// Estimated costs, in 1/256 bits, of the symbols written for a frame.
+struct Costs {
+    /// The cost of each token for each plane, band and complexity, either from the root of the
+    /// token tree, or after a zero where no end of block can follow.
+    tokens: [[[[[u32; 12]; 2]; 3]; 8]; 4],
+    /// The cost of the extra bits and the sign that follow the token of each level.
+    levels: Vec<u32>,
+    luma_modes: [u32; 5],
+    /// The cost of each subblock mode, for each mode above and to the left.
+    subblock_modes: [[[u32; 10]; 10]; 10],
+    chroma_modes: [u32; 4],
+}
+
+impl Costs {
+    fn new(probs: &TokenProbTreeNodes) -> Self {
+        let mut tokens = [[[[[0; 12]; 2]; 3]; 8]; 4];
+        for (costs, probs) in tokens.iter_mut().zip(probs) {
+            for (costs, probs) in costs.iter_mut().zip(probs) {
+                for (costs, tree) in costs.iter_mut().zip(probs) {
+                    for (skip, costs) in costs.iter_mut().enumerate() {
+                        for (token, cost) in costs.iter_mut().enumerate() {
+                            if skip == 0 || token as i8 != DCT_EOB {
+                                *cost = tree_cost(tree, tree[skip], token as i8);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let levels = (0..=MAX_LEVEL)
+            .map(|level| {
+                let (token, extra) = coefficient_token(level);
+                let sign = bool_cost(false, 128);
+                if token < DCT_CAT1 {
+                    return if level == 0 { 0 } else { sign };
+                }
+                let probs = PROB_DCT_CAT[(token - DCT_CAT1) as usize];
+                let bits = probs.iter().take_while(|&&p| p != 0).count();
+                let extra_cost: u32 = (0..bits)
+                    .map(|j| bool_cost((extra >> (bits - 1 - j)) & 1 != 0, probs[j]))
+                    .sum();
+                extra_cost + sign
+            })
+            .collect();
+
+        let mut subblock_modes = [[[0; 10]; 10]; 10];
+        for (costs, trees) in subblock_modes.iter_mut().zip(&KEYFRAME_BPRED_MODE_NODES) {
+            for (costs, tree) in costs.iter_mut().zip(trees) {
+                for (cost, mode) in costs.iter_mut().zip(INTRA_MODES) {
+                    *cost = tree_cost(tree, tree[0], mode as i8);
+                }
+            }
+        }
+
+        let tree = &KEYFRAME_YMODE_NODES;
+        let luma_mode_cost = |mode: LumaMode| tree_cost(tree, tree[0], mode as i8);
+        let tree = &KEYFRAME_UV_MODE_NODES;
+        let chroma_mode_cost = |mode: ChromaMode| tree_cost(tree, tree[0], mode as i8);
+        Self {
+            tokens,
+            levels,
+            luma_modes: [
+                luma_mode_cost(LumaMode::DC),
+                luma_mode_cost(LumaMode::V),
+                luma_mode_cost(LumaMode::H),
+                luma_mode_cost(LumaMode::TM),
+                luma_mode_cost(LumaMode::B),
+            ],
+            subblock_modes,
+            chroma_modes: CHROMA_MODES.map(chroma_mode_cost),
+        }
+    }
+
+    /// Estimated cost of the levels of a block from `first` on, as `write_coefficients` writes
+    /// them.
+    fn block(&self, plane: usize, levels: &[i32; 16], first: usize, complexity: usize) -> u32 {
+        let costs = &self.tokens[plane];
+        let eob = DCT_EOB as usize;
+        let Some(last) = (first..16).rev().find(|&i| levels[i] != 0) else {
+            return costs[usize::from(COEFF_BANDS[first])][complexity][0][eob];
+        };
+
+        let mut cost = 0;
+        let mut complexity = complexity;
+        let mut skip = 0;
+        for (i, &level) in levels.iter().enumerate().take(last + 1).skip(first) {
+            let abs = level.unsigned_abs() as usize;
+            let (token, _) = coefficient_token(abs as i32);
+            cost += costs[usize::from(COEFF_BANDS[i])][complexity][skip][token as usize]
+                + self.levels[abs];
+            (skip, complexity) = match abs {
+                0 => (1, 0),
+                1 => (0, 1),
+                _ => (0, 2),
+            };
+        }
+
+        if last < 15 {
+            cost += costs[usize::from(COEFF_BANDS[last + 1])][complexity][0][eob];
+        }
+        cost
+    }
+}
+
+/// The prediction modes of a macroblock, which are written to the first partition.
+#[derive(Clone, Copy)]
+struct MacroBlockModes {
+    luma_mode: LumaMode,
+    /// The subblock modes, or the mode implied by the luma mode for each subblock.
+    bpred: [IntraMode; 16],
+    chroma_mode: ChromaMode,
+    coeffs_skipped: bool,
+}
+
+/// A way to encode the luma plane of a macroblock, along with its reconstruction and its
+/// rate-distortion trade-off.
+struct LumaCandidate {
+    mode: LumaMode,
+    bpred: [IntraMode; 16],
+    y2: Option<[i32; 16]>,
+    y: [[i32; 16]; 16],
+    ws: [u8; 357],
+    distortion: u32,
+    rate: u32,
+}
+
+/// The quantized coefficients of a macroblock, in zigzag order.
+struct MacroBlockLevels {
+    y2: Option<[i32; 16]>,
+    y: [[i32; 16]; 16],
+    u: [[i32; 16]; 4],
+    v: [[i32; 16]; 4],
+}
+
+impl MacroBlockLevels {
+    fn is_zero(&self) -> bool {
+        let zero = |levels: &[i32; 16]| levels.iter().all(|&l| l == 0);
+        self.y2.iter().all(zero)
+            && self.y.iter().all(zero)
+            && self.u.iter().all(zero)
+            && self.v.iter().all(zero)
+    }
+}
+
+struct Vp8Encoder<'a> {
+    source: &'a SourcePlanes,
+    mbwidth: usize,
+    mbheight: usize,
+    quantizer_index: u8,
+    /// The segments, or a single one with the quantizer of the frame if segmentation is off.
+    segments: Vec<Segment>,
+    /// The segment of each macroblock.
+    segment_map: Vec<u8>,
+    costs: Costs,
+    /// Whether levels are chosen with trellis quantization instead of rounding.
+    trellis: bool,
+
+    /// The frame as the decoder reconstructs it, before loop filtering.
+    frame: Frame,
+    top_border: Vec<u8>,
+    left_border: Vec<u8>,
+
+    /// Whether the blocks above and to the left had nonzero coefficients, laid out as in
+    /// `MacroBlock::complexity` of the decoder.
+    top_complexity: Vec<[u8; 9]>,
+    left_complexity: [u8; 9],
+
+    macroblocks: Vec<MacroBlockModes>,
+    tokens: ArithmeticEncoder,
+}
+
+impl<'a> Vp8Encoder<'a> {
+    fn new(source: &'a SourcePlanes, width: u16, height: u16, quantizer_index: u8) -> Self {
+        let mut frame = Frame::default();
+        frame.width = width;
+        frame.height = height;
+        frame.keyframe = true;
+        frame.for_display = true;
+        frame.ybuf = vec![0; usize::from(width) * usize::from(height)];
+        frame.ubuf =
+            vec![0; usize::from(frame.chroma_width()) * usize::from(frame.chroma_height())];
+        frame.vbuf =
+            vec![0; usize::from(frame.chroma_width()) * usize::from(frame.chroma_height())];
+
+        let mbwidth = usize::from(width.div_ceil(16));
+        Self {
+            source,
+            mbwidth,
+            mbheight: usize::from(height.div_ceil(16)),
+            quantizer_index,
+            segments: vec![Segment::new(quantizer_index)],
+            segment_map: vec![0; mbwidth * usize::from(height.div_ceil(16))],
+            costs: Costs::new(&COEFF_PROB_NODES),
+            trellis: false,
+            frame,
+            top_border: vec![127; usize::from(width) + 4 + 16],
+            left_border: vec![129; 1 + 16],
+            top_complexity: vec![[0; 9]; mbwidth],
+            left_complexity: [0; 9],
+            macroblocks: Vec::new(),
+            tokens: ArithmeticEncoder::new(),
+        }
+    }
+
+    /// Groups the macroblocks into up to `count` segments by how busy they are, and spreads the
+    /// quantizers of the segments around the one for `compression`.
+    fn assign_segments(&mut self, compression: f64, count: usize) {
+        let alphas: Vec<u8> = (0..self.mbheight)
+            .flat_map(|mby| (0..self.mbwidth).map(move |mbx| (mbx, mby)))
+            .map(|(mbx, mby)| macroblock_alpha(self.source, mbx, mby))
+            .collect();
+        let (segment_map, centers) = cluster(&alphas, count.clamp(1, MAX_SEGMENTS));
+        self.segment_map = segment_map;
+        if centers.len() == 1 {
+            self.segments = vec![Segment::new(self.quantizer_index)];
+            return;
+        }
+
+        let mid = alphas.iter().map(|&a| f64::from(a)).sum::<f64>() / alphas.len() as f64;
+        let min = f64::from(*centers.iter().min().unwrap());
+        let max = f64::from(*centers.iter().max().unwrap());
+        self.segments = centers
+            .iter()
+            .map(|&center| {
+                let alpha =
+                    (255.0 * (f64::from(center) - mid) / (max - min).max(1.0)).clamp(-127.0, 127.0);
+                let exponent = 1.0 - SEGMENT_SPREAD * alpha;
+                Segment::new(compressed_quantizer_index(compression.powf(exponent)))
+            })
+            .collect();
+    }
+
+    /// Quantizes a block of `plane` from `first` on, with trellis quantization if it is enabled.
+    #[allow(clippy::too_many_arguments)]
+    fn quantize_block(
+        &self,
+        coeffs: &mut [i32; 16],
+        first: usize,
+        quantizers: &[Quantizer; 2],
+        plane: usize,
+        complexity: usize,
+        lambda: u64,
+    ) -> [i32; 16] {
+        if self.trellis {
+            trellis_quantize_block(
+                coeffs,
+                first,
+                quantizers,
+                &self.costs,
+                plane,
+                complexity,
+                lambda,
+            )
+        } else {
+            quantize_block(coeffs, first, quantizers)
+        }
+    }
+
+    fn quantizers(&self, mbx: usize, mby: usize) -> &Quantizers {
+        let segment = self.segment_map[mby * self.mbwidth + mbx];
+        &self.segments[usize::from(segment)].quantizers
+    }
+
+    fn encode_macroblocks(&mut self) {
+        for mby in 0..self.mbheight {
+            self.left_complexity = [0; 9];
+            for mbx in 0..self.mbwidth {
+                let luma = self.encode_luma(mbx, mby);
+                let (chroma_mode, u, v) = self.encode_chroma(mbx, mby);
+                let levels = MacroBlockLevels {
+                    y2: luma.y2,
+                    y: luma.y,
+                    u,
+                    v,
+                };
+
+                let coeffs_skipped = levels.is_zero();
+                if coeffs_skipped {
+                    self.skip_coefficients(mbx, luma.mode);
+                } else {
+                    self.write_residual_data(mbx, &levels);
+                }
+
+                self.macroblocks.push(MacroBlockModes {
+                    luma_mode: luma.mode,
+                    bpred: luma.bpred,
+                    chroma_mode,
+                    coeffs_skipped,
+                });
+            }
+            self.left_border = vec![129; 1 + 16];
+        }
+    }
+
+    /// Chooses the luma prediction of a macroblock with the best rate-distortion score, and
+    /// quantizes and reconstructs it.
+    fn encode_luma(&mut self, mbx: usize, mby: usize) -> LumaCandidate {
+        let border =
+            create_border_luma(mbx, mby, self.mbwidth, &self.top_border, &self.left_border);
+
+        let lambda = self.quantizers(mbx, mby).lambda_i16;
+        let best = LUMA_MODES
+            .iter()
+            .map(|&mode| self.luma16_candidate(mbx, mby, &border, mode))
+            .min_by_key(|candidate| rd_score(candidate.distortion, candidate.rate, lambda))
+            .unwrap();
+
+        let lambda = self.quantizers(mbx, mby).lambda_mode;
+        let score = rd_score(best.distortion, best.rate, lambda);
+        let best = self
+            .luma4_candidate(mbx, mby, &border, score)
+            .unwrap_or(best);
+
+        self.reconstruct_luma(mbx, mby, &best.ws);
+        best
+    }
+
+    /// Predicts the whole luma plane of a macroblock with one mode, and quantizes and
+    /// reconstructs it.
+    fn luma16_candidate(
+        &self,
+        mbx: usize,
+        mby: usize,
+        border: &[u8; 357],
+        mode: LumaMode,
+    ) -> LumaCandidate {
+        let stride = 1usize + 16 + 4;
+        let source_stride = self.source.luma_stride;
+        let source = &self.source.y[mby * 16 * source_stride + mbx * 16..];
+
+        let mut ws = *border;
+        predict_luma(&mut ws, mode, mbx, mby);
+
+        let mut blocks = [[0i32; 16]; 16];
+        let mut dc = [0; 16];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let y0 = 1 + (i / 4) * 4;
+            let x0 = 1 + (i % 4) * 4;
+            *block = transform_residuals(&ws, stride, y0, x0, source, source_stride);
+            dc[i] = block[0];
+        }
+
+        transform::fwht4x4(&mut dc);
+        let quantizers = self.quantizers(mbx, mby);
+        let y2 = quantize_block(&mut dc, 0, &quantizers.y2);
+        transform::iwht4x4(&mut dc);
+
+        let mut top = self.top_complexity[mbx];
+        let mut left = self.left_complexity;
+        let mut levels = [[0; 16]; 16];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let (x, y) = (i % 4, i / 4);
+            let complexity = usize::from(top[x + 1] + left[y + 1]);
+            let lambda = quantizers.lambda_trellis_i16;
+            levels[i] = self.quantize_block(block, 1, &quantizers.y1, 0, complexity, lambda);
+            let nonzero = u8::from(levels[i][1..].iter().any(|&l| l != 0));
+            top[x + 1] = nonzero;
+            left[y + 1] = nonzero;
+
+            block[0] = dc[i];
+            transform::idct4x4(block);
+            add_residue(&mut ws, block, 1 + (i / 4) * 4, 1 + (i % 4) * 4, stride);
+        }
+
+        let intra = mode.into_intra().unwrap();
+        LumaCandidate {
+            mode,
+            bpred: [intra; 16],
+            y2: Some(y2),
+            y: levels,
+            ws,
+            distortion: block_sse(&ws, stride, source, source_stride, 16),
+            rate: self.costs.luma_modes[mode as usize] + self.luma16_rate(mbx, &y2, &levels),
+        }
+    }
+
+    /// Chooses the mode of each luma subblock of a macroblock in turn, and quantizes and
+    /// reconstructs it. Gives up once the score reaches `max_score`.
+    fn luma4_candidate(
+        &self,
+        mbx: usize,
+        mby: usize,
+        border: &[u8; 357],
+        max_score: u64,
+    ) -> Option<LumaCandidate> {
+        let stride = 1usize + 16 + 4;
+        let source_stride = self.source.luma_stride;
+        let source = &self.source.y[mby * 16 * source_stride + mbx * 16..];
+        let quantizers = self.quantizers(mbx, mby);
+
+        let above = (mby > 0).then(|| &self.macroblocks[(mby - 1) * self.mbwidth + mbx]);
+        let left = (mbx > 0).then(|| &self.macroblocks[mby * self.mbwidth + mbx - 1]);
+        let mut top_complexity = self.top_complexity[mbx];
+        let mut left_complexity = self.left_complexity;
+
+        let mut ws = *border;
+        let mut bpred = [IntraMode::DC; 16];
+        let mut levels = [[0; 16]; 16];
+        let mut distortion = 0;
+        let mut rate = self.costs.luma_modes[LumaMode::B as usize];
+        for i in 0..16 {
+            let (x, y) = (i % 4, i / 4);
+            let (x0, y0) = (1 + x * 4, 1 + y * 4);
+            let block_source = &source[y * 4 * source_stride + x * 4..];
+            let top_mode = match y {
+                0 => above.map_or(IntraMode::DC, |mb| mb.bpred[12 + x]),
+                _ => bpred[i - 4],
+            };
+            let left_mode = match x {
+                0 => left.map_or(IntraMode::DC, |mb| mb.bpred[3 + y * 4]),
+                _ => bpred[i - 1],
+            };
+            let mode_costs = &self.costs.subblock_modes[top_mode as usize][left_mode as usize];
+            let complexity = usize::from(top_complexity[x + 1] + left_complexity[y + 1]);
+            let lambda = quantizers.lambda_trellis_i4;
+
+            let mut best: Option<(u64, IntraMode, [i32; 16], u32, u32)> = None;
+            let mut best_ws = ws;
+            for mode in INTRA_MODES {
+                predict_subblock(&mut ws, mode, x0, y0, stride);
+                let mut block = transform_residuals(&ws, stride, y0, x0, source, source_stride);
+                let block_levels =
+                    self.quantize_block(&mut block, 0, &quantizers.y1, 3, complexity, lambda);
+                transform::idct4x4(&mut block);
+                add_residue(&mut ws, &block, y0, x0, stride);
+
+                let ws_offset = (y0 - 1) * stride + x0 - 1;
+                let block_distortion =
+                    block_sse(&ws[ws_offset..], stride, block_source, source_stride, 4);
+                let block_rate =
+                    mode_costs[mode as usize] + self.costs.block(3, &block_levels, 0, complexity);
+                let score = rd_score(block_distortion, block_rate, quantizers.lambda_i4);
+                if best.map_or(true, |(best_score, ..)| score < best_score) {
+                    best = Some((score, mode, block_levels, block_distortion, block_rate));
+                    best_ws = ws;
+                }
+            }
+
+            let (_, mode, block_levels, block_distortion, block_rate) = best.unwrap();
+            ws = best_ws;
+            bpred[i] = mode;
+            levels[i] = block_levels;
+            let nonzero = u8::from(block_levels.iter().any(|&l| l != 0));
+            top_complexity[x + 1] = nonzero;
+            left_complexity[y + 1] = nonzero;
+
+            distortion += block_distortion;
+            rate += block_rate;
+            if rd_score(distortion, rate, quantizers.lambda_mode) >= max_score {
+                return None;
+            }
+        }
+
+        Some(LumaCandidate {
+            mode: LumaMode::B,
+            bpred,
+            y2: None,
+            y: levels,
+            ws,
+            distortion,
+            rate,
+        })
+    }
+
+    /// Estimated cost of the luma levels of a macroblock predicted as a whole, with the contexts
+    /// `write_residual_data` uses.
+    fn luma16_rate(&self, mbx: usize, y2: &[i32; 16], y: &[[i32; 16]; 16]) -> u32 {
+        let mut top = self.top_complexity[mbx];
+        let mut left = self.left_complexity;
+
+        let mut rate = self.costs.block(1, y2, 0, usize::from(top[0] + left[0]));
+        for (i, levels) in y.iter().enumerate() {
+            let (x, y) = (i % 4, i / 4);
+            rate += self
+                .costs
+                .block(0, levels, 1, usize::from(top[x + 1] + left[y + 1]));
+            let nonzero = u8::from(levels[1..].iter().any(|&l| l != 0));
+            top[x + 1] = nonzero;
+            left[y + 1] = nonzero;
+        }
+        rate
+    }
+
+    /// Stores a reconstructed luma workspace, as `Vp8Decoder::intra_predict_luma` does.
+    fn reconstruct_luma(&mut self, mbx: usize, mby: usize, ws: &[u8]) {
+        let stride = 1usize + 16 + 4;
+        let w = usize::from(self.frame.width);
+
+        self.left_border[0] = ws[16];
+        for (i, left) in self.left_border[1..][..16].iter_mut().enumerate() {
+            *left = ws[(i + 1) * stride + 16];
+        }
+        self.top_border[mbx * 16..][..16].copy_from_slice(&ws[16 * stride + 1..][..16]);
+
+        let ylength = cmp::min(usize::from(self.frame.height) - mby * 16, 16);
+        let xlength = cmp::min(w - mbx * 16, 16);
+        for y in 0..ylength {
+            self.frame.ybuf[(mby * 16 + y) * w + mbx * 16..][..xlength]
+                .copy_from_slice(&ws[(1 + y) * stride + 1..][..xlength]);
+        }
+    }
+
+    /// Chooses the chroma prediction of a macroblock with the best rate-distortion score, and
+    /// quantizes and reconstructs it.
+    fn encode_chroma(
+        &mut self,
+        mbx: usize,
+        mby: usize,
+    ) -> (ChromaMode, [[i32; 16]; 4], [[i32; 16]; 4]) {
+        let stride = 1usize + 8;
+        let source_stride = self.source.chroma_stride;
+        let offset = mby * 8 * source_stride + mbx * 8;
+        let u_source = &self.source.u[offset..];
+        let v_source = &self.source.v[offset..];
+
+        let quantizers = self.quantizers(mbx, mby);
+        let encode_plane = |ws: &mut [u8; 81], source: &[u8], j: usize| {
+            let mut top = self.top_complexity[mbx];
+            let mut left = self.left_complexity;
+            let mut levels = [[0; 16]; 4];
+            for (i, levels) in levels.iter_mut().enumerate() {
+                let (x, y) = (i % 2, i / 2);
+                let (y0, x0) = (1 + y * 4, 1 + x * 4);
+                let mut block = transform_residuals(ws, stride, y0, x0, source, source_stride);
+                let complexity = usize::from(top[x + j] + left[y + j]);
+                let lambda = quantizers.lambda_trellis_uv;
+                *levels = self.quantize_block(&mut block, 0, &quantizers.uv, 2, complexity, lambda);
+                let nonzero = u8::from(levels.iter().any(|&l| l != 0));
+                top[x + j] = nonzero;
+                left[y + j] = nonzero;
+
+                transform::idct4x4(&mut block);
+                add_residue(ws, &block, y0, x0, stride);
+            }
+            levels
+        };
+
+        let (u_border, v_border) = create_border_chroma(mbx, mby, &self.frame);
+        let (_, mode, uws, vws, u_levels, v_levels) = CHROMA_MODES
+            .iter()
+            .map(|&mode| {
+                let (mut uws, mut vws) = (u_border, v_border);
+                predict_chroma(&mut uws, mode, mbx, mby);
+                predict_chroma(&mut vws, mode, mbx, mby);
+                let u_levels = encode_plane(&mut uws, u_source, 5);
+                let v_levels = encode_plane(&mut vws, v_source, 7);
+
+                let distortion = block_sse(&uws, stride, u_source, source_stride, 8)
+                    + block_sse(&vws, stride, v_source, source_stride, 8);
+                let rate = self.costs.chroma_modes[mode as usize]
+                    + self.chroma_rate(mbx, &u_levels, &v_levels);
+                let score = rd_score(distortion, rate, quantizers.lambda_uv);
+                (score, mode, uws, vws, u_levels, v_levels)
+            })
+            .min_by_key(|&(score, ..)| score)
+            .unwrap();
+
+        let w = usize::from(self.frame.chroma_width());
+        let ylength = cmp::min(usize::from(self.frame.chroma_height()) - mby * 8, 8);
+        let xlength = cmp::min(w - mbx * 8, 8);
+        for y in 0..ylength {
+            let index = (mby * 8 + y) * w + mbx * 8;
+            let ws_index = (1 + y) * stride + 1;
+            self.frame.ubuf[index..][..xlength].copy_from_slice(&uws[ws_index..][..xlength]);
+            self.frame.vbuf[index..][..xlength].copy_from_slice(&vws[ws_index..][..xlength]);
+        }
+
+        (mode, u_levels, v_levels)
+    }
+
+    /// Estimated cost of the chroma levels of a macroblock, with the contexts
+    /// `write_residual_data` uses.
+    fn chroma_rate(&self, mbx: usize, u: &[[i32; 16]; 4], v: &[[i32; 16]; 4]) -> u32 {
+        let mut top = self.top_complexity[mbx];
+        let mut left = self.left_complexity;
+
+        let mut rate = 0;
+        for (j, blocks) in [(5usize, u), (7usize, v)] {
+            for (i, levels) in blocks.iter().enumerate() {
+                let (x, y) = (i % 2, i / 2);
+                rate += self
+                    .costs
+                    .block(2, levels, 0, usize::from(top[x + j] + left[y + j]));
+                let nonzero = u8::from(levels.iter().any(|&l| l != 0));
+                top[x + j] = nonzero;
+                left[y + j] = nonzero;
+            }
+        }
+        rate
+    }
+
+    /// Resets the contexts of a macroblock without coefficients, as the decoder does.
+    fn skip_coefficients(&mut self, mbx: usize, luma_mode: LumaMode) {
+        let first = if luma_mode == LumaMode::B { 1 } else { 0 };
+        self.left_complexity[first..].fill(0);
+        self.top_complexity[mbx][first..].fill(0);
+    }
+
+    /// Writes the coefficients of a macroblock, the counterpart of
+    /// `Vp8Decoder::read_residual_data`.
+    fn write_residual_data(&mut self, mbx: usize, levels: &MacroBlockLevels) {
+        let probs: &TokenProbTreeNodes = &COEFF_PROB_NODES;
+        let top = &mut self.top_complexity[mbx];
+        let left = &mut self.left_complexity;
+
+        let (plane, first) = match &levels.y2 {
+            Some(y2) => {
+                let complexity = usize::from(top[0] + left[0]);
+                let n = write_coefficients(&mut self.tokens, &probs[1], y2, 0, complexity);
+                top[0] = u8::from(n);
+                left[0] = u8::from(n);
+                (0, 1)
+            }
+            None => (3, 0),
+        };
+
+        for y in 0usize..4 {
+            for x in 0usize..4 {
+                let complexity = usize::from(top[x + 1] + left[y + 1]);
+                let block = &levels.y[x + y * 4];
+                let n =
+                    write_coefficients(&mut self.tokens, &probs[plane], block, first, complexity);
+                top[x + 1] = u8::from(n);
+                left[y + 1] = u8::from(n);
+            }
+        }
+
+        for (j, blocks) in [(5usize, &levels.u), (7usize, &levels.v)] {
+            for y in 0usize..2 {
+                for x in 0usize..2 {
+                    let complexity = usize::from(top[x + j] + left[y + j]);
+                    let block = &blocks[x + y * 2];
+                    let n = write_coefficients(&mut self.tokens, &probs[2], block, 0, complexity);
+                    top[x + j] = u8::from(n);
+                    left[y + j] = u8::from(n);
+                }
+            }
+        }
+    }
+
+    /// The tree the segment of each macroblock is written with, with probabilities that match
+    /// how often each segment is used.
+    fn segment_tree(&self) -> [TreeNode; 3] {
+        let mut counts = [0usize; MAX_SEGMENTS];
+        for &segment in &self.segment_map {
+            counts[usize::from(segment)] += 1;
+        }
+        let probability = |left: usize, right: usize| match left + right {
+            0 => 255,
+            total => ((255 * left + total / 2) / total) as u8,
+        };
+
+        let mut tree = SEGMENT_TREE_NODE_DEFAULTS;
+        tree[0].prob = probability(counts[0] + counts[1], counts[2] + counts[3]);
+        tree[1].prob = probability(counts[0], counts[1]);
+        tree[2].prob = probability(counts[2], counts[3]);
+        tree
+    }
+
+    /// Sums of squared differences between a frame and the source in YUV, over the macroblocks of
+    /// each segment.
+    fn segment_sse(&self, frame: &Frame) -> Vec<u64> {
+        let source = self.source;
+        let width = usize::from(frame.width);
+        let chroma_width = usize::from(frame.chroma_width());
+        let planes = [
+            (&frame.ybuf, &source.y, source.luma_stride, width, 16),
+            (
+                &frame.ubuf,
+                &source.u,
+                source.chroma_stride,
+                chroma_width,
+                8,
+            ),
+            (
+                &frame.vbuf,
+                &source.v,
+                source.chroma_stride,
+                chroma_width,
+                8,
+            ),
+        ];
+
+        let mut sse = vec![0; self.segments.len()];
+        for (plane, source, stride, width, size) in planes {
+            for (y, (row, source_row)) in plane
+                .chunks_exact(width)
+                .zip(source.chunks_exact(stride))
+                .enumerate()
+            {
+                let segments = &self.segment_map[(y / size) * self.mbwidth..];
+                for ((a, b), &segment) in
+                    row.chunks(size).zip(source_row.chunks(size)).zip(segments)
+                {
+                    sse[usize::from(segment)] += a
+                        .iter()
+                        .zip(b)
+                        .map(|(&a, &b)| (i64::from(a) - i64::from(b)).pow(2) as u64)
+                        .sum::<u64>();
+                }
+            }
+        }
+        sse
+    }
+
+    /// Peak signal-to-noise ratio of a frame against the source in YUV, in dB.
+    fn psnr(&self, frame: &Frame) -> f64 {
+        let sse = self.segment_sse(frame).iter().sum::<u64>();
+        let samples = frame.ybuf.len() + frame.ubuf.len() + frame.vbuf.len();
+        10.0 * (255.0 * 255.0 * samples as f64 / sse as f64).log10()
+    }
+
+    /// The reconstructed frame after the loop filter, with the filter type and sharpness of the
+    /// frame and the filter levels of the segments, as the decoder filters it.
+    fn filtered_frame(&self) -> Frame {
+        let mut frame = self.frame.clone();
+        for (i, mb) in self.macroblocks.iter().enumerate() {
+            let segment = &self.segments[usize::from(self.segment_map[i])];
+            let filter_inner_edges = mb.luma_mode == LumaMode::B || !mb.coeffs_skipped;
+            frame.filter_macroblock(
+                i % self.mbwidth,
+                i / self.mbwidth,
+                segment.filter_level,
+                filter_inner_edges,
+            );
+        }
+        frame
+    }
+
+    /// Chooses the loop filter type, sharpness and the level of each segment that bring the
+    /// filtered frame closest to the source, unless they are set in `params`. Returns the filtered
+    /// frame.
+    ///
+    /// The levels are chosen first, for each segment on its own, and then the sharpness and the
+    /// type for the whole frame.
+    fn choose_loop_filter(&mut self, params: &EncoderParams) -> Frame {
+        self.frame.filter_type = params.simple_filter.unwrap_or(false);
+        self.frame.sharpness_level = params.filter_sharpness.unwrap_or(0).min(MAX_SHARPNESS);
+        match params.filter_level {
+            Some(level) => {
+                for segment in &mut self.segments {
+                    segment.filter_level = level.min(MAX_FILTER_LEVEL);
+                }
+            }
+            None => self.choose_filter_levels(),
+        }
+
+        let mut best = self.filtered_frame();
+        let mut best_sse = self.segment_sse(&best).iter().sum::<u64>();
+        let mut try_frame = |encoder: &mut Self, simple: bool, sharpness: u8| {
+            let previous = (encoder.frame.filter_type, encoder.frame.sharpness_level);
+            (encoder.frame.filter_type, encoder.frame.sharpness_level) = (simple, sharpness);
+            let frame = encoder.filtered_frame();
+            let sse = encoder.segment_sse(&frame).iter().sum::<u64>();
+            if sse < best_sse {
+                (best, best_sse) = (frame, sse);
+                true
+            } else {
+                (encoder.frame.filter_type, encoder.frame.sharpness_level) = previous;
+                false
+            }
+        };
+        // Without filtering, the sharpness and the type make no difference.
+        if self.segments.iter().any(|s| s.filter_level > 0) {
+            if params.filter_sharpness.is_none() {
+                for sharpness in 1..=MAX_SHARPNESS {
+                    if !try_frame(self, self.frame.filter_type, sharpness) {
+                        break;
+                    }
+                }
+            }
+            if params.simple_filter.is_none() {
+                try_frame(self, true, self.frame.sharpness_level);
+            }
+        }
+
+        // Some decoders skip the loop filter altogether when the level of the frame is zero, even
+        // if the segments have levels of their own.
+        self.frame.filter_level = self.segments.iter().map(|s| s.filter_level).max().unwrap();
+        best.filter_level = self.frame.filter_level;
+        best
+    }
+
+    /// Chooses the loop filter level of each segment that brings its macroblocks closest to the
+    /// source, with the filter type and sharpness of the frame.
+    fn choose_filter_levels(&mut self) {
+        let mut best = vec![(u64::MAX, 0); self.segments.len()];
+        let try_levels = |encoder: &mut Self, levels: &[u8], best: &mut [(u64, u8)]| {
+            for (segment, &level) in encoder.segments.iter_mut().zip(levels) {
+                segment.filter_level = level;
+            }
+            let sse = encoder.segment_sse(&encoder.filtered_frame());
+            for ((best_sse, best_level), (sse, &level)) in
+                best.iter_mut().zip(sse.into_iter().zip(levels))
+            {
+                if sse < *best_sse {
+                    (*best_sse, *best_level) = (sse, level);
+                }
+            }
+        };
+
+        // Quality rises with the level up to a point and then falls, so the search stops once the
+        // level is well past the best one of each segment.
+        for level in (0..=MAX_FILTER_LEVEL).step_by(FILTER_LEVEL_STEP.into()) {
+            try_levels(self, &vec![level; self.segments.len()], &mut best);
+            if best
+                .iter()
+                .all(|&(_, best_level)| best_level + 2 * FILTER_LEVEL_STEP <= level)
+            {
+                break;
+            }
+        }
+        for delta in [-2, -1, 1, 2] {
+            let levels: Vec<u8> = best
+                .iter()
+                .map(|&(_, level)| level.saturating_add_signed(delta).min(MAX_FILTER_LEVEL))
+                .collect();
+            try_levels(self, &levels, &mut best);
+        }
+
+        for (segment, (_, level)) in self.segments.iter_mut().zip(best) {
+            segment.filter_level = level;
+        }
+    }
+
+    /// Writes the frame header and the macroblock headers to the first partition.
+    fn write_first_partition(&self) -> Vec<u8> {
+        let mut b = ArithmeticEncoder::new();
+
+        b.write_literal(1, 0); // color space
+        b.write_literal(1, self.frame.pixel_type);
+
+        let segment_tree = (self.segments.len() > 1).then(|| self.segment_tree());
+        b.write_flag(segment_tree.is_some());
+        if let Some(tree) = &segment_tree {
+            b.write_flag(true); // update the segment map
+            b.write_flag(true); // update the segment data
+            b.write_flag(true); // absolute values instead of deltas
+            for i in 0..MAX_SEGMENTS {
+                let quantizer_index = self.segments.get(i).map_or(0, |s| s.quantizer_index);
+                b.write_optional_signed_value(7, i32::from(quantizer_index));
+            }
+            for i in 0..MAX_SEGMENTS {
+                let filter_level = self.segments.get(i).map_or(0, |s| s.filter_level);
+                b.write_optional_signed_value(6, i32::from(filter_level));
+            }
+            for node in tree {
+                b.write_flag(node.prob != 255);
+                if node.prob != 255 {
+                    b.write_literal(8, node.prob);
+                }
+            }
+        }
+
+        b.write_flag(self.frame.filter_type);
+        b.write_literal(6, self.frame.filter_level);
+        b.write_literal(3, self.frame.sharpness_level);
+        b.write_flag(false); // loop filter adjustments
+
+        b.write_literal(2, 0); // one token partition
+
+        b.write_literal(7, self.quantizer_index);
+        for _ in 0..5 {
+            b.write_optional_signed_value(4, 0); // quantizer delta
+        }
+
+        b.write_literal(1, 0); // refresh entropy probabilities
+
+        for is in &COEFF_UPDATE_PROBS {
+            for js in is {
+                for ks in js {
+                    for &prob in ks {
+                        // Keep the default probabilities.
+                        b.write_bool(false, prob);
+                    }
+                }
+            }
+        }
+
+        let skipped = self
+            .macroblocks
+            .iter()
+            .filter(|mb| mb.coeffs_skipped)
+            .count();
+        let prob_skip_false = (skipped > 0).then(|| {
+            let not_skipped = self.macroblocks.len() - skipped;
+            (not_skipped * 255 / self.macroblocks.len()).clamp(1, 254) as u8
+        });
+        b.write_literal(1, u8::from(prob_skip_false.is_some()));
+        if let Some(prob) = prob_skip_false {
+            b.write_literal(8, prob);
+        }
+
+        for (i, mb) in self.macroblocks.iter().enumerate() {
+            let (mbx, mby) = (i % self.mbwidth, i / self.mbwidth);
+            if let Some(tree) = &segment_tree {
+                b.write_with_tree(tree, self.segment_map[i] as i8);
+            }
+            if let Some(prob) = prob_skip_false {
+                b.write_bool(mb.coeffs_skipped, prob);
+            }
+
+            b.write_with_tree(&KEYFRAME_YMODE_NODES, mb.luma_mode as i8);
+            if mb.luma_mode == LumaMode::B {
+                let above = (mby > 0).then(|| &self.macroblocks[i - self.mbwidth]);
+                let left = (mbx > 0).then(|| &self.macroblocks[i - 1]);
+                for y in 0usize..4 {
+                    for x in 0usize..4 {
+                        let top = match y {
+                            0 => above.map_or(IntraMode::DC, |mb| mb.bpred[12 + x]),
+                            _ => mb.bpred[x + (y - 1) * 4],
+                        };
+                        let left = match x {
+                            0 => left.map_or(IntraMode::DC, |mb| mb.bpred[3 + y * 4]),
+                            _ => mb.bpred[x - 1 + y * 4],
+                        };
+                        b.write_with_tree(
+                            &KEYFRAME_BPRED_MODE_NODES[top as usize][left as usize],
+                            mb.bpred[x + y * 4] as i8,
+                        );
+                    }
+                }
+            }
+
+            b.write_with_tree(&KEYFRAME_UV_MODE_NODES, mb.chroma_mode as i8);
+        }
+
+        b.flush()
+    }
+}
+
+/// What a lossy frame is encoded for.
+#[derive(Clone, Copy)]
+pub(crate) enum Target {
+    /// A quality from 0 to 100.
+    Quality(u8),
+    /// The highest quality whose frame takes at most this many bytes, starting the search at a
+    /// quality.
+    Size(usize, u8),
+    /// The lowest quality whose frame reaches this PSNR in dB, starting the search at a quality.
+    Psnr(f64, u8),
+}
+
+/// Largest number of times a frame is encoded to reach a size or a PSNR.
+const MAX_PASSES: usize = 8;
+
+/// How far below the target size a frame may be for the search to stop, as a fraction of it.
+const SIZE_TOLERANCE: f64 = 0.02;
+
+/// How far above the target PSNR a frame may be for the search to stop, in dB.
+const PSNR_TOLERANCE: f64 = 0.1;
+
+/// An encoded frame, kept as its partitions until it is written.
+struct EncodedFrame {
+    /// The frame tag, start code and dimensions.
+    header: [u8; 10],
+    first_partition: Vec<u8>,
+    tokens: Vec<u8>,
+}
+
+impl EncodedFrame {
+    /// Number of bytes the frame takes once written.
+    fn len(&self) -> usize {
+        self.header.len() + self.first_partition.len() + self.tokens.len()
+    }
+
+    fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(&self.header)?;
+        w.write_all(&self.first_partition)?;
+        w.write_all(&self.tokens)
+    }
+}
+
+/// A frame encoded at some compression.
+struct Pass {
+    frame: EncodedFrame,
+    psnr: f64,
+}
+
+/// Encodes an RGBA image as a VP8 keyframe, writes the contents of its `VP8 ` chunk to `writer`
+/// and returns their size. The alpha channel is ignored.
+pub(crate) fn encode_frame<W: Write>(
+    writer: W,
+    pixels: &[u8],
+    width: u16,
+    height: u16,
+    target: Target,
+    params: &EncoderParams,
+) -> Result<usize, EncodingError> {
+    let source = SourcePlanes::from_rgba(pixels, usize::from(width), usize::from(height));
+    let encode = |compression| encode_pass(&source, width, height, compression, params);
+    let frame = match target {
+        Target::Quality(quality) => encode(compression(quality))?.frame,
+        Target::Size(size, quality) => search(compression(quality), true, encode, |pass| {
+            let len = pass.frame.len() as f64;
+            let size = size as f64;
+            (len <= size, len >= size * (1.0 - SIZE_TOLERANCE))
+        })?,
+        Target::Psnr(psnr, quality) => search(compression(quality), false, encode, |pass| {
+            (pass.psnr >= psnr, pass.psnr <= psnr + PSNR_TOLERANCE)
+        })?,
+    };
+    frame.write(writer)?;
+    Ok(frame.len())
+}
+
+fn encode_pass(
+    source: &SourcePlanes,
+    width: u16,
+    height: u16,
+    compression: f64,
+    params: &EncoderParams,
+) -> Result<Pass, EncodingError> {
+    let mut encoder = Vp8Encoder::new(
+        source,
+        width,
+        height,
+        compressed_quantizer_index(compression),
+    );
+    encoder.trellis = params.trellis;
+    if params.segments > 1 {
+        encoder.assign_segments(compression, usize::from(params.segments));
+    }
+    encoder.encode_macroblocks();
+    let filtered = encoder.choose_loop_filter(params);
+    let psnr = encoder.psnr(&filtered);
+    Ok(Pass {
+        frame: finish_frame(encoder)?,
+        psnr,
+    })
+}
+
+/// Bisects the compression of a frame, starting at `start`, until a pass is acceptable and close
+/// to the target. `evaluate` tells both of a pass. Acceptable passes have compressions below the
+/// others if `acceptable_below` is set, and above them otherwise.
+///
+/// Returns the acceptable pass closest to the target, or if there is none, the pass at the
+/// extreme compression that comes closest.
+fn search(
+    start: f64,
+    acceptable_below: bool,
+    mut encode: impl FnMut(f64) -> Result<Pass, EncodingError>,
+    evaluate: impl Fn(&Pass) -> (bool, bool),
+) -> Result<EncodedFrame, EncodingError> {
+    let (mut low, mut high) = (0.0, 1.0);
+    let mut compression = start;
+    let mut best = None;
+    for _ in 0..MAX_PASSES {
+        let pass = encode(compression)?;
+        let (acceptable, close) = evaluate(&pass);
+        if acceptable && close {
+            return Ok(pass.frame);
+        }
+
+        if acceptable == acceptable_below {
+            low = compression;
+        } else {
+            high = compression;
+        }
+        if acceptable {
+            best = Some(pass.frame);
+        }
+        compression = (low + high) / 2.0;
+    }
+
+    match best {
+        Some(frame) => Ok(frame),
+        None => Ok(encode(if acceptable_below { 0.0 } else { 1.0 })?.frame),
+    }
+}
+
+fn finish_frame(encoder: Vp8Encoder<'_>) -> Result<EncodedFrame, EncodingError> {
+    let first_partition = encoder.write_first_partition();
+    if first_partition.len() > MAX_FIRST_PARTITION_SIZE {
+        return Err(EncodingError::PartitionTooLarge);
+    }
+
+    let mut header = [0; 10];
+    // Keyframe, version 0, shown, followed by the size of the first partition.
+    let tag = (1 << 4) | (first_partition.len() as u32) << 5;
+    header[..3].copy_from_slice(&tag.to_le_bytes()[..3]);
+    header[3..6].copy_from_slice(&[0x9d, 0x01, 0x2a]);
+    header[6..8].copy_from_slice(&encoder.frame.width.to_le_bytes());
+    header[8..].copy_from_slice(&encoder.frame.height.to_le_bytes());
+    Ok(EncodedFrame {
+        header,
+        first_partition,
+        tokens: encoder.tokens.flush(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::vp8::Vp8Decoder;
+
+    fn quantizer_index(quality: u8) -> u8 {
+        compressed_quantizer_index(compression(quality))
+    }
+
+    fn frame_data(encoder: Vp8Encoder<'_>) -> Vec<u8> {
+        let mut data = Vec::new();
+        finish_frame(encoder).unwrap().write(&mut data).unwrap();
+        data
+    }
+
+    /// An image with smooth gradients, sharp edges and noise.
+    fn test_image(width: usize, height: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let noise = (state >> 24) as u8;
+                let pixel = match (x / 8 + y / 8) % 3 {
+                    0 => [(x * 4) as u8, (y * 4) as u8, 128, 255],
+                    1 => [noise, noise / 2, 255 - noise, 255],
+                    _ => [if x % 4 < 2 { 255 } else { 0 }, 0, (x * y) as u8, 255],
+                };
+                pixels.extend_from_slice(&pixel);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn reconstruction_matches_decoder() {
+        for (width, height) in [(1, 1), (37, 21), (64, 48)] {
+            let pixels = test_image(width, height);
+            let source = SourcePlanes::from_rgba(&pixels, width, height);
+            for (quality, segments, trellis) in [
+                (0, 1, false),
+                (50, 1, false),
+                (100, 1, false),
+                (20, 4, false),
+                (80, 3, false),
+                (0, 1, true),
+                (60, 4, true),
+                (100, 1, true),
+            ] {
+                let mut encoder = Vp8Encoder::new(
+                    &source,
+                    width as u16,
+                    height as u16,
+                    quantizer_index(quality),
+                );
+                encoder.trellis = trellis;
+                encoder.assign_segments(compression(quality), segments);
+                encoder.encode_macroblocks();
+                let expected = encoder.choose_loop_filter(&EncoderParams::default());
+
+                let data = frame_data(encoder);
+                let frame = Vp8Decoder::decode_frame(&data[..]).unwrap();
+                let name = format!(
+                    "{width}x{height} at {quality} with {segments} segments, trellis {trellis}"
+                );
+                assert_eq!((frame.width, frame.height), (width as u16, height as u16));
+                assert!(frame.ybuf == expected.ybuf, "{name}");
+                assert!(frame.ubuf == expected.ubuf, "{name}");
+                assert!(frame.vbuf == expected.vbuf, "{name}");
+            }
+        }
+    }
+
+    #[test]
+    fn mode_decision() {
+        let encode = |pixels: &[u8]| {
+            let source = SourcePlanes::from_rgba(pixels, 64, 64);
+            let mut encoder = Vp8Encoder::new(&source, 64, 64, quantizer_index(75));
+            encoder.encode_macroblocks();
+            encoder.macroblocks
+        };
+
+        // Flat areas are best predicted as a whole, and need no coefficients once there are
+        // neighbors to predict from.
+        let flat = [90, 140, 200, 255].repeat(64 * 64);
+        let macroblocks = encode(&flat);
+        assert!(macroblocks.iter().all(|mb| mb.luma_mode != LumaMode::B));
+        assert!(macroblocks[1..].iter().all(|mb| mb.coeffs_skipped));
+
+        // Diagonal lines are best predicted along their direction, subblock by subblock.
+        let diagonal: Vec<u8> = (0..64 * 64)
+            .flat_map(|i| {
+                let value = if (i % 64 + i / 64) % 6 < 3 { 30 } else { 220 };
+                [value, value, value, 255]
+            })
+            .collect();
+        let macroblocks = encode(&diagonal);
+        assert!(macroblocks.iter().any(|mb| mb.luma_mode == LumaMode::B));
+        assert!(macroblocks
+            .iter()
+            .flat_map(|mb| mb.bpred)
+            .any(|mode| mode == IntraMode::LD));
+    }
+
+    #[test]
+    fn trellis_quantization() {
+        let mut rng = rand::thread_rng();
+        let costs = Costs::new(&COEFF_PROB_NODES);
+        for _ in 0..2000 {
+            let quantizers = Quantizers::new(rng.gen_range(0..128));
+            let (plane, first, quantizers, lambda) = match rng.gen_range(0..3) {
+                0 => (0, 1, &quantizers.y1, quantizers.lambda_trellis_i16),
+                1 => (3, 0, &quantizers.y1, quantizers.lambda_trellis_i4),
+                _ => (2, 0, &quantizers.uv, quantizers.lambda_trellis_uv),
+            };
+            let complexity = rng.gen_range(0..3);
+            let scale = rng.gen_range(1..400);
+            let coeffs: [i32; 16] = std::array::from_fn(|_| rng.gen_range(-scale..=scale));
+
+            let score = |quantize: &dyn Fn(&mut [i32; 16]) -> [i32; 16]| {
+                let mut dequantized = coeffs;
+                let levels = quantize(&mut dequantized);
+                let distortion: u64 = (first..16)
+                    .map(|i| (coeffs[i] - dequantized[i]).unsigned_abs().pow(2) as u64)
+                    .sum();
+                let rate = costs.block(plane, &levels, first, complexity);
+                256 * distortion + lambda * u64::from(rate)
+            };
+            // Rounding picks from the same levels as the trellis, so it can't do better.
+            let rounded = score(&|coeffs| quantize_block(coeffs, first, quantizers));
+            let trellis = score(&|coeffs| {
+                trellis_quantize_block(coeffs, first, quantizers, &costs, plane, complexity, lambda)
+            });
+            assert!(trellis <= rounded, "{trellis} > {rounded} for {coeffs:?}");
+        }
+    }
+
+    #[test]
+    fn segments() {
+        // A flat sky above a noisy foreground.
+        let pixels: Vec<u8> = test_image(64, 64)
+            .chunks_exact(4)
+            .enumerate()
+            .flat_map(|(i, pixel)| match i / 64 < 32 {
+                true => [100, 150, 230, 255],
+                false => [pixel[0], pixel[1], pixel[2], 255],
+            })
+            .collect();
+        let source = SourcePlanes::from_rgba(&pixels, 64, 64);
+        let mut encoder = Vp8Encoder::new(&source, 64, 64, quantizer_index(75));
+        encoder.assign_segments(compression(75), 4);
+
+        let sky = encoder.segment_map[0];
+        assert!(encoder.segment_map[..8].iter().all(|&s| s == sky));
+        assert!(encoder.segment_map[8..].iter().all(|&s| s != sky));
+        let foreground = &encoder.segments[usize::from(encoder.segment_map[15])];
+        let sky = &encoder.segments[usize::from(sky)];
+        assert!(sky.quantizer_index < quantizer_index(75));
+        assert!(foreground.quantizer_index > quantizer_index(75));
+
+        // Without variety, there is a single segment.
+        let flat = [50, 50, 50, 255].repeat(64 * 64);
+        let source = SourcePlanes::from_rgba(&flat, 64, 64);
+        let mut encoder = Vp8Encoder::new(&source, 64, 64, quantizer_index(75));
+        encoder.assign_segments(compression(75), 4);
+        assert_eq!(encoder.segments.len(), 1);
+    }
+
+    #[test]
+    fn loop_filter() {
+        // Smooth gradients, which show the edges between blocks at low qualities.
+        let (width, height) = (96, 64);
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 2 + y) as u8, (y * 3) as u8, (x + y * 2) as u8, 255]
+            })
+            .collect();
+        let source = SourcePlanes::from_rgba(&pixels, width, height);
+        let encode = |quality, params: &EncoderParams| {
+            let mut encoder = Vp8Encoder::new(
+                &source,
+                width as u16,
+                height as u16,
+                quantizer_index(quality),
+            );
+            encoder.assign_segments(compression(quality), usize::from(params.segments));
+            encoder.encode_macroblocks();
+            let filtered = encoder.choose_loop_filter(params);
+
+            let data = frame_data(encoder);
+            let frame = Vp8Decoder::decode_frame(&data[..]).unwrap();
+            assert!(frame.ybuf == filtered.ybuf);
+            assert!(frame.ubuf == filtered.ubuf);
+            assert!(frame.vbuf == filtered.vbuf);
+            frame
+        };
+        let psnr =
+            |frame: &Frame| Vp8Encoder::new(&source, width as u16, height as u16, 0).psnr(frame);
+
+        // The chosen filter does better than none.
+        for (quality, segments) in [(0, 1), (10, 4), (50, 4)] {
+            let params = EncoderParams {
+                segments,
+                ..Default::default()
+            };
+            let unfiltered = encode(
+                quality,
+                &EncoderParams {
+                    filter_level: Some(0),
+                    ..params.clone()
+                },
+            );
+            assert_eq!(unfiltered.filter_level, 0);
+            let filtered = encode(quality, &params);
+            assert!(psnr(&filtered) >= psnr(&unfiltered));
+            if quality == 0 {
+                assert!(filtered.filter_level > 0);
+                assert!(psnr(&filtered) > psnr(&unfiltered) + 0.1);
+            }
+        }
+
+        // Settings from the parameters are kept.
+        let params = EncoderParams {
+            filter_level: Some(30),
+            filter_sharpness: Some(5),
+            simple_filter: Some(true),
+            segments: 1,
+            ..Default::default()
+        };
+        let frame = encode(20, &params);
+        assert_eq!(frame.filter_level, 30);
+        assert_eq!(frame.sharpness_level, 5);
+        assert!(frame.filter_type);
+    }
+
+    #[test]
+    fn target_psnr() {
+        let (width, height) = (64, 48);
+        let pixels = test_image(width, height);
+        let source = SourcePlanes::from_rgba(&pixels, width, height);
+        let params = EncoderParams {
+            segments: 1,
+            ..Default::default()
+        };
+
+        let mut previous_len = 0;
+        for target in [25.0, 32.0, 40.0] {
+            let mut data = Vec::new();
+            let len = encode_frame(
+                &mut data,
+                &pixels,
+                width as u16,
+                height as u16,
+                Target::Psnr(target, 75),
+                &params,
+            )
+            .unwrap();
+            assert_eq!(len, data.len());
+            assert!(data.len() > previous_len);
+            previous_len = data.len();
+
+            let frame = Vp8Decoder::decode_frame(&data[..]).unwrap();
+            let encoder = Vp8Encoder::new(&source, width as u16, height as u16, 0);
+            let psnr = encoder.psnr(&frame);
+            assert!(
+                psnr > target - 0.5 && psnr < target + 1.0,
+                "{psnr} for {target}"
+            );
+        }
+    }
+
+    #[test]
+    fn quantizer_index_range() {
+        assert_eq!(quantizer_index(0), 127);
+        assert_eq!(quantizer_index(100), 0);
+        assert_eq!(quantizer_index(255), 0);
+        for quality in 1..=100 {
+            assert!(quantizer_index(quality) <= quantizer_index(quality - 1));
+        }
+    }
+}
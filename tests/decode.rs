@@ -165,3 +165,17 @@ reftest!(gallery2, 1_webp_ll, 2_webp_ll, 3_webp_ll, 4_webp_ll, 5_webp_ll);
 reftest!(gallery2, 1_webp_a, 2_webp_a, 3_webp_a, 4_webp_a, 5_webp_a);
 reftest!(animated, random_lossless, random_lossy);
 reftest!(regression, color_index, dark, tiny);
+
+/// The loop filter of lossy images, compared against the YUV planes that libwebp decodes, since the
+/// conversion to RGB above doesn't match it exactly.
+#[test]
+fn loop_filter_matches_libwebp() {
+    // A keyframe filtered with the normal filter at level 12, which is below the level where the
+    // high edge variance threshold of keyframes rises from 0 to 1.
+    let data = std::fs::read("tests/images/regression/low_filter_level.webp").unwrap();
+    assert_eq!(&data[12..16], b"VP8 ");
+    let frame = image_webp::vp8::Vp8Decoder::decode_frame(Cursor::new(&data[20..])).unwrap();
+
+    let reference = std::fs::read("tests/reference/regression/low_filter_level.yuv").unwrap();
+    assert!([frame.ybuf, frame.ubuf, frame.vbuf].concat() == reference);
+}